@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+use std::ops::Range;
+
+use ropey::{Rope, RopeBuilder};
+
+// Lines are separated by '\n' only; the line ending itself is never part of
+// what `line` returns. All positions are char indices unless stated otherwise.
+pub trait TextBuffer {
+    fn len_chars(&self) -> usize;
+    fn len_bytes(&self) -> usize;
+    fn len_lines(&self) -> usize;
+
+    fn char_at(&self, char_idx: usize) -> char;
+    fn line(&self, line_idx: usize) -> String;
+    fn line_len(&self, line_idx: usize) -> usize;
+
+    fn line_to_char(&self, line_idx: usize) -> usize;
+    fn char_to_line(&self, char_idx: usize) -> usize;
+    fn char_to_byte(&self, char_idx: usize) -> usize;
+    fn byte_to_char(&self, byte_idx: usize) -> usize;
+
+    fn insert(&mut self, char_idx: usize, text: &str);
+    fn remove(&mut self, range: Range<usize>);
+
+    fn slice(&self, range: Range<usize>) -> String;
+    fn text(&self) -> String;
+    // The rest of the storage chunk holding `byte_idx`, for streaming readers.
+    fn chunk_at_byte(&self, byte_idx: usize) -> &str;
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()>;
+
+    fn pos_to_char(&self, line_idx: usize, col: usize) -> usize {
+        self.line_to_char(line_idx) + col
+    }
+
+    fn char_to_pos(&self, char_idx: usize) -> (usize, usize) {
+        let line_idx = self.char_to_line(char_idx);
+        (line_idx, char_idx - self.line_to_char(line_idx))
+    }
+}
+
+pub struct RopeBuffer {
+    rope: Rope,
+}
+
+impl RopeBuffer {
+    pub fn new() -> Self {
+        Self { rope: Rope::new() }
+    }
+
+    pub fn from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Self {
+        let mut builder = RopeBuilder::new();
+        for (i, line) in lines.enumerate() {
+            if i > 0 {
+                builder.append("\n");
+            }
+            builder.append(line);
+        }
+        Self { rope: builder.finish() }
+    }
+}
+
+impl TextBuffer for RopeBuffer {
+    fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    fn char_at(&self, char_idx: usize) -> char {
+        self.rope.char(char_idx)
+    }
+
+    fn line(&self, line_idx: usize) -> String {
+        let line = self.rope.line(line_idx);
+        let mut text = String::with_capacity(line.len_bytes());
+        for chunk in line.chunks() {
+            text.push_str(chunk);
+        }
+        if text.ends_with('\n') {
+            text.pop();
+        }
+        text
+    }
+
+    fn line_len(&self, line_idx: usize) -> usize {
+        let line = self.rope.line(line_idx);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    fn line_to_char(&self, line_idx: usize) -> usize {
+        self.rope.line_to_char(line_idx)
+    }
+
+    fn char_to_line(&self, char_idx: usize) -> usize {
+        self.rope.char_to_line(char_idx)
+    }
+
+    fn char_to_byte(&self, char_idx: usize) -> usize {
+        self.rope.char_to_byte(char_idx)
+    }
+
+    fn byte_to_char(&self, byte_idx: usize) -> usize {
+        self.rope.byte_to_char(byte_idx)
+    }
+
+    fn insert(&mut self, char_idx: usize, text: &str) {
+        self.rope.insert(char_idx, text);
+    }
+
+    fn remove(&mut self, range: Range<usize>) {
+        self.rope.remove(range);
+    }
+
+    fn slice(&self, range: Range<usize>) -> String {
+        self.rope.slice(range).to_string()
+    }
+
+    fn text(&self) -> String {
+        self.rope.to_string()
+    }
+
+    fn chunk_at_byte(&self, byte_idx: usize) -> &str {
+        let (chunk, chunk_start, _, _) = self.rope.chunk_at_byte(byte_idx);
+        &chunk[byte_idx - chunk_start..]
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        for chunk in self.rope.chunks() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RopeBuffer, TextBuffer};
+
+    #[test]
+    fn conversions() {
+        // 'é' takes two bytes and '🦀' four.
+        let buffer = RopeBuffer::from_lines(["héllo", "", "🦀x"].into_iter());
+        assert_eq!((buffer.len_chars(), buffer.len_bytes(), buffer.len_lines()), (9, 13, 3));
+        assert_eq!(buffer.line_to_char(1), 6);
+        assert_eq!(buffer.line_to_char(2), 7);
+        assert_eq!(buffer.char_to_line(5), 0);
+        assert_eq!(buffer.char_to_line(6), 1);
+        assert_eq!(buffer.char_to_byte(2), 3);
+        assert_eq!(buffer.char_to_byte(8), 12);
+        assert_eq!(buffer.byte_to_char(12), 8);
+        assert_eq!(buffer.char_to_pos(8), (2, 1));
+        assert_eq!(buffer.pos_to_char(2, 1), 8);
+        assert_eq!(buffer.char_at(7), '🦀');
+        assert_eq!(buffer.slice(4..9), "o\n\n🦀x");
+    }
+
+    #[test]
+    fn line_endings_are_not_in_lines() {
+        let mut buffer = RopeBuffer::from_lines(["ab", "c"].into_iter());
+        assert_eq!((buffer.line(0), buffer.line_len(0)), ("ab".to_string(), 2));
+        assert_eq!((buffer.line(1), buffer.line_len(1)), ("c".to_string(), 1));
+        // A text ending in '\n' has an empty last line.
+        buffer.insert(4, "\n");
+        assert_eq!(buffer.len_lines(), 3);
+        assert_eq!((buffer.line(1), buffer.line_len(1)), ("c".to_string(), 1));
+        assert_eq!((buffer.line(2), buffer.line_len(2)), (String::new(), 0));
+        assert_eq!(buffer.line_to_char(2), buffer.len_chars());
+        assert_eq!(buffer.char_to_pos(buffer.len_chars()), (2, 0));
+        buffer.remove(2..5);
+        assert_eq!(buffer.text(), "ab");
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let buffer = RopeBuffer::new();
+        assert_eq!((buffer.len_chars(), buffer.len_lines()), (0, 1));
+        assert_eq!((buffer.line(0), buffer.line_len(0)), (String::new(), 0));
+        assert_eq!(buffer.char_to_pos(0), (0, 0));
+        assert_eq!(buffer.chunk_at_byte(0), "");
+    }
+
+    #[test]
+    fn chunks_and_writing() {
+        let buffer = RopeBuffer::from_lines(["one", "two"].into_iter());
+        assert_eq!(buffer.chunk_at_byte(4), "two");
+        let mut written = Vec::new();
+        buffer.write_to(&mut written).unwrap();
+        assert_eq!(written, b"one\ntwo");
+    }
+}
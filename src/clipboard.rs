@@ -0,0 +1,289 @@
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crossterm::execute;
+
+// Helpers tried when config.lua doesn't name any: the variable set when
+// their display server runs (if any), then the copy and paste commands.
+const HELPERS: &[(&str, &str, &str)] = &[
+    ("WAYLAND_DISPLAY", "wl-copy", "wl-paste --no-newline"),
+    ("DISPLAY", "xclip -selection clipboard", "xclip -selection clipboard -o"),
+    ("DISPLAY", "xsel --clipboard --input", "xsel --clipboard --output"),
+    ("", "pbcopy", "pbpaste"),
+];
+
+// How long a helper gets before it is given up on, so a stuck one can't
+// hang the editor.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// OSC 52 has the terminal set the clipboard itself, which also works over
+// ssh and through tmux with its default `set-clipboard external`.
+struct SetClipboard<'a>(&'a str);
+
+impl crossterm::Command for SetClipboard<'_> {
+    fn write_ansi(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        write!(f, "\x1b]52;c;{}\x07", STANDARD.encode(self.0))
+    }
+
+    #[cfg(windows)]
+    fn execute_winapi(&self) -> crossterm::Result<()> {
+        Ok(())
+    }
+}
+
+// The system clipboard. Copies go out through OSC 52 and the copy helper;
+// the terminal can't be asked for the clipboard back, so pasting needs the
+// paste helper.
+#[derive(Default)]
+pub struct Clipboard {
+    pub osc52: bool,
+    // Whether yanks to the unnamed register go to the clipboard as well as
+    // ones to `"+`.
+    pub unnamed: bool,
+    pub copy: Option<String>,
+    pub paste: Option<String>,
+    // The copy helper runs on a thread of its own, so a slow one doesn't
+    // hold up editing; texts for it queue here.
+    copies: Option<Sender<String>>,
+    progress: Arc<(Mutex<Progress>, Condvar)>,
+}
+
+// Copies the helper thread hasn't finished, and the last one that failed.
+#[derive(Default)]
+struct Progress {
+    pending: usize,
+    error: Option<String>,
+}
+
+impl Clipboard {
+    pub fn new(osc52: bool, unnamed: bool, copy: Option<String>, paste: Option<String>) -> Self {
+        let detected = if copy.is_none() && paste.is_none() { detect() } else { None };
+        let (copy, paste) = match detected {
+            Some((copy, paste)) => (Some(copy.to_string()), Some(paste.to_string())),
+            None => (copy, paste),
+        };
+        Self { osc52, unnamed, copy, paste, ..Self::default() }
+    }
+
+    // Errors of the copy helper come later, from `take_error`.
+    pub fn copy(&mut self, text: &str) -> io::Result<()> {
+        if self.osc52 {
+            execute!(io::stdout(), SetClipboard(text))?;
+        }
+        let command = match &self.copy {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        let progress = self.progress.clone();
+        let copies = self.copies.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<String>();
+            thread::spawn(move || {
+                for text in receiver {
+                    let result = run(&command, Some(&text), false);
+                    let (lock, done) = &*progress;
+                    let mut progress = lock.lock().unwrap();
+                    progress.pending -= 1;
+                    if let Err(e) = result {
+                        progress.error = Some(e.to_string());
+                    }
+                    done.notify_all();
+                }
+            });
+            sender
+        });
+        self.progress.0.lock().unwrap().pending += 1;
+        if copies.send(text.to_string()).is_err() {
+            self.progress.0.lock().unwrap().pending -= 1;
+        }
+        Ok(())
+    }
+
+    // Waits, for as long as a helper gets, for the copies still running.
+    fn wait_for_copies(&self) {
+        let (lock, done) = &*self.progress;
+        let progress = lock.lock().unwrap();
+        let _ = done.wait_timeout_while(progress, TIMEOUT, |progress| progress.pending > 0);
+    }
+
+    // None when there is no paste helper to ask. A copy still on its way
+    // goes first, so a yank is pasted rather than what it replaced.
+    pub fn paste(&self) -> Option<io::Result<String>> {
+        let command = self.paste.as_ref()?;
+        self.wait_for_copies();
+        Some(run(command, None, true).map(|output| String::from_utf8_lossy(&output).into_owned()))
+    }
+
+    pub fn take_error(&self) -> Option<String> {
+        self.progress.0.lock().unwrap().error.take()
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command).stderr(Stdio::null());
+    shell
+}
+
+// Runs a helper with `input` on its stdin, returning what it printed when
+// `output` is wanted. Copy helpers that stay around to serve the selection
+// keep their stdout, so it isn't a pipe for them.
+fn run(command: &str, input: Option<&str>, output: bool) -> io::Result<Vec<u8>> {
+    let mut child = shell(command)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(if output { Stdio::piped() } else { Stdio::null() })
+        .spawn()?;
+    let (stdin, stdout, input) = (child.stdin.take(), child.stdout.take(), input.map(str::to_string));
+    // The pipes are fed and drained on a thread, which a helper that stops
+    // reading or never closes its output blocks instead of the editor.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+        let mut bytes = Vec::new();
+        let result = match stdout {
+            Some(mut stdout) => stdout.read_to_end(&mut bytes).map(|_| bytes),
+            None => Ok(bytes),
+        };
+        let _ = sender.send(result);
+    });
+    let deadline = Instant::now() + TIMEOUT;
+    let bytes = receiver.recv_timeout(TIMEOUT);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            break None;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    match (status, bytes) {
+        (Some(status), Ok(bytes)) if status.success() => bytes,
+        (Some(_), Ok(_)) => Err(io::Error::other(format!("`{}` failed", command))),
+        _ => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("`{}` timed out", command)))
+        }
+    }
+}
+
+fn detect() -> Option<(&'static str, &'static str)> {
+    let path = env::var_os("PATH")?;
+    let installed = |command: &str| {
+        let program = command.split(' ').next().unwrap_or(command);
+        env::split_paths(&path).any(|dir| Path::new(&dir).join(program).is_file())
+    };
+    HELPERS.iter()
+        .find(|(display, copy, _)| (display.is_empty() || env::var_os(display).is_some()) && installed(copy))
+        .map(|&(_, copy, paste)| (copy, paste))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process};
+
+    use super::Clipboard;
+    use crate::register::{Register, Registers};
+    use crate::Atto;
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    // A clipboard whose helper is a script keeping the text in a file next
+    // to it, and that file.
+    fn fake_clipboard() -> (Clipboard, String) {
+        let dir = env::temp_dir().join(format!("atto-clipboard-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        let helper = dir.join("helper");
+        fs::write(&helper, "case $1 in\n  copy) cat > \"$(dirname \"$0\")/clip\" ;;\n  paste) cat \"$(dirname \"$0\")/clip\" ;;\nesac\n").unwrap();
+        let helper = helper.display().to_string();
+        let clipboard = Clipboard::new(false, true, Some(format!("sh {} copy", helper)), Some(format!("sh {} paste", helper)));
+        (clipboard, dir.join("clip").display().to_string())
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut clipboard, file) = fake_clipboard();
+        clipboard.copy("héllo\nworld").unwrap();
+        clipboard.wait_for_copies();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "héllo\nworld");
+        assert_eq!(clipboard.paste().unwrap().unwrap(), "héllo\nworld");
+        fs::remove_dir_all(file.trim_end_matches("clip")).unwrap();
+    }
+
+    #[test]
+    fn registers_through_the_helper() {
+        let (clipboard, file) = fake_clipboard();
+        let mut registers = Registers::default();
+        registers.clipboard = clipboard;
+        registers.store(None, Register { text: "yanked\n".to_string(), linewise: true }, true);
+        registers.clipboard.wait_for_copies();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "yanked\n");
+        let register = registers.get(Some('+')).unwrap();
+        assert_eq!((register.text.as_str(), register.linewise), ("yanked\n", true));
+        // Copied in another program since.
+        fs::write(&file, "elsewhere").unwrap();
+        let register = registers.get(None).unwrap();
+        assert_eq!((register.text.as_str(), register.linewise), ("elsewhere", false));
+        assert_eq!(registers.get(Some('0')).unwrap().text, "yanked\n");
+        assert!(registers.take_error().is_none());
+        fs::remove_dir_all(file.trim_end_matches("clip")).unwrap();
+    }
+
+    #[test]
+    fn stuck_helpers_time_out() {
+        let mut clipboard = Clipboard::new(false, true, Some("sleep 5".to_string()), Some("sleep 5".to_string()));
+        let start = Instant::now();
+        // Copying doesn't wait for the helper at all.
+        clipboard.copy(&"x".repeat(1 << 20)).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(clipboard.paste().unwrap().is_err());
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(clipboard.take_error().as_deref(), Some("`sleep 5` timed out"));
+    }
+
+    #[test]
+    fn failing_helper() {
+        let mut clipboard = Clipboard::new(false, true, Some("exit 1".to_string()), Some("exit 1".to_string()));
+        clipboard.copy("x").unwrap();
+        clipboard.wait_for_copies();
+        assert_eq!(clipboard.take_error().as_deref(), Some("`exit 1` failed"));
+        let mut registers = Registers::default();
+        registers.clipboard = clipboard;
+        registers.store(Some('a'), Register { text: "kept".to_string(), linewise: false }, true);
+        // The clipboard doesn't have it, so it isn't asked.
+        assert_eq!(registers.get(None).unwrap().text, "kept");
+        assert!(registers.take_error().is_none());
+        assert!(registers.get(Some('+')).is_none());
+        assert_eq!(registers.take_error().as_deref(), Some("Clipboard error: `exit 1` failed"));
+    }
+
+    #[test]
+    fn only_yanks_reach_the_clipboard() {
+        let (clipboard, file) = fake_clipboard();
+        let mut atto = Atto::scratch("one\ntwo\nthree", true);
+        atto.registers.clipboard = clipboard;
+        atto.type_keys("ddx");
+        atto.registers.clipboard.wait_for_copies();
+        assert!(fs::metadata(&file).is_err());
+        atto.type_keys("yy");
+        atto.registers.clipboard.wait_for_copies();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "wo\n");
+        // Deleted text is still put from the unnamed register.
+        atto.type_keys("jddkp");
+        assert_eq!(atto.doc().buffer.text(), "wo\nthree");
+        fs::remove_dir_all(file.trim_end_matches("clip")).unwrap();
+    }
+}
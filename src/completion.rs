@@ -0,0 +1,530 @@
+use std::collections::HashSet;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use tui::backend::Backend;
+use tui::layout::Rect;
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Clear, List, ListItem, ListState};
+
+use crate::{unicode, Atto, Mode};
+
+// Words typed into a buffer start completing after this many chars.
+const MIN_PREFIX: usize = 2;
+const MIN_WORD: usize = 3;
+const MAX_WORDS: usize = 10_000;
+// Buffer words come from this many lines either side of the cursor, so the
+// popup opens as quickly in a huge log as in a small file.
+const WORD_LINES: usize = 2_000;
+const POPUP_HEIGHT: usize = 10;
+const POPUP_WIDTH: usize = 60;
+
+#[derive(Clone)]
+pub struct Item {
+    pub label: String,
+    pub text: String,
+    // Where the item comes from, or what the language server says it is.
+    pub detail: String,
+    // `text` has `$1` / `${1:default}` placeholders.
+    pub snippet: bool,
+}
+
+// The candidates for the word or path being typed, filtered by what has
+// been typed of it so far.
+pub struct Completion {
+    pub start: usize,
+    line: usize,
+    path: bool,
+    items: Vec<Item>,
+    matches: Vec<usize>,
+    selected: usize,
+}
+
+// The placeholders of an inserted snippet that Tab hasn't reached yet, with
+// the end of the last one visited and the document length then, to follow
+// the edits made since.
+pub struct Snippet {
+    stops: Vec<Range<usize>>,
+    at: usize,
+    len: usize,
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn word_start(chars: &[char], col: usize) -> usize {
+    let mut start = col;
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    start
+}
+
+// Where the last component of a path ending at `col` starts, if the text
+// before the cursor looks like a path at all.
+fn path_start(chars: &[char], col: usize) -> Option<(usize, usize)> {
+    let mut start = col;
+    while start > 0 && !chars[start - 1].is_whitespace() && !"\"'`()[]{}<>=,;".contains(chars[start - 1]) {
+        start -= 1;
+    }
+    let slash = chars[start..col].iter().rposition(|&c| c == '/')?;
+    Some((start, start + slash + 1))
+}
+
+fn path_items(dir: &str, hidden: bool) -> Vec<Item> {
+    let path = match dir.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+        None if dir.is_empty() => PathBuf::from("."),
+        None => PathBuf::from(dir),
+    };
+    let mut items: Vec<Item> = fs::read_dir(path).into_iter().flatten().filter_map(|entry| {
+        let entry = entry.ok()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !hidden {
+            return None;
+        }
+        let dir = entry.path().is_dir();
+        let text = if dir { format!("{}/", name) } else { name };
+        Some(Item { label: text.clone(), text, detail: if dir { "dir" } else { "file" }.to_string(), snippet: false })
+    }).collect();
+    items.sort_by(|a, b| a.label.cmp(&b.label));
+    items
+}
+
+// Expands LSP snippet syntax into plain text and the char ranges of its
+// tab stops, in the order Tab visits them with `$0` last.
+pub fn expand_snippet(snippet: &str) -> (String, Vec<Range<usize>>) {
+    let mut text = String::new();
+    let mut stops = Vec::new();
+    expand_into(snippet, &mut text, &mut stops);
+    stops.sort_by_key(|(n, _)| if *n == 0 { usize::MAX } else { *n });
+    let mut seen = HashSet::new();
+    let ranges = stops.into_iter().filter(|(n, _)| seen.insert(*n)).map(|(_, range)| range).collect();
+    (text, ranges)
+}
+
+fn expand_into(snippet: &str, text: &mut String, stops: &mut Vec<(usize, Range<usize>)>) {
+    let chars: Vec<char> = snippet.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                text.push(chars[i + 1]);
+                i += 2;
+            }
+            '$' if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                let digits: String = chars[i + 1..].iter().take_while(|c| c.is_ascii_digit()).collect();
+                let at = text.chars().count();
+                stops.push((digits.parse().unwrap_or(0), at..at));
+                i += 1 + digits.len();
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                // The matching brace, skipping nested placeholders.
+                let mut depth = 0;
+                let mut end = i + 1;
+                while end < chars.len() {
+                    match chars[end] {
+                        '\\' => end += 1,
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    end += 1;
+                }
+                let inner: String = chars[i + 2..end.min(chars.len())].iter().collect();
+                let name_len = inner.chars().take_while(|c| c.is_alphanumeric() || *c == '_').count();
+                let (name, rest) = inner.split_at(inner.char_indices().nth(name_len).map_or(inner.len(), |(b, _)| b));
+                let start = text.chars().count();
+                if let Some(default) = rest.strip_prefix(':') {
+                    expand_into(default, text, stops);
+                } else if let Some(choices) = rest.strip_prefix('|') {
+                    text.push_str(choices.trim_end_matches('|').split(',').next().unwrap_or(""));
+                }
+                if let Ok(n) = name.parse() {
+                    stops.push((n, start..text.chars().count()));
+                }
+                i = end + 1;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Completion {
+    fn filter(&mut self, prefix: &str) {
+        let matcher = SkimMatcherV2::default().smart_case();
+        let mut scored: Vec<(i64, usize)> = self.items.iter().enumerate().filter_map(|(index, item)| {
+            if prefix.is_empty() {
+                return Some((0, index));
+            }
+            if item.label == prefix {
+                return None;
+            }
+            Some((matcher.fuzzy_match(&item.label, prefix)?, index))
+        }).collect();
+        scored.sort_by(|(a, a_index), (b, b_index)| {
+            b.cmp(a).then_with(|| self.items[*a_index].label.len().cmp(&self.items[*b_index].label.len()))
+        });
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.selected = 0;
+    }
+
+    fn visible(&self) -> bool {
+        !self.matches.is_empty()
+    }
+}
+
+impl Atto {
+    // The typed part of the word or path being completed.
+    fn completion_prefix(&self) -> Option<String> {
+        let completion = self.completion.as_ref()?;
+        let buffer = self.doc().buffer.as_ref();
+        let cursor = self.cursor_char();
+        if self.cursor_y != completion.line || cursor < completion.start {
+            return None;
+        }
+        let prefix = buffer.slice(completion.start..cursor);
+        let valid = if completion.path { !prefix.contains(['/', ' ', '\t']) } else { prefix.chars().all(is_word) };
+        valid.then_some(prefix)
+    }
+
+    // Gathers candidates for the text before the cursor: file names after a
+    // '/', otherwise words from every buffer, then the language server and
+    // plugin sources.
+    pub fn open_completion(&mut self, manual: bool) {
+        let line: Vec<char> = self.doc().buffer.line(self.cursor_y).chars().collect();
+        let col = self.cursor_x.min(line.len());
+        let line_start = self.doc().buffer.line_to_char(self.cursor_y);
+        let (start, mut items, path) = match path_start(&line, col) {
+            Some((path_start, name_start)) => {
+                let dir: String = line[path_start..name_start].iter().collect();
+                let hidden = line.get(name_start) == Some(&'.');
+                (name_start, path_items(&dir, hidden), true)
+            }
+            None => {
+                let start = word_start(&line, col);
+                // Right after a '.' and the like only the server knows what fits.
+                let triggered = start == col && col > 0 && self.lsp_triggers_completion(line[col - 1]);
+                if !manual && !triggered && col - start < MIN_PREFIX {
+                    return;
+                }
+                let words = if triggered { Vec::new() } else { self.buffer_words(line_start + start) };
+                (start, words, false)
+            }
+        };
+        let prefix: String = line[start..col].iter().collect();
+        items.extend(self.plugin_completions(&prefix));
+        self.completion = Some(Completion { start: line_start + start, line: self.cursor_y, path, items, matches: Vec::new(), selected: 0 });
+        if !path {
+            self.lsp_complete(line_start + start);
+        }
+        self.refilter_completion();
+        if manual && !self.completion.as_ref().is_some_and(Completion::visible) && !self.lsp_completing() {
+            self.status_message = Some("No completions".to_string());
+        }
+    }
+
+    // Words near the cursor in the current buffer, then near where it was
+    // left in the others, leaving out the one being typed at `skip`.
+    fn buffer_words(&self, skip: usize) -> Vec<Item> {
+        let mut seen = HashSet::new();
+        let mut items = Vec::new();
+        let others = self.documents.iter().enumerate().filter(|(i, _)| *i != self.current).map(|(_, d)| (d, d.cursor.0));
+        for (n, (doc, around)) in std::iter::once((self.doc(), self.cursor_y)).chain(others).enumerate() {
+            let first = around.saturating_sub(WORD_LINES);
+            let last = (around + WORD_LINES).min(doc.buffer.len_lines() - 1);
+            for line_idx in first..=last {
+                let line = doc.buffer.line(line_idx);
+                let line_start = doc.buffer.line_to_char(line_idx);
+                let mut col = 0;
+                for word in line.split(|c: char| !is_word(c)) {
+                    let at = line_start + col;
+                    col += word.chars().count() + 1;
+                    let typed = n == 0 && at == skip;
+                    if word.chars().count() < MIN_WORD || typed || !seen.insert(word.to_string()) {
+                        continue;
+                    }
+                    items.push(Item { label: word.to_string(), text: word.to_string(), detail: "word".to_string(), snippet: false });
+                    if items.len() >= MAX_WORDS {
+                        return items;
+                    }
+                }
+            }
+        }
+        items
+    }
+
+    // Items the language server sent for the completion started at `start`.
+    pub fn add_completions(&mut self, start: usize, items: Vec<Item>) {
+        match self.completion.as_mut() {
+            Some(completion) if completion.start == start => {
+                // The server's items go first, ahead of plain buffer words.
+                let labels: HashSet<String> = items.iter().map(|item| item.label.clone()).collect();
+                completion.items.retain(|item| item.detail != "word" || !labels.contains(&item.label));
+                completion.items.splice(0..0, items);
+            }
+            _ => return,
+        }
+        self.refilter_completion();
+    }
+
+    fn refilter_completion(&mut self) {
+        match self.completion_prefix() {
+            Some(prefix) => self.completion.as_mut().unwrap().filter(&prefix),
+            None => self.completion = None,
+        }
+    }
+
+    // Keys for the popup itself while it shows; false for keys it doesn't
+    // take, which are handled as usual.
+    pub fn completion_key(&mut self, key: KeyEvent) -> bool {
+        if !self.completion.as_ref().is_some_and(Completion::visible) || self.mode != Mode::Insert {
+            return false;
+        }
+        // The cursor left the word some way the popup didn't follow.
+        if self.completion_prefix().is_none() {
+            self.completion = None;
+            return false;
+        }
+        let completion = self.completion.as_mut().unwrap();
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let next = (completion.selected + 1) % completion.matches.len();
+        let prev = completion.selected.checked_sub(1).unwrap_or(completion.matches.len() - 1);
+        match key.code {
+            KeyCode::Tab | KeyCode::Enter => self.accept_completion(),
+            KeyCode::Down => completion.selected = next,
+            KeyCode::Char('n') if ctrl => completion.selected = next,
+            KeyCode::Up | KeyCode::BackTab => completion.selected = prev,
+            KeyCode::Char('p') if ctrl => completion.selected = prev,
+            KeyCode::Esc => {
+                self.completion = None;
+                return false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // After each key: follows the typing with the filter, closes the popup
+    // once the cursor leaves the word, or opens it on a word long enough, a
+    // '/' or a character the language server completes after.
+    pub fn update_completion(&mut self, key: KeyEvent) {
+        if self.mode != Mode::Insert || self.prompt.is_some() || self.picker.is_some() || self.selections.len() > 1 {
+            self.completion = None;
+            self.snippet = None;
+            return;
+        }
+        if self.completion.is_some() {
+            self.refilter_completion();
+            if self.completion.is_some() {
+                return;
+            }
+        }
+        let typed = match key.code {
+            KeyCode::Char(c) if self.changed && !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+            _ => return,
+        };
+        if is_word(typed) || typed == '/' || self.lsp_triggers_completion(typed) {
+            self.open_completion(false);
+        }
+    }
+
+    fn accept_completion(&mut self) {
+        if self.completion_prefix().is_none() {
+            self.completion = None;
+            return;
+        }
+        let completion = self.completion.take().unwrap();
+        let item = &completion.items[completion.matches[completion.selected]];
+        let (text, stops) = if item.snippet { expand_snippet(&item.text) } else { (item.text.clone(), Vec::new()) };
+        let cursor = self.cursor_char();
+        let grouped = self.doc().history.grouping();
+        if !grouped {
+            self.doc_mut().history.group();
+        }
+        self.remove_text(completion.start..cursor);
+        self.insert_text(completion.start, &text);
+        if !grouped {
+            self.doc_mut().history.seal();
+        }
+        self.set_cursor_char(completion.start + text.chars().count());
+        if completion.path && text.ends_with('/') {
+            self.open_completion(false);
+        } else if !stops.is_empty() {
+            let stops = stops.into_iter().map(|stop| completion.start + stop.start..completion.start + stop.end).collect();
+            self.snippet = Some(Snippet { stops, at: 0, len: self.doc().buffer.len_chars() });
+            self.next_snippet_stop();
+        }
+    }
+
+    // Moves to the next placeholder of the last snippet, selecting its
+    // default text so typing replaces it; false when there is none left.
+    pub fn next_snippet_stop(&mut self) -> bool {
+        let len = self.doc().buffer.len_chars();
+        let snippet = match self.snippet.as_mut() {
+            Some(snippet) if !snippet.stops.is_empty() => snippet,
+            _ => {
+                self.snippet = None;
+                return false;
+            }
+        };
+        // What was typed over the last placeholder moves the ones after it.
+        let shift = len as isize - snippet.len as isize;
+        for stop in snippet.stops.iter_mut().filter(|stop| stop.start >= snippet.at) {
+            *stop = stop.start.saturating_add_signed(shift).min(len)..stop.end.saturating_add_signed(shift).min(len);
+        }
+        let stop = snippet.stops.remove(0);
+        snippet.at = stop.end;
+        snippet.len = len;
+        if snippet.stops.is_empty() {
+            self.snippet = None;
+        }
+        self.set_cursor_char(stop.end);
+        self.selection_start = (!stop.is_empty()).then(|| self.doc().buffer.char_to_pos(stop.start));
+        true
+    }
+
+    pub fn render_completion<B: Backend>(&self, f: &mut tui::Frame<B>, size: Rect) {
+        let completion = match &self.completion {
+            Some(completion) if completion.visible() && self.completion_prefix().is_some() => completion,
+            _ => return,
+        };
+        let height = completion.matches.len().min(POPUP_HEIGHT);
+        let first = (completion.selected + 1).saturating_sub(height);
+        let shown: Vec<&Item> = completion.matches[first..first + height].iter().map(|&i| &completion.items[i]).collect();
+        let label_width = shown.iter().map(|item| unicode::display_col(&item.label, usize::MAX)).max().unwrap_or(0);
+        let detail_width = shown.iter().map(|item| item.detail.chars().count()).max().unwrap_or(0);
+        let width = (label_width + detail_width + 3).min(POPUP_WIDTH).min(size.width as usize) as u16;
+
+        // Lined up under the start of the word.
+        let (x, y) = self.cursor_screen_position();
+        let line = self.doc().buffer.line(self.cursor_y);
+        let start_col = completion.start - self.doc().buffer.line_to_char(self.cursor_y);
+        let back = self.display_x().saturating_sub(unicode::display_col(&line, start_col)) as u16;
+        let x = x.saturating_sub(back).min(size.width - width);
+        let height = height as u16;
+        let y = if y + 1 + height < size.height { y + 1 } else { y.saturating_sub(height) };
+        let area = Rect::new(x, y, width, height.min(size.height));
+
+        let label_width = (width as usize).saturating_sub(detail_width + 2).max(1);
+        let items: Vec<ListItem> = shown.iter().map(|item| {
+            let mut spans = vec![Span::raw(" ")];
+            spans.extend(unicode::visible_spans(&item.label, 0, label_width, &[]));
+            let pad = label_width.saturating_sub(unicode::display_col(&item.label, usize::MAX)) + 1;
+            spans.push(Span::raw(" ".repeat(pad)));
+            spans.push(Span::styled(item.detail.clone(), Style::default().fg(Color::Gray)));
+            ListItem::new(Spans::from(spans))
+        }).collect();
+        let list = List::new(items)
+            .style(Style::default().bg(Color::DarkGray).fg(Color::White))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default();
+        state.select(Some(completion.selected - first));
+        f.render_widget(Clear, area);
+        f.render_stateful_widget(list, area, &mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+    use super::{expand_snippet, WORD_LINES};
+    use crate::Atto;
+
+    #[test]
+    fn plain_snippet() {
+        assert_eq!(expand_snippet("foo()"), ("foo()".to_string(), vec![]));
+        assert_eq!(expand_snippet("a\\$1\\}"), ("a$1}".to_string(), vec![]));
+    }
+
+    #[test]
+    fn tab_stops_in_order_with_the_final_one_last() {
+        let (text, stops) = expand_snippet("fn $2($1) {$0}");
+        assert_eq!(text, "fn () {}");
+        assert_eq!(stops, vec![4..4, 3..3, 7..7]);
+    }
+
+    #[test]
+    fn placeholders() {
+        let (text, stops) = expand_snippet("for ${1:x} in ${2:iter${3:()}} {\n\t$0\n}");
+        assert_eq!(text, "for x in iter() {\n\t\n}");
+        assert_eq!(stops, vec![4..5, 9..15, 13..15, 19..19]);
+    }
+
+    #[test]
+    fn choices_and_repeated_stops() {
+        let (text, stops) = expand_snippet("${1|one,two|} ${1:again} $1");
+        assert_eq!(text, "one again ");
+        assert_eq!(stops, vec![0..3]);
+    }
+
+    #[test]
+    fn unicode_counts_chars() {
+        let (text, stops) = expand_snippet("é🦀${1:ü}");
+        assert_eq!(text, "é🦀ü");
+        assert_eq!(stops, vec![2..3]);
+    }
+
+    // An editor completing "fo" on the second line, from the word "foobar".
+    fn completing() -> Atto {
+        let mut atto = Atto::scratch("foobar\nx ", false);
+        atto.set_cursor((1, 2));
+        atto.type_keys("fo");
+        assert!(atto.completion.is_some());
+        atto
+    }
+
+    #[test]
+    fn accept() {
+        let mut atto = completing();
+        atto.type_keys("<Tab>");
+        assert_eq!(atto.doc().buffer.line(1), "x foobar");
+        assert!(atto.completion.is_none());
+    }
+
+    #[test]
+    fn cursor_moved_before_the_word() {
+        let mut atto = completing();
+        atto.set_cursor((1, 0));
+        assert!(!atto.completion_key(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE)));
+        assert!(atto.completion.is_none());
+        assert_eq!(atto.doc().buffer.line(1), "x fo");
+    }
+
+    #[test]
+    fn mouse_closes_the_popup() {
+        let mut atto = completing();
+        atto.handle_mouse(MouseEvent { kind: MouseEventKind::ScrollDown, column: 0, row: 0, modifiers: KeyModifiers::NONE });
+        assert!(atto.completion.is_none());
+        let mut atto = completing();
+        atto.handle_mouse(MouseEvent { kind: MouseEventKind::Down(MouseButton::Left), column: 0, row: 0, modifiers: KeyModifiers::NONE });
+        assert!(atto.completion.is_none());
+    }
+
+    #[test]
+    fn words_near_the_cursor() {
+        let mut text = vec!["faraway".to_string()];
+        text.extend((0..WORD_LINES).map(|_| String::new()));
+        text.push("nearby ".to_string());
+        let mut atto = Atto::scratch(&text.join("\n"), false);
+        atto.set_cursor((WORD_LINES + 1, 7));
+        atto.type_keys("<C-Space>");
+        let labels: Vec<&str> = atto.completion.as_ref().unwrap().items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, vec!["nearby"]);
+    }
+}
@@ -0,0 +1,553 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::buffer::{RopeBuffer, TextBuffer};
+use crate::encoding::{self, Encoding, LineEnding};
+use crate::git::{self, Sign};
+use crate::highlight::Syntax;
+use crate::history::{Edit, History};
+use crate::lsp::{self, Change, Diagnostic};
+use crate::watch::{self, Stamp};
+
+// Names tried for the temporary file a save writes first.
+const TEMP_ATTEMPTS: usize = 100;
+static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Document {
+    pub buffer: Box<dyn TextBuffer>,
+    pub filename: Option<String>,
+    pub history: History,
+    // `:ff`, `:fenc` or `:eol` changed how the file is written since it was
+    // saved or read.
+    pub format_changed: bool,
+    // Edited since the swap file was last written.
+    pub swap_pending: bool,
+    // How the file was written when it was read, to write it back the same
+    // way.
+    pub final_newline: bool,
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    // The file on disk as last seen, and whether it changed there since the
+    // buffer was read or saved.
+    pub disk: Option<Stamp>,
+    pub stale: bool,
+    // Where the cursor was left while the document was not being shown.
+    pub cursor: (usize, usize),
+    pub scroll_offset: usize,
+    pub syntax: Option<Syntax>,
+    // Edits not yet sent to the language server, once it has the document.
+    pub changes: Option<Vec<Change>>,
+    pub version: i32,
+    pub diagnostics: Vec<Diagnostic>,
+    // The lines of the file as staged in git, and how each line of the buffer
+    // differs from them.
+    pub git_base: Option<Vec<String>>,
+    pub git_signs: Vec<Option<Sign>>,
+    // Edited since the signs were worked out.
+    pub git_pending: bool,
+    // Bookmarked lines, in order; edits move them with their lines.
+    pub bookmarks: Vec<usize>,
+}
+
+impl Document {
+    pub fn new(filename: Option<String>) -> Self {
+        Self {
+            buffer: Box::new(RopeBuffer::new()),
+            filename: filename.clone(),
+            history: History::new(),
+            format_changed: false,
+            swap_pending: false,
+            final_newline: true,
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            disk: None,
+            stale: false,
+            cursor: (0, 0),
+            scroll_offset: 0,
+            syntax: filename.as_deref().and_then(Syntax::for_file),
+            changes: None,
+            version: 0,
+            diagnostics: Vec::new(),
+            git_base: None,
+            git_signs: Vec::new(),
+            git_pending: false,
+            bookmarks: Vec::new(),
+        }
+    }
+
+    pub fn open(filename: &str) -> io::Result<Self> {
+        let mut document = Document::new(Some(filename.to_string()));
+        if Path::new(filename).exists() {
+            document.read()?;
+        }
+        Ok(document)
+    }
+
+    pub fn read(&mut self) -> io::Result<()> {
+        if let Some(lines) = self.read_lines()? {
+            self.buffer = Box::new(RopeBuffer::from_lines(lines.iter().map(String::as_str)));
+            self.history = History::new();
+            if let Some(syntax) = self.syntax.as_mut() {
+                syntax.reset();
+            }
+            if let Some(changes) = self.changes.as_mut() {
+                changes.push(Change { range: None, text: self.buffer.text() });
+            }
+            self.read_done();
+        }
+        Ok(())
+    }
+
+    // Reads the file again as one edit, which undo takes back to the text
+    // from before. `cursor` is where undo puts the cursor.
+    pub fn reload(&mut self, cursor: (usize, usize)) -> io::Result<()> {
+        if let Some(lines) = self.read_lines()? {
+            let old: Vec<char> = self.buffer.text().chars().collect();
+            let new: Vec<char> = lines.join("\n").chars().collect();
+            // Only the part that differs is replaced, so bookmarks and
+            // diagnostics elsewhere stay where they were.
+            let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+            let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+            let removed: String = old[prefix..old.len() - suffix].iter().collect();
+            let inserted: String = new[prefix..new.len() - suffix].iter().collect();
+            if !removed.is_empty() || !inserted.is_empty() {
+                self.history.seal();
+                let edit = Edit { pos: prefix, removed: removed.clone(), inserted: inserted.clone() };
+                self.history.record(edit, cursor);
+                self.history.seal();
+                self.remove(prefix..prefix + removed.chars().count());
+                self.insert(prefix, &inserted);
+            }
+            self.history.mark_saved();
+            self.read_done();
+        }
+        Ok(())
+    }
+
+    // The lines of the file, taking note of how it is written; None for a
+    // document without a file.
+    fn read_lines(&mut self) -> io::Result<Option<Vec<String>>> {
+        let filename = match self.filename.as_ref() {
+            Some(filename) => filename,
+            None => return Ok(None),
+        };
+        // Taken first, so a change while reading is still noticed.
+        self.disk = watch::stamp(filename);
+        self.stale = false;
+        let mut bytes = Vec::new();
+        File::open(filename)?.read_to_end(&mut bytes)?;
+        let (contents, encoding) = encoding::decode(&bytes);
+        let line_ending = LineEnding::detect(&contents);
+        let (lines, final_newline) = encoding::split_lines(&contents, line_ending);
+        self.format_changed = false;
+        self.final_newline = final_newline;
+        self.line_ending = line_ending;
+        self.encoding = encoding;
+        Ok(Some(lines.into_iter().map(str::to_string).collect()))
+    }
+
+    fn read_done(&mut self) {
+        self.bookmarks.retain(|&line| line < self.buffer.len_lines());
+        self.read_git_base();
+    }
+
+    // Writes to a temporary file next to the target and renames it over the
+    // target once it is on disk, so a failed save leaves the file as it was.
+    pub fn write(&mut self) -> io::Result<()> {
+        let filename = match self.filename.as_deref() {
+            Some(filename) => filename,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no file name")),
+        };
+        let bytes = self.encode()?;
+        let path = resolve_links(Path::new(filename));
+        let metadata = fs::metadata(&path).ok();
+        // Without write access to the directory this fails rather than
+        // overwriting the file in place, which a crash could leave half written.
+        let (temp, file) = create_temp(&path)?;
+        let result = write_contents(&file, &bytes).and_then(|()| {
+            if let Some(metadata) = &metadata {
+                fs::set_permissions(&temp, metadata.permissions())?;
+                preserve_owner(&temp, metadata);
+            }
+            fs::rename(&temp, &path)
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        // The rename itself is only durable once the directory is synced.
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            let _ = File::open(dir).and_then(|dir| dir.sync_all());
+        }
+        self.saved(&path);
+        Ok(())
+    }
+
+    fn saved(&mut self, path: &Path) {
+        self.history.mark_saved();
+        self.format_changed = false;
+        self.disk = path.to_str().and_then(watch::stamp);
+        self.stale = false;
+        self.read_git_base();
+    }
+
+    fn read_git_base(&mut self) {
+        self.git_base = self.filename.as_deref().and_then(git::base).map(|bytes| {
+            let (text, _) = encoding::decode(&bytes);
+            let (lines, _) = encoding::split_lines(&text, LineEnding::detect(&text));
+            lines.into_iter().map(str::to_string).collect()
+        });
+        self.update_git_signs();
+    }
+
+    pub fn update_git_signs(&mut self) {
+        self.git_pending = false;
+        self.git_signs = match &self.git_base {
+            Some(base) => git::signs(base, &(0..self.buffer.len_lines()).map(|i| self.buffer.line(i)).collect::<Vec<_>>()),
+            None => Vec::new(),
+        };
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.buffer.write_to(&mut bytes)?;
+        // An empty buffer has no line to end.
+        if self.final_newline && !bytes.is_empty() {
+            bytes.push(b'\n');
+        }
+        if self.line_ending == LineEnding::Lf && self.encoding == Encoding::Utf8 {
+            return Ok(bytes);
+        }
+        let text = String::from_utf8_lossy(&bytes).replace('\n', self.line_ending.as_str());
+        encoding::encode(text, self.encoding)
+    }
+
+    // What the status bar shows of how the file is written, when it isn't
+    // UTF-8 with LF line endings and a final newline.
+    pub fn file_format(&self) -> String {
+        let mut flags = Vec::new();
+        if self.line_ending != LineEnding::Lf {
+            flags.push(self.line_ending.name());
+        }
+        if self.encoding != Encoding::Utf8 {
+            flags.push(self.encoding.name());
+        }
+        if !self.final_newline {
+            flags.push("noeol");
+        }
+        flags.join(" ")
+    }
+
+    pub fn insert(&mut self, pos: usize, text: &str) {
+        shift_diagnostics(&mut self.diagnostics, pos, 0, text);
+        splice(self.buffer.as_mut(), &mut self.syntax, &mut self.changes, &mut self.bookmarks, pos, 0, text);
+        self.swap_pending = true;
+        self.git_pending = true;
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        shift_diagnostics(&mut self.diagnostics, range.start, range.len(), "");
+        splice(self.buffer.as_mut(), &mut self.syntax, &mut self.changes, &mut self.bookmarks, range.start, range.len(), "");
+        self.swap_pending = true;
+        self.git_pending = true;
+    }
+
+    pub fn undo(&mut self) -> Option<(usize, usize)> {
+        let (buffer, syntax, changes, bookmarks) = (&mut self.buffer, &mut self.syntax, &mut self.changes, &mut self.bookmarks);
+        let diagnostics = &mut self.diagnostics;
+        let cursor = self.history.undo(|pos, len, text| {
+            shift_diagnostics(diagnostics, pos, len, text);
+            splice(buffer.as_mut(), syntax, changes, bookmarks, pos, len, text)
+        })?;
+        self.swap_pending = true;
+        self.git_pending = true;
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self) -> Option<(usize, usize)> {
+        let (buffer, syntax, changes, bookmarks) = (&mut self.buffer, &mut self.syntax, &mut self.changes, &mut self.bookmarks);
+        let diagnostics = &mut self.diagnostics;
+        let end = self.history.redo(|pos, len, text| {
+            shift_diagnostics(diagnostics, pos, len, text);
+            splice(buffer.as_mut(), syntax, changes, bookmarks, pos, len, text)
+        })?;
+        self.swap_pending = true;
+        self.git_pending = true;
+        Some(self.buffer.char_to_pos(end))
+    }
+
+    pub fn update_syntax(&mut self) {
+        if let Some(syntax) = self.syntax.as_mut() {
+            syntax.update(self.buffer.as_ref());
+        }
+    }
+
+    // Differs from the file on disk, as far as undo and redo are concerned:
+    // undoing back to the saved text leaves it unmodified.
+    pub fn modified(&self) -> bool {
+        self.history.modified() || self.format_changed
+    }
+
+    pub fn display_name(&self) -> String {
+        self.filename.clone().unwrap_or_else(|| "Untitled".to_string())
+    }
+}
+
+// Lines joined by a removal take their bookmarks along to the line they
+// joined, and lines pushed down by inserted line breaks keep theirs.
+fn shift_bookmarks(buffer: &dyn TextBuffer, bookmarks: &mut Vec<usize>, pos: usize, len: usize, text: &str) {
+    let first = buffer.char_to_line(pos);
+    let last = buffer.char_to_line(pos + len);
+    let breaks = text.matches('\n').count();
+    let at_start = pos == buffer.line_to_char(first);
+    for line in bookmarks.iter_mut() {
+        if *line > last {
+            *line -= last - first;
+        } else if *line > first {
+            *line = first;
+        }
+        if *line > first || (*line == first && at_start && len == 0) {
+            *line += breaks;
+        }
+    }
+    bookmarks.dedup();
+}
+
+// Diagnostics keep to the text they are about until the server sends new
+// ones; text typed at either end of one stays outside it.
+fn shift_diagnostics(diagnostics: &mut [Diagnostic], pos: usize, len: usize, text: &str) {
+    let inserted = text.chars().count();
+    let shift = |offset: usize, after: bool| {
+        if offset < pos || (offset == pos && !after) {
+            offset
+        } else if offset >= pos + len {
+            offset - len + inserted
+        } else {
+            pos
+        }
+    };
+    for diagnostic in diagnostics {
+        let start = shift(diagnostic.range.start, true);
+        diagnostic.range = start..shift(diagnostic.range.end, false).max(start);
+    }
+}
+
+fn write_contents(mut file: &File, bytes: &[u8]) -> io::Result<()> {
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+// The file a path ends up at through any symlinks, so saving replaces the
+// target rather than the link.
+fn resolve_links(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match fs::read_link(&path) {
+            Ok(target) => path = path.parent().unwrap_or(Path::new("")).join(target),
+            Err(_) => break,
+        }
+    }
+    path
+}
+
+// Opens a new temporary file next to `path`, under the first name no other
+// file has, such as one left behind by a crash.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    for _ in 0..TEMP_ATTEMPTS {
+        let count = TEMP_COUNT.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), count));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((temp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "no free name for a temporary file"))
+}
+
+// Gives the new file the old one's owner and group where allowed; without
+// the rights to, it keeps the saving user's.
+#[cfg(unix)]
+fn preserve_owner(path: &Path, metadata: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    if std::os::unix::fs::chown(path, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+        let _ = std::os::unix::fs::chown(path, None, Some(metadata.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn preserve_owner(_path: &Path, _metadata: &fs::Metadata) {}
+
+fn splice(buffer: &mut dyn TextBuffer, syntax: &mut Option<Syntax>, changes: &mut Option<Vec<Change>>, bookmarks: &mut Vec<usize>, pos: usize, len: usize, text: &str) {
+    if let Some(changes) = changes.as_mut() {
+        let range = (lsp::position(buffer, pos), lsp::position(buffer, pos + len));
+        changes.push(Change { range: Some(range), text: text.to_string() });
+    }
+    if !bookmarks.is_empty() {
+        shift_bookmarks(buffer, bookmarks, pos, len, text);
+    }
+    if len > 0 {
+        if let Some(syntax) = syntax.as_mut() {
+            syntax.edit_remove(buffer, pos..pos + len);
+        }
+        buffer.remove(pos..pos + len);
+    }
+    if !text.is_empty() {
+        if let Some(syntax) = syntax.as_mut() {
+            syntax.edit_insert(buffer, pos, text);
+        }
+        buffer.insert(pos, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{env, fs, process};
+
+    use super::{Document, TEMP_COUNT};
+    use crate::history::Edit;
+    use crate::Atto;
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("atto-document-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_past_leftover_temporary_files() {
+        let dir = temp_dir();
+        let path = dir.join("f.txt");
+        let next = TEMP_COUNT.load(Ordering::Relaxed);
+        for count in next..next + 5 {
+            fs::write(dir.join(format!(".f.txt.{}.{}.tmp", process::id(), count)), "left over").unwrap();
+        }
+        let mut document = Document::open(&path.display().to_string()).unwrap();
+        document.insert(0, "saved");
+        document.write().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "saved\n");
+        assert!(!document.modified());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 6);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_file() {
+        let dir = temp_dir();
+        let path = dir.join("empty.txt");
+        fs::write(&path, "").unwrap();
+        let mut document = Document::open(&path.display().to_string()).unwrap();
+        assert!(document.final_newline);
+        assert_eq!(document.file_format(), "");
+        document.write().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_keeps_links_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let target = dir.join("target.txt");
+        fs::write(&target, "old\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        let link = dir.join("link.txt");
+        std::os::unix::fs::symlink("target.txt", &link).unwrap();
+        let mut document = Document::open(&link.display().to_string()).unwrap();
+        document.insert(0, "new ");
+        document.write().unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new old\n");
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A document of lines "0" to "4" with bookmarks on `bookmarks`.
+    fn bookmarked(bookmarks: &[usize]) -> Document {
+        let mut document = Document::new(None);
+        document.buffer.insert(0, "0\n1\n2\n3\n4");
+        document.bookmarks = bookmarks.to_vec();
+        document
+    }
+
+    #[test]
+    fn line_breaks_push_bookmarks_down() {
+        let mut document = bookmarked(&[1, 3]);
+        // Inside line 1, which keeps its bookmark.
+        document.insert(3, "x\ny\n");
+        assert_eq!(document.bookmarks, vec![1, 5]);
+        // At the start of line 1, which moves down with it.
+        document.insert(2, "\n");
+        assert_eq!(document.bookmarks, vec![2, 6]);
+    }
+
+    #[test]
+    fn edits_within_a_line_keep_bookmarks() {
+        let mut document = bookmarked(&[0, 2]);
+        document.insert(4, "xyz");
+        document.remove(4..6);
+        assert_eq!(document.bookmarks, vec![0, 2]);
+    }
+
+    #[test]
+    fn joined_lines_keep_one_bookmark() {
+        let mut document = bookmarked(&[1, 2, 4]);
+        // Joins lines 1 to 3.
+        document.remove(3..6);
+        assert_eq!(document.buffer.text(), "0\n13\n4");
+        assert_eq!(document.bookmarks, vec![1, 2]);
+    }
+
+    #[test]
+    fn undo_puts_bookmarks_back() {
+        let mut document = bookmarked(&[3]);
+        document.history.record(Edit { pos: 0, removed: String::new(), inserted: "a\nb\n".to_string() }, (0, 0));
+        document.insert(0, "a\nb\n");
+        assert_eq!(document.bookmarks, vec![5]);
+        document.undo();
+        assert_eq!(document.bookmarks, vec![3]);
+    }
+
+    #[test]
+    fn undo_to_saved() {
+        let mut atto = Atto::scratch("", true);
+        atto.type_keys("ix<Esc>");
+        assert!(atto.doc().modified());
+        atto.type_keys("u");
+        assert!(!atto.doc().modified());
+        atto.type_keys("<C-r>");
+        assert!(atto.doc().modified());
+
+        let dir = temp_dir();
+        let path = dir.join("saved.txt");
+        fs::write(&path, "one\n").unwrap();
+        atto.documents[0] = Document::open(&path.display().to_string()).unwrap();
+        atto.type_keys("Atwo<Esc>:w<Enter>");
+        assert!(!atto.doc().modified());
+        atto.type_keys("u");
+        assert!(atto.doc().modified());
+        // Redoing past the saved text.
+        atto.type_keys("<C-r>");
+        assert!(!atto.doc().modified());
+        atto.type_keys("x");
+        assert!(atto.doc().modified());
+        atto.type_keys("u");
+        assert!(!atto.doc().modified());
+        assert_eq!(atto.doc().buffer.text(), "onetwo");
+        // A changed line ending needs saving too.
+        atto.type_keys(":ff crlf<Enter>");
+        assert!(atto.doc().modified());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
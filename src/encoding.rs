@@ -0,0 +1,253 @@
+use std::io;
+
+use crate::Atto;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+impl LineEnding {
+    // The first line break decides; text without one gets LF.
+    pub fn detect(text: &str) -> Self {
+        match text.find(['\n', '\r']).map(|i| &text.as_bytes()[i..]) {
+            Some([b'\r', b'\n', ..]) => LineEnding::Crlf,
+            Some([b'\r', ..]) => LineEnding::Cr,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "lf",
+            LineEnding::Crlf => "crlf",
+            LineEnding::Cr => "cr",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Some(LineEnding::Lf),
+            "crlf" | "dos" => Some(LineEnding::Crlf),
+            "cr" | "mac" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Bom => "utf-8-bom",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin-1",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Encoding::Utf8Bom),
+            "utf-16le" | "utf-16" | "ucs-2le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "ucs-2" => Some(Encoding::Utf16Be),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+}
+
+// Text in UTF-8 or UTF-16 says so with a byte order mark; without one it is
+// UTF-8 if it is valid as such. Anything not valid in the encoding it claims
+// is read as Latin-1, where any byte is, so writing it back changes nothing.
+pub fn decode(bytes: &[u8]) -> (String, Encoding) {
+    let decoded = match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => std::str::from_utf8(rest).ok().map(|text| (text.to_string(), Encoding::Utf8Bom)),
+        [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes).map(|text| (text, Encoding::Utf16Le)),
+        [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes).map(|text| (text, Encoding::Utf16Be)),
+        _ => std::str::from_utf8(bytes).ok().map(|text| (text.to_string(), Encoding::Utf8)),
+    };
+    decoded.unwrap_or_else(|| (bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1))
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).ok()
+}
+
+pub fn encode(text: String, encoding: Encoding) -> io::Result<Vec<u8>> {
+    Ok(match encoding {
+        Encoding::Utf8 => text.into_bytes(),
+        Encoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+        Encoding::Utf16Le => [0xFF, 0xFE].into_iter().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect(),
+        Encoding::Utf16Be => [0xFE, 0xFF].into_iter().chain(text.encode_utf16().flat_map(u16::to_be_bytes)).collect(),
+        Encoding::Latin1 => match text.chars().find(|&c| c as u32 > 0xFF) {
+            Some(c) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} can't be written as latin-1", c))),
+            None => text.chars().map(|c| c as u8).collect(),
+        },
+    })
+}
+
+// The lines of `text` and whether it ended with a line break, which an empty
+// text counts as. A '\n' always ends a line since the buffer can't hold one
+// otherwise, but a lone '\r' in a file that doesn't use it as a line ending
+// is kept.
+pub fn split_lines(text: &str, line_ending: LineEnding) -> (Vec<&str>, bool) {
+    let mut lines: Vec<&str> = match line_ending {
+        LineEnding::Lf => text.split('\n').collect(),
+        LineEnding::Crlf => text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)).collect(),
+        // Where '\r' ends lines a "\r\n" is still one line break.
+        LineEnding::Cr => {
+            let mut lines = Vec::new();
+            let mut rest = text;
+            while let Some(i) = rest.find(['\r', '\n']) {
+                lines.push(&rest[..i]);
+                rest = &rest[i + if rest[i..].starts_with("\r\n") { 2 } else { 1 }..];
+            }
+            lines.push(rest);
+            lines
+        }
+    };
+    let final_newline = lines.len() > 1 && lines.last() == Some(&"");
+    if final_newline {
+        lines.pop();
+    }
+    (lines, final_newline || text.is_empty())
+}
+
+impl Atto {
+    // `:ff`, `:fenc` and `:eol` change how the document is written from the
+    // next save on, or show the current setting without an argument.
+    pub fn set_line_ending(&mut self, arg: &str) {
+        match LineEnding::parse(arg) {
+            Some(line_ending) => {
+                self.doc_mut().line_ending = line_ending;
+                self.doc_mut().format_changed = true;
+            }
+            None if arg.is_empty() => self.status_message = Some(format!("Line endings: {}", self.doc().line_ending.name())),
+            None => self.status_message = Some(format!("Unknown line ending: {} (lf, crlf or cr)", arg)),
+        }
+    }
+
+    pub fn set_encoding(&mut self, arg: &str) {
+        match Encoding::parse(arg) {
+            Some(encoding) => {
+                self.doc_mut().encoding = encoding;
+                self.doc_mut().format_changed = true;
+            }
+            None if arg.is_empty() => self.status_message = Some(format!("Encoding: {}", self.doc().encoding.name())),
+            None => self.status_message = Some(format!("Unknown encoding: {} (utf-8, utf-8-bom, utf-16le, utf-16be or latin-1)", arg)),
+        }
+    }
+
+    pub fn set_final_newline(&mut self, arg: &str) {
+        let final_newline = match arg {
+            "on" => true,
+            "off" => false,
+            "" => {
+                let state = if self.doc().final_newline { "on" } else { "off" };
+                self.status_message = Some(format!("Final newline: {}", state));
+                return;
+            }
+            _ => {
+                self.status_message = Some("Usage: eol [on|off]".to_string());
+                return;
+            }
+        };
+        self.doc_mut().final_newline = final_newline;
+        self.doc_mut().format_changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, split_lines, Encoding, LineEnding};
+
+    // Decodes `bytes` as `expected` and checks encoding it again gives them
+    // back.
+    fn round_trip(bytes: &[u8], expected: (&str, Encoding)) {
+        let (text, encoding) = decode(bytes);
+        assert_eq!((text.as_str(), encoding), expected);
+        assert_eq!(encode(text, encoding).unwrap(), bytes);
+    }
+
+    #[test]
+    fn utf8() {
+        round_trip(b"h\xC3\xA9", ("hé", Encoding::Utf8));
+        round_trip(b"", ("", Encoding::Utf8));
+        round_trip(b"\xEF\xBB\xBFh\xC3\xA9", ("hé", Encoding::Utf8Bom));
+    }
+
+    #[test]
+    fn utf16() {
+        round_trip(b"\xFF\xFEh\x00\xE9\x00", ("hé", Encoding::Utf16Le));
+        round_trip(b"\xFE\xFF\x00h\x00\xE9\xD8\x3E\xDD\x80", ("hé🦀", Encoding::Utf16Be));
+    }
+
+    #[test]
+    fn invalid_text_is_latin1() {
+        round_trip(b"h\xE9", ("hé", Encoding::Latin1));
+        // A byte order mark followed by what isn't UTF-8.
+        round_trip(b"\xEF\xBB\xBFh\xE9", ("ï»¿hé", Encoding::Latin1));
+        // An odd number of bytes, or an unpaired surrogate.
+        round_trip(b"\xFF\xFEh\x00i", ("ÿþh\0i", Encoding::Latin1));
+        round_trip(b"\xFF\xFE\x3E\xD8h\x00", ("ÿþ>Øh\0", Encoding::Latin1));
+    }
+
+    #[test]
+    fn latin1_refuses_what_it_cannot_hold() {
+        assert!(encode("é".to_string(), Encoding::Latin1).is_ok());
+        assert!(encode("🦀".to_string(), Encoding::Latin1).is_err());
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(LineEnding::detect("a\nb\r\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\rb\r\n"), LineEnding::Cr);
+        assert_eq!(LineEnding::detect("abc"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(split_lines("a\nb\n", LineEnding::Lf), (vec!["a", "b"], true));
+        assert_eq!(split_lines("a\nb", LineEnding::Lf), (vec!["a", "b"], false));
+        assert_eq!(split_lines("a\n\n", LineEnding::Lf), (vec!["a", ""], true));
+        // A lone '\r' is kept where it doesn't end lines.
+        assert_eq!(split_lines("a\rb\n", LineEnding::Lf), (vec!["a\rb"], true));
+        assert_eq!(split_lines("a\r\nb\rc\r\n", LineEnding::Crlf), (vec!["a", "b\rc"], true));
+        assert_eq!(split_lines("a\rb\r\nc\r", LineEnding::Cr), (vec!["a", "b", "c"], true));
+        assert_eq!(split_lines("a\r\r\nb", LineEnding::Cr), (vec!["a", "", "b"], false));
+    }
+
+    #[test]
+    fn empty_text_has_a_final_newline() {
+        assert_eq!(split_lines("", LineEnding::Lf), (vec![""], true));
+        assert_eq!(split_lines("\n", LineEnding::Lf), (vec![""], true));
+    }
+}
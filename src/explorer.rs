@@ -0,0 +1,403 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crossterm::event::{KeyCode, KeyEvent};
+use tui::backend::Backend;
+use tui::layout::Rect;
+use tui::style::{Color, Modifier, Style};
+use tui::text::Span;
+use tui::widgets::{Block, Borders, List, ListItem, ListState};
+
+use crate::{Atto, Prompt};
+
+const WIDTH: u16 = 30;
+
+struct Entry {
+    path: PathBuf,
+    depth: usize,
+    dir: bool,
+}
+
+// The file tree shown left of the windows, rooted at the directory of the
+// file being edited when it was opened.
+pub struct Explorer {
+    root: PathBuf,
+    entries: Vec<Entry>,
+    expanded: HashSet<PathBuf>,
+    selected: usize,
+    pub area: Rect,
+    pub open: bool,
+    pub focused: bool,
+}
+
+// The empty path is the working directory; paths under it stay relative so
+// they name the same file as the buffers opened from the command line.
+fn read_dir(dir: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut children: Vec<(PathBuf, bool)> = fs::read_dir(listed)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name() != ".git")
+        .map(|entry| (dir.join(entry.file_name()), entry.path().is_dir()))
+        .collect();
+    children.sort_by(|(a, a_dir), (b, b_dir)| b_dir.cmp(a_dir).then_with(|| a.cmp(b)));
+    Ok(children)
+}
+
+fn name(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+impl Explorer {
+    pub fn new(root: PathBuf) -> Self {
+        let mut explorer = Self {
+            root,
+            entries: Vec::new(),
+            expanded: HashSet::new(),
+            selected: 0,
+            area: Rect::default(),
+            open: true,
+            focused: true,
+        };
+        explorer.refresh();
+        explorer
+    }
+
+    pub fn width(&self, screen: u16) -> u16 {
+        if self.open {
+            WIDTH.min(screen / 3)
+        } else {
+            0
+        }
+    }
+
+    // Lists the tree again from disk, keeping the selection on the same path
+    // when it is still there.
+    fn refresh(&mut self) {
+        let selected = self.entries.get(self.selected).map(|entry| entry.path.clone());
+        self.entries.clear();
+        self.list(self.root.clone(), 0);
+        self.expanded.retain(|path| path.is_dir());
+        self.select_path(selected.as_deref());
+    }
+
+    fn list(&mut self, dir: PathBuf, depth: usize) {
+        for (path, is_dir) in read_dir(&dir).unwrap_or_default() {
+            let expanded = is_dir && self.expanded.contains(&path);
+            self.entries.push(Entry { path: path.clone(), depth, dir: is_dir });
+            if expanded {
+                self.list(path, depth + 1);
+            }
+        }
+    }
+
+    fn select_path(&mut self, path: Option<&Path>) {
+        let index = path.and_then(|path| self.entries.iter().position(|entry| entry.path == path));
+        self.selected = index.unwrap_or(self.selected).min(self.entries.len().saturating_sub(1));
+    }
+
+    // The first entry shown, keeping the selected one in view.
+    fn scroll(&self) -> usize {
+        let visible = self.area.height.saturating_sub(1).max(1) as usize;
+        (self.selected + 1).saturating_sub(visible)
+    }
+
+    fn selected(&self) -> Option<&Entry> {
+        self.entries.get(self.selected)
+    }
+
+    // The directory new files go in: the selected one, or the one holding
+    // the selected file.
+    fn target_dir(&self) -> PathBuf {
+        match self.selected() {
+            Some(entry) if entry.dir => entry.path.clone(),
+            Some(entry) => entry.path.parent().map_or_else(|| self.root.clone(), Path::to_path_buf),
+            None => self.root.clone(),
+        }
+    }
+
+    fn set_expanded(&mut self, expanded: bool) {
+        let path = match self.selected() {
+            Some(entry) if entry.dir => entry.path.clone(),
+            _ => return,
+        };
+        if expanded {
+            self.expanded.insert(path);
+        } else {
+            self.expanded.remove(&path);
+        }
+        self.refresh();
+    }
+
+    // Collapses the selected directory, or moves up to the parent of the
+    // selected entry.
+    fn collapse(&mut self) {
+        let (path, depth) = match self.selected() {
+            Some(entry) if entry.dir && self.expanded.contains(&entry.path) => return self.set_expanded(false),
+            Some(entry) => (entry.path.clone(), entry.depth),
+            None => return,
+        };
+        if depth > 0 {
+            self.select_path(path.parent());
+        }
+    }
+}
+
+impl Atto {
+    // Opens the explorer on the directory of the current file, or focuses or
+    // closes it when it is already showing.
+    pub fn toggle_explorer(&mut self) {
+        match self.explorer.as_mut() {
+            Some(explorer) if explorer.open && !explorer.focused => explorer.focused = true,
+            Some(explorer) if explorer.open => explorer.open = false,
+            _ => {
+                let filename = self.doc().filename.clone().unwrap_or_default();
+                let root = Path::new(&filename).parent().map(Path::to_path_buf).unwrap_or_default();
+                let mut explorer = Explorer::new(root);
+                explorer.select_path(Some(Path::new(&filename)));
+                self.explorer = Some(explorer);
+            }
+        }
+    }
+
+    // Keys go to the explorer while it has focus.
+    pub fn explorer_key(&mut self, key: KeyEvent) {
+        let explorer = match self.explorer.as_mut() {
+            Some(explorer) => explorer,
+            None => return,
+        };
+        let last = explorer.entries.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => explorer.selected = explorer.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => explorer.selected = (explorer.selected + 1).min(last),
+            KeyCode::Home | KeyCode::Char('g') => explorer.selected = 0,
+            KeyCode::End | KeyCode::Char('G') => explorer.selected = last,
+            KeyCode::Left | KeyCode::Char('h') => explorer.collapse(),
+            KeyCode::Right | KeyCode::Char('l') => explorer.set_expanded(true),
+            KeyCode::Enter => self.explorer_open(),
+            KeyCode::Char('a') => {
+                let dir = explorer.target_dir();
+                self.prompt = Some(Prompt::NewFile);
+                self.command_input = if dir.as_os_str().is_empty() { String::new() } else { format!("{}/", dir.display()) };
+            }
+            KeyCode::Char('r') => {
+                if let Some(entry) = explorer.selected() {
+                    self.command_input = entry.path.display().to_string();
+                    self.prompt = Some(Prompt::Rename);
+                }
+            }
+            KeyCode::Char('d') if explorer.selected().is_some() => {
+                self.command_input.clear();
+                self.prompt = Some(Prompt::Delete);
+            }
+            KeyCode::Char('R') => explorer.refresh(),
+            KeyCode::Char(':') => {
+                self.prompt = Some(Prompt::Command);
+                self.command_input.clear();
+            }
+            KeyCode::Tab => explorer.focused = false,
+            KeyCode::Esc | KeyCode::Char('q') => explorer.open = false,
+            _ => {}
+        }
+    }
+
+    // Opens the selected file in the focused window, or expands or collapses
+    // the selected directory.
+    fn explorer_open(&mut self) {
+        let explorer = match self.explorer.as_mut() {
+            Some(explorer) => explorer,
+            None => return,
+        };
+        let (path, dir) = match explorer.selected() {
+            Some(entry) => (entry.path.clone(), entry.dir),
+            None => return,
+        };
+        if dir {
+            let expanded = explorer.expanded.contains(&path);
+            explorer.set_expanded(!expanded);
+        } else {
+            explorer.focused = false;
+            self.edit_file(&path.display().to_string());
+        }
+    }
+
+    // A path ending in '/' makes a directory.
+    pub fn explorer_create(&mut self, input: &str) {
+        let result = if input.ends_with('/') {
+            fs::create_dir_all(input)
+        } else {
+            let path = Path::new(input);
+            path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+                fs::OpenOptions::new().write(true).create_new(true).open(path).map(|_| ())
+            })
+        };
+        match result {
+            Ok(()) => self.explorer_changed(Path::new(input.trim_end_matches('/'))),
+            Err(e) => self.status_message = Some(format!("Error creating {}: {}", input, e)),
+        }
+    }
+
+    // Buffers of the renamed file, or of files in a renamed directory, follow
+    // it to the new name.
+    pub fn explorer_rename(&mut self, to: &str) {
+        let from = match self.explorer.as_ref().and_then(Explorer::selected) {
+            Some(entry) => entry.path.clone(),
+            None => return,
+        };
+        if let Err(e) = fs::rename(&from, to) {
+            self.status_message = Some(format!("Error renaming {}: {}", from.display(), e));
+            return;
+        }
+        for document in self.documents.iter_mut() {
+            let renamed = document.filename.as_deref().and_then(|filename| {
+                let rest = Path::new(filename).strip_prefix(&from).ok()?;
+                Some(Path::new(to).join(rest).display().to_string())
+            });
+            if let Some(renamed) = renamed {
+                document.filename = Some(renamed.trim_end_matches('/').to_string());
+            }
+        }
+        self.explorer_changed(Path::new(to));
+    }
+
+    pub fn explorer_delete(&mut self, answer: &str) {
+        if !answer.eq_ignore_ascii_case("y") {
+            return;
+        }
+        let (path, dir) = match self.explorer.as_ref().and_then(Explorer::selected) {
+            Some(entry) => (entry.path.clone(), entry.dir),
+            None => return,
+        };
+        let result = if dir { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        match result {
+            Ok(()) => {
+                self.status_message = Some(format!("Deleted {}", path.display()));
+                self.explorer_changed(&path);
+            }
+            Err(e) => self.status_message = Some(format!("Error deleting {}: {}", path.display(), e)),
+        }
+    }
+
+    // Shows `path` once it has been created, renamed or deleted.
+    fn explorer_changed(&mut self, path: &Path) {
+        if let Some(explorer) = self.explorer.as_mut() {
+            if let Some(parent) = path.parent().filter(|parent| parent.starts_with(&explorer.root)) {
+                for dir in parent.ancestors().take_while(|dir| *dir != explorer.root) {
+                    explorer.expanded.insert(dir.to_path_buf());
+                }
+            }
+            explorer.refresh();
+            explorer.select_path(Some(path));
+        }
+    }
+
+    // False when the click is outside of the explorer.
+    pub fn explorer_click(&mut self, column: u16, row: u16) -> bool {
+        let explorer = match self.explorer.as_mut() {
+            Some(explorer) if explorer.open => explorer,
+            _ => return false,
+        };
+        let area = explorer.area;
+        if column >= area.x + area.width || row < area.y || row >= area.y + area.height {
+            return false;
+        }
+        let index = explorer.scroll() + (row - area.y) as usize;
+        if row > area.y && index <= explorer.entries.len() {
+            explorer.selected = index - 1;
+            explorer.focused = true;
+            self.explorer_open();
+        }
+        true
+    }
+
+    pub fn render_explorer<B: Backend>(&self, f: &mut tui::Frame<B>) {
+        let explorer = match &self.explorer {
+            Some(explorer) if explorer.open => explorer,
+            _ => return,
+        };
+        let visible = explorer.area.height.saturating_sub(1).max(1) as usize;
+        let first = explorer.scroll();
+        let current = self.doc().filename.as_deref().map(Path::new);
+        let items: Vec<ListItem> = explorer.entries[first..].iter().take(visible).map(|entry| {
+            let marker = match (entry.dir, explorer.expanded.contains(&entry.path)) {
+                (true, true) => "▾ ",
+                (true, false) => "▸ ",
+                _ => "  ",
+            };
+            let slash = if entry.dir { "/" } else { "" };
+            let text = format!("{}{}{}{}", "  ".repeat(entry.depth), marker, name(&entry.path), slash);
+            let style = if current == Some(entry.path.as_path()) {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else if entry.dir {
+                Style::default().fg(Color::Blue)
+            } else {
+                Style::default()
+            };
+            ListItem::new(Span::styled(text, style))
+        }).collect();
+        let highlight = if explorer.focused {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().bg(Color::DarkGray)
+        };
+        let root = if explorer.root.as_os_str().is_empty() { ".".to_string() } else { explorer.root.display().to_string() };
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::RIGHT).title(Span::raw(root)))
+            .highlight_style(highlight);
+        let mut state = ListState::default();
+        state.select((!explorer.entries.is_empty()).then(|| explorer.selected - first));
+        f.render_stateful_widget(list, explorer.area, &mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs, process};
+
+    use crate::document::Document;
+    use crate::Atto;
+
+    fn listed(atto: &Atto, dir: &Path) -> Vec<String> {
+        let explorer = atto.explorer.as_ref().unwrap();
+        explorer.entries.iter().map(|entry| entry.path.strip_prefix(dir).unwrap().display().to_string()).collect()
+    }
+
+    fn selected(atto: &Atto, dir: &Path) -> String {
+        listed(atto, dir)[atto.explorer.as_ref().unwrap().selected].clone()
+    }
+
+    #[test]
+    fn create_rename_delete() {
+        let dir = env::temp_dir().join(format!("atto-explorer-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a\n").unwrap();
+        let mut atto = Atto::scratch("", true);
+        atto.documents[0] = Document::open(&dir.join("a.txt").display().to_string()).unwrap();
+        atto.type_keys("<C-n>");
+        assert_eq!(listed(&atto, &dir), ["a.txt"]);
+
+        // New files go in the directory of the selected one, along with the
+        // directories their name has.
+        atto.type_keys("asub/b.txt<Enter>");
+        assert!(dir.join("sub/b.txt").is_file());
+        assert_eq!(listed(&atto, &dir), ["sub", "sub/b.txt", "a.txt"]);
+        assert_eq!(selected(&atto, &dir), "sub/b.txt");
+        atto.type_keys("anew/<Enter>");
+        assert!(dir.join("sub/new").is_dir());
+
+        // The buffer of a renamed file follows it.
+        atto.type_keys("j<Enter><C-n>r<BS><BS><BS>md<Enter>");
+        assert!(dir.join("sub/b.md").is_file() && !dir.join("sub/b.txt").exists());
+        assert_eq!(atto.doc().filename, Some(dir.join("sub/b.md").display().to_string()));
+        assert_eq!(selected(&atto, &dir), "sub/b.md");
+
+        atto.type_keys("dn<Enter>");
+        assert!(dir.join("sub/b.md").exists());
+        atto.type_keys("dy<Enter>");
+        assert!(!dir.join("sub/b.md").exists());
+        assert_eq!(listed(&atto, &dir), ["sub", "sub/new", "a.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use std::ops::Range;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Past this many inserted or deleted lines the rest of the difference is
+// marked as one change rather than worked out.
+const MAX_EDITS: usize = 1000;
+
+// How a line differs from the version of the file staged in git.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sign {
+    Added,
+    Modified,
+    // Lines were removed below this one, or above the first line.
+    Removed,
+    RemovedAbove,
+}
+
+// The file's contents in the git index, if it is tracked.
+pub fn base(filename: &str) -> Option<Vec<u8>> {
+    let path = Path::new(filename);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name()?.to_str()?;
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["show", &format!(":./{}", name)])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+// The sign of each line of `lines` against `base`.
+pub fn signs(base: &[String], lines: &[String]) -> Vec<Option<Sign>> {
+    let mut signs = vec![None; lines.len()];
+    for (old, new) in hunks(base, lines) {
+        if new.is_empty() {
+            match new.start.checked_sub(1) {
+                Some(line) => signs[line] = Some(Sign::Removed),
+                None if !signs.is_empty() => signs[0] = Some(Sign::RemovedAbove),
+                None => {}
+            }
+        } else {
+            let sign = if old.is_empty() { Sign::Added } else { Sign::Modified };
+            signs[new].fill(Some(sign));
+        }
+    }
+    signs
+}
+
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+// The ranges of `old` replaced by ranges of `new`, from Myers' shortest edit
+// script over whatever lies between the common start and end.
+fn hunks(old: &[String], new: &[String]) -> Vec<(Range<usize>, Range<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    if a.is_empty() && b.is_empty() {
+        return Vec::new();
+    }
+    let ops = match edits(a, b) {
+        Some(ops) => ops,
+        None => return vec![(prefix..old.len() - suffix, prefix..new.len() - suffix)],
+    };
+    let mut hunks: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+    let (mut i, mut j) = (prefix, prefix);
+    let mut open = false;
+    for op in ops {
+        if let Op::Equal = op {
+            i += 1;
+            j += 1;
+            open = false;
+            continue;
+        }
+        if !open {
+            hunks.push((i..i, j..j));
+            open = true;
+        }
+        let hunk = hunks.last_mut().unwrap();
+        match op {
+            Op::Delete => {
+                i += 1;
+                hunk.0.end = i;
+            }
+            _ => {
+                j += 1;
+                hunk.1.end = j;
+            }
+        }
+    }
+    hunks
+}
+
+// The edits turning `a` into `b`, or None past MAX_EDITS. Each round of the
+// search keeps the furthest point reached on every diagonal, and the rounds
+// are walked back from the end to recover the path.
+fn edits(a: &[String], b: &[String]) -> Option<Vec<Op>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max as isize {
+        if d as usize > MAX_EDITS {
+            return None;
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) { v[i + 1] } else { v[i - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let at = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.extend((0..x).map(|_| Op::Equal));
+    ops.reverse();
+    Some(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hunks, signs, Sign, MAX_EDITS};
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(' ').filter(|line| !line.is_empty()).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn unchanged() {
+        assert!(hunks(&lines("a b c"), &lines("a b c")).is_empty());
+        assert_eq!(signs(&lines("a b c"), &lines("a b c")), vec![None; 3]);
+    }
+
+    #[test]
+    fn added() {
+        assert_eq!(hunks(&lines("a b c"), &lines("a x b c")), vec![(1..1, 1..2)]);
+        assert_eq!(signs(&lines("a b c"), &lines("a x b c")), vec![None, Some(Sign::Added), None, None]);
+        assert_eq!(signs(&[], &lines("x")), vec![Some(Sign::Added)]);
+    }
+
+    #[test]
+    fn modified() {
+        assert_eq!(hunks(&lines("a b c"), &lines("a B c")), vec![(1..2, 1..2)]);
+        assert_eq!(signs(&lines("a b c"), &lines("a B c")), vec![None, Some(Sign::Modified), None]);
+    }
+
+    #[test]
+    fn removed() {
+        assert_eq!(hunks(&lines("a b c"), &lines("a c")), vec![(1..2, 1..1)]);
+        assert_eq!(signs(&lines("a b c"), &lines("a c")), vec![Some(Sign::Removed), None]);
+        assert_eq!(signs(&lines("a b"), &lines("b")), vec![Some(Sign::RemovedAbove)]);
+        assert!(signs(&lines("a b"), &[]).is_empty());
+    }
+
+    #[test]
+    fn several_hunks() {
+        let (old, new) = (lines("a b c d e"), lines("a X c e f"));
+        assert_eq!(hunks(&old, &new), vec![(1..2, 1..2), (3..4, 3..3), (5..5, 4..5)]);
+        let expected = vec![None, Some(Sign::Modified), Some(Sign::Removed), None, Some(Sign::Added)];
+        assert_eq!(signs(&old, &new), expected);
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let (old, new) = (lines("x a x b x"), lines("x b x a x"));
+        let changed: usize = hunks(&old, &new).iter().map(|(a, b)| a.len() + b.len()).sum();
+        assert_eq!(changed, 4);
+    }
+
+    #[test]
+    fn too_different_is_one_hunk() {
+        let old: Vec<String> = (0..MAX_EDITS).map(|i| format!("a{}", i)).collect();
+        let new: Vec<String> = (0..MAX_EDITS).map(|i| format!("b{}", i)).collect();
+        assert_eq!(hunks(&old, &new), vec![(0..MAX_EDITS, 0..MAX_EDITS)]);
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ignore::{WalkBuilder, WalkState};
+use regex::Regex;
+use tui::backend::Backend;
+use tui::layout::Rect;
+use tui::style::{Color, Modifier, Style};
+use tui::text::Span;
+use tui::widgets::{Block, Borders, List, ListItem, ListState};
+
+use crate::search;
+use crate::{Atto, Prompt};
+
+// More matches than this are dropped rather than listed.
+const MAX_MATCHES: usize = 10_000;
+const PANEL_HEIGHT: u16 = 10;
+
+pub struct GrepMatch {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
+
+// The results of the last `:grep`, shown in a panel under the windows.
+pub struct Quickfix {
+    title: String,
+    matches: Vec<GrepMatch>,
+    selected: usize,
+    pub open: bool,
+    pub focused: bool,
+    search: Option<Search>,
+}
+
+// A `:grep` still walking the files in the background.
+struct Search {
+    pattern: String,
+    results: Receiver<Vec<GrepMatch>>,
+}
+
+// Searches every file under `root` that .gitignore and friends don't exclude,
+// a directory per thread, and sends the matches of each file once it is read.
+// Files that aren't UTF-8 text are skipped. The walk stops after MAX_MATCHES
+// or once nobody listens anymore.
+pub fn grep(root: &Path, regex: &Regex, sender: &Sender<Vec<GrepMatch>>) {
+    let count = AtomicUsize::new(0);
+    WalkBuilder::new(root).build_parallel().run(|| {
+        let (count, sender) = (&count, sender.clone());
+        Box::new(move |entry| {
+            let entry = match entry {
+                Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => entry,
+                _ => return WalkState::Continue,
+            };
+            let text = match fs::read_to_string(entry.path()) {
+                Ok(text) if !text.contains('\0') => text,
+                _ => return WalkState::Continue,
+            };
+            let path = entry.path().strip_prefix(root).unwrap_or(entry.path()).display().to_string();
+            let mut matches = Vec::new();
+            for (line, content) in text.lines().enumerate() {
+                for m in regex.find_iter(content) {
+                    let col = content[..m.start()].chars().count();
+                    matches.push(GrepMatch { path: path.clone(), line, col, text: content.trim().to_string() });
+                }
+            }
+            if matches.is_empty() {
+                return WalkState::Continue;
+            }
+            let found = count.fetch_add(matches.len(), Ordering::Relaxed) + matches.len();
+            if sender.send(matches).is_err() || found > MAX_MATCHES {
+                WalkState::Quit
+            } else {
+                WalkState::Continue
+            }
+        })
+    });
+}
+
+impl Quickfix {
+    pub fn new(title: String, matches: Vec<GrepMatch>) -> Self {
+        Self { title, matches, selected: 0, open: true, focused: true, search: None }
+    }
+
+    pub fn searching(&self) -> bool {
+        self.search.is_some()
+    }
+
+    // Adds the matches of one file in order of path, keeping the same entry
+    // selected.
+    fn add(&mut self, found: Vec<GrepMatch>) {
+        let path = &found[0].path;
+        let pos = self.matches.partition_point(|m| &m.path < path);
+        if pos <= self.selected && !self.matches.is_empty() {
+            self.selected += found.len();
+        }
+        self.matches.splice(pos..pos, found);
+    }
+
+    pub fn height(&self, screen: u16) -> u16 {
+        if self.open {
+            PANEL_HEIGHT.min(screen / 3)
+        } else {
+            0
+        }
+    }
+}
+
+impl Atto {
+    pub fn grep_command(&mut self, pattern: &str) {
+        self.grep_under(PathBuf::from("."), pattern);
+    }
+
+    // Starts searching the files under `root`, with the panel showing the
+    // matches as they come in.
+    fn grep_under(&mut self, root: PathBuf, pattern: &str) {
+        if pattern.is_empty() {
+            self.status_message = Some("Usage: grep <pattern>".to_string());
+            return;
+        }
+        let regex = match search::compile(pattern, None) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.status_message = Some(format!("Invalid pattern: {}", e));
+                return;
+            }
+        };
+        let (sender, results) = mpsc::channel();
+        thread::spawn(move || grep(&root, &regex, &sender));
+        let mut quickfix = Quickfix::new(String::new(), Vec::new());
+        quickfix.search = Some(Search { pattern: pattern.to_string(), results });
+        self.quickfix = Some(quickfix);
+        self.grep_update();
+    }
+
+    // Takes in what the running `:grep` found since the last time. Dropping
+    // the search once there are enough matches stops the walk.
+    pub fn grep_update(&mut self) {
+        let quickfix = match self.quickfix.as_mut() {
+            Some(quickfix) => quickfix,
+            None => return,
+        };
+        let search = match quickfix.search.take() {
+            Some(search) => search,
+            None => return,
+        };
+        let done = loop {
+            match search.results.try_recv() {
+                Ok(found) => quickfix.add(found),
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        let truncated = quickfix.matches.len() > MAX_MATCHES;
+        quickfix.matches.truncate(MAX_MATCHES);
+        quickfix.selected = quickfix.selected.min(quickfix.matches.len().saturating_sub(1));
+        if done && quickfix.matches.is_empty() {
+            self.quickfix = None;
+            self.status_message = Some(format!("No matches for {}", search.pattern));
+            return;
+        }
+        let state = if truncated {
+            ", truncated"
+        } else if !done {
+            ", searching"
+        } else {
+            ""
+        };
+        quickfix.title = format!("grep {} ({} matches{})", search.pattern, quickfix.matches.len(), state);
+        if !done && !truncated {
+            quickfix.search = Some(search);
+        }
+    }
+
+    // Opens the selected match, or the one `step` entries away from it.
+    pub fn quickfix_jump(&mut self, step: isize) {
+        let quickfix = match self.quickfix.as_mut() {
+            Some(quickfix) if !quickfix.matches.is_empty() => quickfix,
+            _ => {
+                self.status_message = Some("No grep results".to_string());
+                return;
+            }
+        };
+        let last = quickfix.matches.len() - 1;
+        quickfix.selected = quickfix.selected.saturating_add_signed(step).min(last);
+        let entry = &quickfix.matches[quickfix.selected];
+        let (path, line, col) = (entry.path.clone(), entry.line, entry.col);
+        let position = format!("({} of {})", quickfix.selected + 1, last + 1);
+        self.edit_file(&path);
+        if self.doc().filename.as_deref() == Some(path.as_str()) {
+            self.set_cursor((line, col));
+            self.status_message = Some(position);
+        }
+    }
+
+    pub fn quickfix_show(&mut self, open: bool) {
+        match self.quickfix.as_mut() {
+            Some(quickfix) => {
+                quickfix.open = open;
+                quickfix.focused = open;
+            }
+            None if open => self.status_message = Some("No grep results".to_string()),
+            None => {}
+        }
+    }
+
+    // Keys go to the panel while it has focus.
+    pub fn quickfix_key(&mut self, key: KeyEvent) {
+        let quickfix = match self.quickfix.as_mut() {
+            Some(quickfix) => quickfix,
+            None => return,
+        };
+        let last = quickfix.matches.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => quickfix.selected = quickfix.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => quickfix.selected = (quickfix.selected + 1).min(last),
+            KeyCode::PageUp => quickfix.selected = quickfix.selected.saturating_sub(PANEL_HEIGHT as usize),
+            KeyCode::PageDown => quickfix.selected = (quickfix.selected + PANEL_HEIGHT as usize).min(last),
+            KeyCode::Home | KeyCode::Char('g') => quickfix.selected = 0,
+            KeyCode::End | KeyCode::Char('G') => quickfix.selected = last,
+            KeyCode::Enter => {
+                quickfix.focused = false;
+                self.quickfix_jump(0);
+            }
+            KeyCode::Tab => quickfix.focused = false,
+            KeyCode::Char(':') => {
+                self.prompt = Some(Prompt::Command);
+                self.command_input.clear();
+            }
+            KeyCode::Esc | KeyCode::Char('q') => self.quickfix_show(false),
+            _ => {}
+        }
+    }
+
+    pub fn render_quickfix<B: Backend>(&self, f: &mut tui::Frame<B>, area: Rect) {
+        let quickfix = match &self.quickfix {
+            Some(quickfix) if quickfix.open => quickfix,
+            _ => return,
+        };
+        // Only the entries that fit are laid out, with the selected one last
+        // when it is further down than that.
+        let visible = area.height.saturating_sub(1).max(1) as usize;
+        let first = (quickfix.selected + 1).saturating_sub(visible);
+        let items: Vec<ListItem> = quickfix.matches[first..].iter().take(visible).map(|m| {
+            ListItem::new(format!("{}:{}:{}: {}", m.path, m.line + 1, m.col + 1, m.text))
+        }).collect();
+        let highlight = if quickfix.focused {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().bg(Color::DarkGray)
+        };
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::TOP).title(Span::raw(quickfix.title.as_str())))
+            .highlight_style(highlight);
+        let mut state = ListState::default();
+        state.select(Some(quickfix.selected - first));
+        f.render_stateful_widget(list, area, &mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process, thread};
+
+    use regex::Regex;
+
+    use super::{grep, GrepMatch};
+    use crate::Atto;
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    // A repository with a file .gitignore leaves out.
+    fn files() -> PathBuf {
+        let dir = env::temp_dir().join(format!("atto-grep-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join(".gitignore"), "ignored.txt\n").unwrap();
+        fs::write(dir.join("ignored.txt"), "foo\n").unwrap();
+        fs::write(dir.join("b.txt"), "foo, foo\nbar\n  and foo\n").unwrap();
+        fs::write(dir.join("sub/a.txt"), "xfoo\n").unwrap();
+        fs::write(dir.join("a.bin"), "foo\0").unwrap();
+        dir
+    }
+
+    fn found(matches: &[GrepMatch]) -> Vec<(&str, usize, usize, &str)> {
+        matches.iter().map(|m| (m.path.as_str(), m.line, m.col, m.text.as_str())).collect()
+    }
+
+    #[test]
+    fn every_match_of_every_file() {
+        let dir = files();
+        let (sender, results) = mpsc::channel();
+        grep(&dir, &Regex::new("foo").unwrap(), &sender);
+        drop(sender);
+        let mut matches: Vec<GrepMatch> = results.into_iter().flatten().collect();
+        matches.sort_by(|a, b| (&a.path, a.line, a.col).cmp(&(&b.path, b.line, b.col)));
+        let sub = Path::new("sub").join("a.txt").display().to_string();
+        assert_eq!(found(&matches), [
+            ("b.txt", 0, 0, "foo, foo"),
+            ("b.txt", 0, 5, "foo, foo"),
+            ("b.txt", 2, 6, "and foo"),
+            (sub.as_str(), 0, 1, "xfoo"),
+        ]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn wait(atto: &mut Atto) {
+        let start = Instant::now();
+        while atto.quickfix.as_ref().is_some_and(|quickfix| quickfix.searching()) {
+            assert!(start.elapsed() < Duration::from_secs(10), "grep never finished");
+            atto.grep_update();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn grep_command() {
+        let dir = files();
+        let mut atto = Atto::scratch("", true);
+        atto.grep_under(dir.clone(), "foo");
+        wait(&mut atto);
+        let quickfix = atto.quickfix.as_ref().unwrap();
+        assert_eq!(quickfix.title, "grep foo (4 matches)");
+        assert_eq!(quickfix.matches.iter().map(|m| m.path.as_str()).collect::<Vec<_>>()[..3], ["b.txt"; 3]);
+        assert!(quickfix.open && quickfix.focused);
+
+        atto.grep_under(dir.clone(), "nowhere");
+        wait(&mut atto);
+        assert!(atto.quickfix.is_none());
+        assert_eq!(atto.status_message.as_deref(), Some("No matches for nowhere"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn results_arriving_keep_the_selection() {
+        let mut atto = Atto::scratch("", true);
+        atto.quickfix = Some(super::Quickfix::new(String::new(), Vec::new()));
+        let quickfix = atto.quickfix.as_mut().unwrap();
+        let file = |path: &str, lines: usize| {
+            (0..lines).map(|line| GrepMatch { path: path.to_string(), line, col: 0, text: String::new() }).collect()
+        };
+        quickfix.add(file("b", 2));
+        quickfix.selected = 1;
+        quickfix.add(file("a", 1));
+        quickfix.add(file("c", 1));
+        let entry = &quickfix.matches[quickfix.selected];
+        assert_eq!((entry.path.as_str(), entry.line), ("b", 1));
+        assert_eq!(quickfix.matches.iter().map(|m| m.path.as_str()).collect::<String>(), "abbc");
+    }
+}
@@ -0,0 +1,282 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use tui::style::{Color, Style};
+use tui::text::Span;
+
+use crate::document::Document;
+use crate::git::Sign;
+use crate::window::Window;
+use crate::{diagnostic_style, Atto};
+
+// Edits get their git signs at most this often, as working them out compares
+// every line of the file.
+const GIT_INTERVAL: Duration = Duration::from_millis(300);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Numbers {
+    Absolute,
+    Relative,
+    // The cursor's line absolute, the others relative to it.
+    Hybrid,
+    Off,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source {
+    Git,
+    Diagnostics,
+    Search,
+    Bookmarks,
+}
+
+// The columns left of the text: line numbers, then sign columns that each
+// show the sign of the first of their sources with one on the line.
+pub struct Gutter {
+    pub numbers: Numbers,
+    pub signs: Vec<Vec<Source>>,
+    last_git: Instant,
+}
+
+impl Default for Gutter {
+    fn default() -> Self {
+        Self {
+            numbers: Numbers::Absolute,
+            signs: vec![vec![Source::Diagnostics, Source::Bookmarks, Source::Search], vec![Source::Git]],
+            last_git: Instant::now(),
+        }
+    }
+}
+
+impl Numbers {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "absolute" => Some(Numbers::Absolute),
+            "relative" => Some(Numbers::Relative),
+            "hybrid" => Some(Numbers::Hybrid),
+            "off" | "none" => Some(Numbers::Off),
+            _ => None,
+        }
+    }
+}
+
+impl Source {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "git" => Some(Source::Git),
+            "diagnostics" => Some(Source::Diagnostics),
+            "search" => Some(Source::Search),
+            "bookmarks" => Some(Source::Bookmarks),
+            _ => None,
+        }
+    }
+}
+
+impl Gutter {
+    // Room for the largest line number and a space before it, and never less
+    // than the four columns numbers always had.
+    fn number_width(&self, len_lines: usize) -> usize {
+        match self.numbers {
+            Numbers::Off => 0,
+            _ => len_lines.to_string().len().max(3) + 1,
+        }
+    }
+
+    // Without sign columns a space still keeps the numbers off the text.
+    pub fn width(&self, len_lines: usize) -> usize {
+        let numbers = self.number_width(len_lines);
+        let separator = (numbers > 0) as usize;
+        numbers + self.signs.len().max(separator)
+    }
+
+    fn number(&self, line: usize, cursor_line: usize) -> usize {
+        match self.numbers {
+            Numbers::Relative => line.abs_diff(cursor_line),
+            Numbers::Hybrid if line != cursor_line => line.abs_diff(cursor_line),
+            _ => line + 1,
+        }
+    }
+}
+
+fn git_sign(sign: Sign) -> Span<'static> {
+    match sign {
+        Sign::Added => Span::styled("▎", Style::default().fg(Color::Green)),
+        Sign::Modified => Span::styled("▎", Style::default().fg(Color::Yellow)),
+        Sign::Removed => Span::styled("▁", Style::default().fg(Color::Red)),
+        Sign::RemovedAbove => Span::styled("▔", Style::default().fg(Color::Red)),
+    }
+}
+
+impl Atto {
+    // Works out the git signs of the documents edited since they last were,
+    // at most every GIT_INTERVAL.
+    pub fn update_git_signs(&mut self) {
+        if self.gutter.last_git.elapsed() < GIT_INTERVAL {
+            return;
+        }
+        self.gutter.last_git = Instant::now();
+        for document in self.documents.iter_mut().filter(|d| d.git_pending) {
+            document.update_git_signs();
+        }
+    }
+
+    // How long until edits are due to get their git signs, if any are waiting.
+    pub fn git_timeout(&self) -> Option<Duration> {
+        let pending = self.documents.iter().any(|d| d.git_pending);
+        pending.then(|| GIT_INTERVAL.saturating_sub(self.gutter.last_git.elapsed()))
+    }
+
+    // The gutter width of the focused window, where its text starts.
+    pub fn gutter_width(&self) -> u16 {
+        self.gutter.width(self.doc().buffer.len_lines()) as u16
+    }
+
+    // The gutter of each line of `lines` shown in `window`.
+    pub fn gutter_spans(&self, window: &Window, lines: Range<usize>) -> Vec<Vec<Span<'static>>> {
+        let doc = &self.documents[window.document];
+        let width = self.gutter.number_width(doc.buffer.len_lines());
+        let sources: Vec<Vec<Option<Span>>> = [Source::Git, Source::Diagnostics, Source::Search, Source::Bookmarks]
+            .iter()
+            .map(|&source| self.signs(doc, source, lines.clone()))
+            .collect();
+        lines.clone().map(|line| {
+            let mut spans = Vec::new();
+            if width > 0 {
+                spans.push(Span::raw(format!("{:>width$}", self.gutter.number(line, window.cursor.0), width = width)));
+            }
+            for column in &self.gutter.signs {
+                let sign = column.iter().find_map(|&source| sources[source as usize][line - lines.start].clone());
+                spans.push(sign.unwrap_or_else(|| Span::raw(" ")));
+            }
+            if self.gutter.signs.is_empty() && width > 0 {
+                spans.push(Span::raw(" "));
+            }
+            spans
+        }).collect()
+    }
+
+    // The gutter of the rows a wrapped line continues on.
+    pub fn gutter_continuation(&self, doc: &Document) -> Span<'static> {
+        let width = self.gutter.number_width(doc.buffer.len_lines());
+        let indicator = if width > 0 { self.wrap.indicator.as_str() } else { "" };
+        let text = format!("{:>width$}{}", indicator, " ".repeat(self.gutter.width(doc.buffer.len_lines()) - width), width = width);
+        Span::styled(text, Style::default().fg(Color::DarkGray))
+    }
+
+    // What `source` has to show on each of `lines`.
+    fn signs(&self, doc: &Document, source: Source, lines: Range<usize>) -> Vec<Option<Span<'static>>> {
+        let mut signs = vec![None; lines.len()];
+        if !self.gutter.signs.iter().flatten().any(|&s| s == source) {
+            return signs;
+        }
+        match source {
+            Source::Git => {
+                for line in lines.clone() {
+                    signs[line - lines.start] = doc.git_signs.get(line).copied().flatten().map(git_sign);
+                }
+            }
+            // The most severe diagnostic on a line marks it.
+            Source::Diagnostics => {
+                let mut worst = vec![u64::MAX; lines.len()];
+                for diagnostic in &doc.diagnostics {
+                    let first = doc.buffer.char_to_line(diagnostic.range.start.min(doc.buffer.len_chars()));
+                    let last = doc.buffer.char_to_line(diagnostic.range.end.min(doc.buffer.len_chars()));
+                    for line in first.max(lines.start)..=last.min(lines.end.saturating_sub(1)) {
+                        if diagnostic.severity < worst[line - lines.start] {
+                            worst[line - lines.start] = diagnostic.severity;
+                            let letter = ["E", "W", "I", "H"][(diagnostic.severity.clamp(1, 4) - 1) as usize];
+                            signs[line - lines.start] = Some(Span::styled(letter, diagnostic_style(diagnostic.severity)));
+                        }
+                    }
+                }
+            }
+            Source::Search => {
+                if let Some(search) = self.search.as_ref().filter(|search| search.highlight) {
+                    for line in lines.clone() {
+                        if search.regex.is_match(&doc.buffer.line(line)) {
+                            signs[line - lines.start] = Some(Span::styled("•", Style::default().fg(Color::Yellow)));
+                        }
+                    }
+                }
+            }
+            Source::Bookmarks => {
+                for &line in doc.bookmarks.iter().filter(|line| lines.contains(line)) {
+                    signs[line - lines.start] = Some(Span::styled("●", Style::default().fg(Color::Cyan)));
+                }
+            }
+        }
+        signs
+    }
+
+    pub fn toggle_bookmark(&mut self) {
+        let line = self.cursor_y;
+        let bookmarks = &mut self.doc_mut().bookmarks;
+        match bookmarks.binary_search(&line) {
+            Ok(i) => {
+                bookmarks.remove(i);
+            }
+            Err(i) => bookmarks.insert(i, line),
+        }
+    }
+
+    // Goes to the next bookmark after the cursor's line, or the previous one
+    // before it, wrapping around the end of the file.
+    pub fn jump_bookmark(&mut self, forward: bool) {
+        let bookmarks = &self.doc().bookmarks;
+        let line = if forward {
+            bookmarks.iter().find(|&&line| line > self.cursor_y).or(bookmarks.first())
+        } else {
+            bookmarks.iter().rev().find(|&&line| line < self.cursor_y).or(bookmarks.last())
+        };
+        match line.copied() {
+            Some(line) => self.set_cursor((line, 0)),
+            None => self.status_message = Some("No bookmarks".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gutter, Numbers, Source};
+    use crate::window::Window;
+    use crate::Atto;
+
+    #[test]
+    fn width() {
+        let mut gutter = Gutter::default();
+        // Numbers take at least three columns and a space, then a column per
+        // sign column.
+        assert_eq!(gutter.width(10), 6);
+        assert_eq!(gutter.width(12_345), 8);
+        gutter.signs = vec![vec![Source::Git]];
+        assert_eq!(gutter.width(1), 5);
+        gutter.signs.clear();
+        assert_eq!(gutter.width(1), 5);
+        gutter.numbers = Numbers::Off;
+        assert_eq!(gutter.width(1), 0);
+        gutter.signs = vec![vec![Source::Bookmarks], vec![Source::Git]];
+        assert_eq!(gutter.width(1), 2);
+    }
+
+    // The gutter text of every line, with the cursor on the third.
+    fn gutter(atto: &Atto) -> Vec<String> {
+        let mut window = Window::new(0);
+        window.cursor = (2, 0);
+        let spans = atto.gutter_spans(&window, 0..atto.doc().buffer.len_lines());
+        spans.iter().map(|line| line.iter().map(|span| span.content.as_ref()).collect()).collect()
+    }
+
+    #[test]
+    fn numbers() {
+        let mut atto = Atto::scratch("a\nb\nc\nd", true);
+        atto.gutter.signs = vec![vec![Source::Bookmarks]];
+        atto.type_keys("jjjmm");
+        assert_eq!(gutter(&atto), ["   1 ", "   2 ", "   3 ", "   4●"]);
+        atto.gutter.numbers = Numbers::Relative;
+        assert_eq!(gutter(&atto), ["   2 ", "   1 ", "   0 ", "   1●"]);
+        atto.gutter.numbers = Numbers::Hybrid;
+        assert_eq!(gutter(&atto), ["   2 ", "   1 ", "   3 ", "   1●"]);
+        atto.gutter.numbers = Numbers::Off;
+        assert_eq!(gutter(&atto), [" ", " ", " ", "●"]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::Path;
+
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Parser, Point, Query, QueryCursor, Tree};
+use tui::style::{Color, Modifier, Style};
+
+use crate::buffer::TextBuffer;
+
+struct Grammar {
+    extensions: &'static [&'static str],
+    language: fn() -> Language,
+    highlights: &'static str,
+}
+
+const GRAMMARS: &[Grammar] = &[
+    Grammar {
+        extensions: &["rs"],
+        language: || tree_sitter_rust::LANGUAGE.into(),
+        highlights: tree_sitter_rust::HIGHLIGHTS_QUERY,
+    },
+    Grammar {
+        extensions: &["c", "h"],
+        language: || tree_sitter_c::LANGUAGE.into(),
+        highlights: tree_sitter_c::HIGHLIGHT_QUERY,
+    },
+    Grammar {
+        extensions: &["py", "pyi"],
+        language: || tree_sitter_python::LANGUAGE.into(),
+        highlights: tree_sitter_python::HIGHLIGHTS_QUERY,
+    },
+    Grammar {
+        extensions: &["json"],
+        language: || tree_sitter_json::LANGUAGE.into(),
+        highlights: tree_sitter_json::HIGHLIGHTS_QUERY,
+    },
+    Grammar {
+        extensions: &["md", "markdown"],
+        language: || tree_sitter_md::LANGUAGE.into(),
+        highlights: tree_sitter_md::HIGHLIGHT_QUERY_BLOCK,
+    },
+    Grammar {
+        extensions: &["lua"],
+        language: || tree_sitter_lua::LANGUAGE.into(),
+        highlights: tree_sitter_lua::HIGHLIGHTS_QUERY,
+    },
+];
+
+fn style_for(capture: &str) -> Style {
+    let style = Style::default();
+    match capture.split('.').next().unwrap_or(capture) {
+        "keyword" | "conditional" | "repeat" | "include" => style.fg(Color::Magenta),
+        "function" | "constructor" => style.fg(Color::Blue),
+        "type" => style.fg(Color::Yellow),
+        "string" | "escape" => style.fg(Color::Green),
+        "number" | "constant" | "boolean" => style.fg(Color::Cyan),
+        "comment" => style.fg(Color::DarkGray),
+        "attribute" | "label" => style.fg(Color::LightMagenta),
+        "variable" if capture == "variable.builtin" => style.fg(Color::Red),
+        "property" => style.fg(Color::LightBlue),
+        "text" if capture == "text.title" => style.add_modifier(Modifier::BOLD),
+        "text" => style.fg(Color::Green),
+        "punctuation" if capture == "punctuation.special" => style.fg(Color::LightMagenta),
+        _ => style,
+    }
+}
+
+pub struct Syntax {
+    parser: Parser,
+    query: Query,
+    tree: Option<Tree>,
+    dirty: bool,
+}
+
+impl Syntax {
+    pub fn for_file(filename: &str) -> Option<Self> {
+        let extension = Path::new(filename).extension()?.to_str()?;
+        let grammar = GRAMMARS.iter().find(|g| g.extensions.contains(&extension))?;
+        let language = (grammar.language)();
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, grammar.highlights).ok()?;
+        Some(Self {
+            parser,
+            query,
+            tree: None,
+            dirty: true,
+        })
+    }
+
+    // Both edit hooks must run before the buffer itself is changed.
+    pub fn edit_insert(&mut self, buffer: &dyn TextBuffer, pos: usize, text: &str) {
+        let start_byte = buffer.char_to_byte(pos);
+        let start = point_at(buffer, start_byte);
+        let rows = text.matches('\n').count();
+        let columns = text.rsplit('\n').next().unwrap_or("").len();
+        let new_end = if rows == 0 {
+            Point::new(start.row, start.column + columns)
+        } else {
+            Point::new(start.row + rows, columns)
+        };
+        self.edit(InputEdit {
+            start_byte,
+            old_end_byte: start_byte,
+            new_end_byte: start_byte + text.len(),
+            start_position: start,
+            old_end_position: start,
+            new_end_position: new_end,
+        });
+    }
+
+    pub fn edit_remove(&mut self, buffer: &dyn TextBuffer, range: Range<usize>) {
+        let start_byte = buffer.char_to_byte(range.start);
+        let old_end_byte = buffer.char_to_byte(range.end);
+        let start = point_at(buffer, start_byte);
+        self.edit(InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte: start_byte,
+            start_position: start,
+            old_end_position: point_at(buffer, old_end_byte),
+            new_end_position: start,
+        });
+    }
+
+    fn edit(&mut self, edit: InputEdit) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.edit(&edit);
+        }
+        self.dirty = true;
+    }
+
+    pub fn reset(&mut self) {
+        self.tree = None;
+        self.dirty = true;
+    }
+
+    pub fn update(&mut self, buffer: &dyn TextBuffer) {
+        if !self.dirty {
+            return;
+        }
+        let len = buffer.len_bytes();
+        let mut read = |byte: usize, _: Point| -> &[u8] {
+            if byte >= len {
+                &[]
+            } else {
+                buffer.chunk_at_byte(byte).as_bytes()
+            }
+        };
+        self.tree = self.parser.parse_with(&mut read, self.tree.as_ref());
+        self.dirty = false;
+    }
+
+    // Styles for `lines`, one list of (char column range, style) per line.
+    pub fn highlight(&self, buffer: &dyn TextBuffer, lines: Range<usize>) -> Vec<Vec<(Range<usize>, Style)>> {
+        let mut styles = vec![Vec::new(); lines.len()];
+        let tree = match self.tree {
+            Some(ref tree) if !self.dirty => tree,
+            _ => return styles,
+        };
+        let start_char = buffer.line_to_char(lines.start);
+        let end_char = if lines.end < buffer.len_lines() {
+            buffer.line_to_char(lines.end)
+        } else {
+            buffer.len_chars()
+        };
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(buffer.char_to_byte(start_char)..buffer.char_to_byte(end_char));
+        let node_text = |node: tree_sitter::Node| {
+            let range = buffer.byte_to_char(node.start_byte())..buffer.byte_to_char(node.end_byte());
+            std::iter::once(buffer.slice(range).into_bytes())
+        };
+        let names = self.query.capture_names();
+        let mut seen = HashSet::new();
+        let mut captures = cursor.captures(&self.query, tree.root_node(), node_text);
+        while let Some((found, index)) = captures.next() {
+            let capture = found.captures[*index];
+            let style = style_for(names[capture.index as usize]);
+            let node = capture.node;
+            if style == Style::default() || !seen.insert(node.byte_range()) {
+                continue;
+            }
+            let start = buffer.byte_to_char(node.start_byte()).max(start_char);
+            let end = buffer.byte_to_char(node.end_byte()).min(end_char);
+            let mut pos = start;
+            while pos < end {
+                let (line, col) = buffer.char_to_pos(pos);
+                let line_end = buffer.line_to_char(line) + buffer.line_len(line);
+                let stop = end.min(line_end);
+                if let Some(line_styles) = styles.get_mut(line - lines.start) {
+                    line_styles.push((col..col + stop - pos, style));
+                }
+                pos = line_end + 1;
+            }
+        }
+        styles
+    }
+}
+
+fn point_at(buffer: &dyn TextBuffer, byte: usize) -> Point {
+    let line = buffer.char_to_line(buffer.byte_to_char(byte));
+    let line_start = buffer.char_to_byte(buffer.line_to_char(line));
+    Point::new(line, byte - line_start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::RopeBuffer;
+
+    #[test]
+    fn queries_compile() {
+        for grammar in GRAMMARS {
+            let language = (grammar.language)();
+            assert!(Query::new(&language, grammar.highlights).is_ok(), "{:?}", grammar.extensions);
+        }
+    }
+
+    #[test]
+    fn lua() {
+        let buffer = RopeBuffer::from_lines(["local x = 1 -- one", "return x"].into_iter());
+        let mut syntax = Syntax::for_file("init.lua").unwrap();
+        syntax.update(&buffer);
+        let styles = syntax.highlight(&buffer, 0..2);
+        // `local`, the number and the comment; `return` on the next line.
+        let columns: Vec<Range<usize>> = styles[0].iter().map(|(range, _)| range.clone()).collect();
+        assert!(columns.contains(&(0..5)) && columns.contains(&(10..11)) && columns.contains(&(12..18)), "{:?}", styles);
+        assert!(styles[1].iter().any(|(range, _)| *range == (0..6)), "{:?}", styles);
+    }
+}
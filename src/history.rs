@@ -0,0 +1,251 @@
+pub struct Edit {
+    pub pos: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+impl Edit {
+    // Typing forwards and backspacing backwards over adjacent text both
+    // extend the previous edit instead of starting a new undo step.
+    fn continues(&self, next: &Edit) -> bool {
+        if self.removed.is_empty() && next.removed.is_empty() {
+            next.pos == self.pos + self.inserted.chars().count()
+        } else if self.inserted.is_empty() && next.inserted.is_empty() {
+            next.pos + next.removed.chars().count() == self.pos
+        } else {
+            false
+        }
+    }
+}
+
+struct Revision {
+    id: usize,
+    edits: Vec<Edit>,
+    cursor: (usize, usize),
+}
+
+pub struct History {
+    undo: Vec<Revision>,
+    redo: Vec<Revision>,
+    open: bool,
+    grouping: bool,
+    // Revisions are numbered from 1, 0 being the text before any of them.
+    next_id: usize,
+    // The revision the file on disk has. Ids aren't reused, so once new edits
+    // drop it from the redo list nothing gets back to it.
+    saved: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            open: false,
+            grouping: false,
+            next_id: 1,
+            saved: 0,
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.undo.last().map_or(0, |revision| revision.id)
+    }
+
+    // Marks the current text as the one on disk. Later edits start a new
+    // revision rather than changing the saved one.
+    pub fn mark_saved(&mut self) {
+        self.open = false;
+        self.saved = self.current();
+    }
+
+    // Whether the text differs from the one last saved or read.
+    pub fn modified(&self) -> bool {
+        self.saved != self.current()
+    }
+
+    // `cursor` is where the cursor was before the edit, restored on undo.
+    pub fn record(&mut self, edit: Edit, cursor: (usize, usize)) {
+        self.redo.clear();
+        if self.open {
+            if let Some(revision) = self.undo.last_mut() {
+                if self.grouping || revision.edits.last().is_some_and(|last| last.continues(&edit)) {
+                    revision.edits.push(edit);
+                    return;
+                }
+            }
+        }
+        self.undo.push(Revision {
+            id: self.next_id,
+            edits: vec![edit],
+            cursor,
+        });
+        self.next_id += 1;
+        self.open = true;
+    }
+
+    // Ends the current group so the next edit becomes its own undo step.
+    pub fn seal(&mut self) {
+        self.open = false;
+        self.grouping = false;
+    }
+
+    pub fn grouping(&self) -> bool {
+        self.grouping
+    }
+
+    // Makes every edit up to the next `seal` part of one undo step.
+    pub fn group(&mut self) {
+        self.seal();
+        self.grouping = true;
+    }
+
+    // Reverts the last revision through `splice(pos, len, text)`, which
+    // replaces `len` chars at `pos` with `text`.
+    pub fn undo(&mut self, mut splice: impl FnMut(usize, usize, &str)) -> Option<(usize, usize)> {
+        let revision = self.undo.pop()?;
+        for edit in revision.edits.iter().rev() {
+            splice(edit.pos, edit.inserted.chars().count(), &edit.removed);
+        }
+        let cursor = revision.cursor;
+        self.redo.push(revision);
+        self.open = false;
+        Some(cursor)
+    }
+
+    // Re-applies the last undone revision, returning the char index just past
+    // its final edit.
+    pub fn redo(&mut self, mut splice: impl FnMut(usize, usize, &str)) -> Option<usize> {
+        let revision = self.redo.pop()?;
+        for edit in &revision.edits {
+            splice(edit.pos, edit.removed.chars().count(), &edit.inserted);
+        }
+        let end = revision.edits.last().map(|last| last.pos + last.inserted.chars().count());
+        self.undo.push(revision);
+        self.open = false;
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn splice(text: &mut String, pos: usize, len: usize, new: &str) {
+        let start = text.char_indices().nth(pos).map_or(text.len(), |(i, _)| i);
+        let end = text[start..].char_indices().nth(len).map_or(text.len(), |(i, _)| start + i);
+        text.replace_range(start..end, new);
+    }
+
+    // Applies an edit to `text` and records it like the editor does.
+    fn edit(history: &mut History, text: &mut String, pos: usize, removed: usize, inserted: &str) {
+        let old: String = text.chars().skip(pos).take(removed).collect();
+        splice(text, pos, removed, inserted);
+        history.record(Edit { pos, removed: old, inserted: inserted.to_string() }, (0, pos));
+    }
+
+    fn undo(history: &mut History, text: &mut String) -> Option<(usize, usize)> {
+        history.undo(|pos, len, new| splice(text, pos, len, new))
+    }
+
+    fn redo(history: &mut History, text: &mut String) -> Option<usize> {
+        history.redo(|pos, len, new| splice(text, pos, len, new))
+    }
+
+    #[test]
+    fn typing_and_backspacing_group() {
+        let mut history = History::new();
+        let mut text = String::new();
+        for (i, c) in "héllo".chars().enumerate() {
+            edit(&mut history, &mut text, i, 0, &c.to_string());
+        }
+        // A jump elsewhere starts a new step.
+        edit(&mut history, &mut text, 0, 0, ">");
+        for pos in (4..6).rev() {
+            edit(&mut history, &mut text, pos, 1, "");
+        }
+        assert_eq!(text, ">hél");
+        assert_eq!(undo(&mut history, &mut text), Some((0, 5)));
+        assert_eq!(text, ">héllo");
+        assert_eq!(undo(&mut history, &mut text), Some((0, 0)));
+        assert_eq!(text, "héllo");
+        assert_eq!(undo(&mut history, &mut text), Some((0, 0)));
+        assert_eq!(text, "");
+        assert_eq!(undo(&mut history, &mut text), None);
+        assert_eq!(redo(&mut history, &mut text), Some(5));
+        assert_eq!(text, "héllo");
+    }
+
+    #[test]
+    fn seal() {
+        let mut history = History::new();
+        let mut text = String::new();
+        edit(&mut history, &mut text, 0, 0, "a");
+        history.seal();
+        edit(&mut history, &mut text, 1, 0, "b");
+        undo(&mut history, &mut text);
+        assert_eq!(text, "a");
+        // Undo seals too, so typing on doesn't join the step before it.
+        edit(&mut history, &mut text, 1, 0, "c");
+        undo(&mut history, &mut text);
+        assert_eq!(text, "a");
+        // And a new edit drops what could be redone.
+        edit(&mut history, &mut text, 1, 0, "d");
+        assert_eq!(redo(&mut history, &mut text), None);
+    }
+
+    #[test]
+    fn group() {
+        let mut history = History::new();
+        let mut text = "one two".to_string();
+        edit(&mut history, &mut text, 0, 0, "x");
+        history.group();
+        assert!(history.grouping());
+        // Edits that don't continue each other still land in the group.
+        edit(&mut history, &mut text, 8, 0, "!");
+        edit(&mut history, &mut text, 1, 3, "ONE");
+        history.seal();
+        assert!(!history.grouping());
+        edit(&mut history, &mut text, 0, 1, "");
+        assert_eq!(text, "ONE two!");
+        undo(&mut history, &mut text);
+        assert_eq!(text, "xONE two!");
+        undo(&mut history, &mut text);
+        assert_eq!(text, "xone two");
+        assert_eq!(redo(&mut history, &mut text), Some(4));
+        assert_eq!(text, "xONE two!");
+    }
+
+    #[test]
+    fn save_point() {
+        let mut history = History::new();
+        let mut text = String::new();
+        assert!(!history.modified());
+        edit(&mut history, &mut text, 0, 0, "a");
+        assert!(history.modified());
+        undo(&mut history, &mut text);
+        assert!(!history.modified());
+        redo(&mut history, &mut text);
+        history.mark_saved();
+        assert!(!history.modified());
+        // Typing on after a save is a new step, so undo gets back to it.
+        edit(&mut history, &mut text, 1, 0, "b");
+        assert!(history.modified());
+        undo(&mut history, &mut text);
+        assert!(!history.modified());
+        // Undoing past the save and redoing back to it.
+        undo(&mut history, &mut text);
+        assert!(history.modified());
+        redo(&mut history, &mut text);
+        assert!(!history.modified());
+        redo(&mut history, &mut text);
+        assert!(history.modified());
+        // Once the saved revision is dropped nothing gets back to it.
+        undo(&mut history, &mut text);
+        undo(&mut history, &mut text);
+        edit(&mut history, &mut text, 0, 0, "c");
+        undo(&mut history, &mut text);
+        assert_eq!(text, "");
+        assert!(history.modified());
+    }
+}
@@ -0,0 +1,426 @@
+use std::ops::Range;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use regex::Regex;
+
+use crate::buffer::TextBuffer;
+use crate::keymap::Key;
+use crate::motion;
+use crate::register::Register;
+use crate::selection::{self, Selection};
+use crate::{Atto, Mode, Prompt};
+
+// Last char of `line`, counting its line break.
+fn line_end(buffer: &dyn TextBuffer, line: usize) -> usize {
+    if line + 1 < buffer.len_lines() {
+        buffer.line_to_char(line + 1) - 1
+    } else {
+        let start = buffer.line_to_char(line);
+        (start + buffer.line_len(line)).saturating_sub(1).max(start)
+    }
+}
+
+fn left(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let (_, col) = buffer.char_to_pos(s.head);
+    Selection::at(if col > 0 { s.head - 1 } else { s.head })
+}
+
+fn right(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let line = buffer.char_to_line(s.head);
+    Selection::at(if s.head < buffer.line_to_char(line) + buffer.line_len(line) { s.head + 1 } else { s.head })
+}
+
+fn vertical(buffer: &dyn TextBuffer, s: Selection, down: bool) -> Selection {
+    let (line, col) = buffer.char_to_pos(s.head);
+    let target = if down { (line + 1).min(buffer.len_lines() - 1) } else { line.saturating_sub(1) };
+    Selection::at(buffer.pos_to_char(target, col.min(buffer.line_len(target))))
+}
+
+// The next word and the whitespace after it.
+fn word(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let anchor = if motion::word_boundary(buffer, s.head) { s.head + 1 } else { s.head };
+    let head = motion::word_forward(buffer, anchor).saturating_sub(1).max(anchor);
+    Selection { anchor, head }
+}
+
+fn word_end(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let anchor = if motion::word_boundary(buffer, s.head) { s.head + 1 } else { s.head };
+    Selection { anchor, head: motion::word_end(buffer, anchor) }
+}
+
+fn word_back(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let anchor = if s.head > 0 && motion::word_boundary(buffer, s.head - 1) { s.head - 1 } else { s.head };
+    Selection { anchor, head: motion::word_backward(buffer, anchor + 1) }
+}
+
+// Whole lines; a selection already made of whole lines grows by one.
+fn lines(buffer: &dyn TextBuffer, s: Selection) -> Selection {
+    let (first, last) = (buffer.char_to_line(s.start()), buffer.char_to_line(s.end() - 1));
+    let full = s.start() == buffer.line_to_char(first) && s.end() - 1 == line_end(buffer, last);
+    let last = if full && last + 1 < buffer.len_lines() { last + 1 } else { last };
+    Selection { anchor: buffer.line_to_char(first), head: line_end(buffer, last) }
+}
+
+impl Atto {
+    // The selections being edited, or a single one at the cursor.
+    fn kakoune_selections(&self) -> Vec<Selection> {
+        if self.selections.is_empty() {
+            return vec![Selection::at(self.cursor_char())];
+        }
+        let len = self.doc().buffer.len_chars();
+        self.selections.iter()
+            .map(|s| Selection { anchor: s.anchor.min(len), head: s.head.min(len) })
+            .collect()
+    }
+
+    pub fn set_selections(&mut self, mut selections: Vec<Selection>, primary: usize) {
+        let primary = primary.min(selections.len() - 1);
+        let primary = selection::normalize(&mut selections, primary);
+        let head = selections[primary].head;
+        self.selections = selections;
+        self.primary = primary;
+        self.set_cursor_char(head);
+    }
+
+    fn select_each(&mut self, extend: bool, f: impl Fn(&dyn TextBuffer, Selection) -> Selection) {
+        let buffer = self.doc().buffer.as_ref();
+        let selections = self.kakoune_selections().into_iter().map(|s| {
+            let moved = f(buffer, s);
+            if extend { Selection { anchor: s.anchor, head: moved.head } } else { moved }
+        }).collect();
+        self.set_selections(selections, self.primary);
+    }
+
+    // Replaces each range (sorted, not overlapping) with its text, returning
+    // where the new text ended up.
+    pub fn replace_each(&mut self, edits: Vec<(Range<usize>, String)>) -> Vec<Range<usize>> {
+        let mut shift = 0isize;
+        let mut placed = Vec::with_capacity(edits.len());
+        for (range, text) in edits {
+            let len = self.doc().buffer.len_chars();
+            let start = ((range.start as isize + shift) as usize).min(len);
+            let end = ((range.end as isize + shift) as usize).min(len);
+            if end > start {
+                self.remove_text(start..end);
+            }
+            if !text.is_empty() {
+                self.insert_text(start, &text);
+            }
+            let inserted = text.chars().count();
+            placed.push(start..start + inserted);
+            shift += inserted as isize - (end - start) as isize;
+        }
+        placed
+    }
+
+    fn yank_selections(&mut self, selections: &[Selection], yank: bool) {
+        let s = selections[self.primary.min(selections.len() - 1)];
+        let buffer = self.doc().buffer.as_ref();
+        let text = buffer.slice(s.start()..s.end().min(buffer.len_chars()));
+        self.registers.store(None, Register { text, linewise: false }, yank);
+    }
+
+    pub fn kakoune_key(&mut self, (code, modifiers): Key) {
+        let c = match code {
+            KeyCode::Char(c) if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+            _ => {
+                self.normal_keys.clear();
+                return;
+            }
+        };
+        self.normal_keys.push(c);
+        let keys = std::mem::take(&mut self.normal_keys);
+        let selections = self.kakoune_selections();
+        match keys.as_str() {
+            "g" => self.normal_keys = keys,
+            "gg" | "gk" => self.set_selections(vec![Selection::at(0)], 0),
+            "ge" | "gj" => {
+                let last = self.doc().buffer.line_to_char(self.doc().buffer.len_lines() - 1);
+                self.set_selections(vec![Selection::at(last)], 0);
+            }
+            "h" | "H" => self.select_each(c == 'H', left),
+            "l" | "L" => self.select_each(c == 'L', right),
+            "j" | "J" => self.select_each(c == 'J', |b, s| vertical(b, s, true)),
+            "k" | "K" => self.select_each(c == 'K', |b, s| vertical(b, s, false)),
+            "w" | "W" => self.select_each(c == 'W', word),
+            "e" | "E" => self.select_each(c == 'E', word_end),
+            "b" | "B" => self.select_each(c == 'B', word_back),
+            "x" => self.select_each(false, lines),
+            "%" => {
+                let len = self.doc().buffer.len_chars();
+                self.set_selections(vec![Selection { anchor: 0, head: len.saturating_sub(1) }], 0);
+            }
+            ";" => self.select_each(false, |_, s| Selection::at(s.head)),
+            "," => {
+                let primary = selections[self.primary.min(selections.len() - 1)];
+                self.set_selections(vec![primary], 0);
+            }
+            "C" => self.copy_selection_below(selections),
+            "s" => {
+                self.prompt = Some(Prompt::Select);
+                self.command_input.clear();
+            }
+            "y" => self.yank_selections(&selections, true),
+            "d" | "c" => {
+                self.yank_selections(&selections, false);
+                self.doc_mut().history.group();
+                let edits = selections.iter().map(|s| (s.start()..s.end(), String::new())).collect();
+                let placed = self.replace_each(edits);
+                let points: Vec<usize> = placed.iter().map(|r| r.start).collect();
+                if c == 'c' {
+                    self.insert_at_points(points);
+                } else {
+                    self.doc_mut().history.seal();
+                    self.set_selections(points.into_iter().map(Selection::at).collect(), self.primary);
+                }
+            }
+            "p" | "P" => {
+                let text = match self.registers.get(None) {
+                    Some(register) => register.text,
+                    None => return,
+                };
+                self.doc_mut().history.group();
+                let edits = selections.iter().map(|s| {
+                    let pos = if c == 'p' { s.end() } else { s.start() };
+                    (pos..pos, text.clone())
+                }).collect();
+                let placed = self.replace_each(edits);
+                self.doc_mut().history.seal();
+                let pasted = placed.iter().map(|r| Selection { anchor: r.start, head: r.end - 1 }).collect();
+                self.set_selections(pasted, self.primary);
+            }
+            "i" => self.insert_at_points(selections.iter().map(|s| s.start()).collect()),
+            "a" => {
+                let len = self.doc().buffer.len_chars();
+                self.insert_at_points(selections.iter().map(|s| s.end().min(len)).collect());
+            }
+            "o" | "O" => {
+                let buffer = self.doc().buffer.as_ref();
+                let mut lines: Vec<usize> = selections.iter().map(|s| buffer.char_to_line(s.head)).collect();
+                lines.dedup();
+                let edits = lines.iter().map(|&line| {
+                    let pos = if c == 'o' {
+                        buffer.line_to_char(line) + buffer.line_len(line)
+                    } else {
+                        buffer.line_to_char(line)
+                    };
+                    (pos..pos, "\n".to_string())
+                }).collect();
+                self.doc_mut().history.group();
+                let placed = self.replace_each(edits);
+                let points = placed.iter().map(|r| if c == 'o' { r.end } else { r.start }).collect();
+                self.insert_at_points(points);
+            }
+            ">" | "<" => self.indent_selections(selections, c == '>'),
+            _ => {}
+        }
+    }
+
+    // Enters insert mode at each of `points`, with a selection per point when
+    // there are several.
+    pub fn insert_at_points(&mut self, points: Vec<usize>) {
+        if !self.doc().history.grouping() {
+            self.doc_mut().history.group();
+        }
+        self.mode = Mode::Insert;
+        if points.len() == 1 {
+            self.selections.clear();
+            self.set_cursor_char(points[0]);
+        } else {
+            self.set_selections(points.into_iter().map(Selection::at).collect(), self.primary);
+        }
+    }
+
+    pub fn kakoune_leave_insert(&mut self) {
+        self.doc_mut().history.seal();
+        self.mode = Mode::Normal;
+        if !self.kakoune() {
+            self.selections.clear();
+        }
+    }
+
+    // Typing with several selections inserts at every one of them.
+    pub fn kakoune_insert_key(&mut self, key: KeyEvent) {
+        let points: Vec<usize> = self.selections.iter().map(|s| s.head).collect();
+        let text = match key.code {
+            KeyCode::Esc => return self.kakoune_leave_insert(),
+            KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c.to_string(),
+            KeyCode::Enter => "\n".to_string(),
+            KeyCode::Tab => "    ".to_string(),
+            KeyCode::Backspace => {
+                let edits = points.iter().map(|&p| (p.saturating_sub(1)..p, String::new())).collect();
+                let placed = self.replace_each(edits);
+                return self.set_selections(placed.iter().map(|r| Selection::at(r.start)).collect(), self.primary);
+            }
+            _ => return,
+        };
+        let edits = points.iter().map(|&p| (p..p, text.clone())).collect();
+        let placed = self.replace_each(edits);
+        self.set_selections(placed.iter().map(|r| Selection::at(r.end)).collect(), self.primary);
+    }
+
+    pub fn select_regex(&mut self, pattern: &str) {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.status_message = Some(format!("Invalid pattern: {}", e));
+                return;
+            }
+        };
+        let buffer = self.doc().buffer.as_ref();
+        let mut found = Vec::new();
+        for s in self.kakoune_selections() {
+            let start = s.start();
+            let text = buffer.slice(start..s.end().min(buffer.len_chars()));
+            for m in regex.find_iter(&text).filter(|m| !m.is_empty()) {
+                let anchor = start + text[..m.start()].chars().count();
+                found.push(Selection { anchor, head: anchor + m.as_str().chars().count() - 1 });
+            }
+        }
+        if found.is_empty() {
+            self.status_message = Some("Nothing selected".to_string());
+        } else {
+            let primary = found.len() - 1;
+            self.set_selections(found, primary);
+        }
+    }
+
+    // Adds a copy of the bottom selection on the next line it fits on.
+    fn copy_selection_below(&mut self, mut selections: Vec<Selection>) {
+        let buffer = self.doc().buffer.as_ref();
+        let last = *selections.last().unwrap();
+        let (anchor_line, anchor_col) = buffer.char_to_pos(last.anchor);
+        let (head_line, head_col) = buffer.char_to_pos(last.head);
+        let height = anchor_line.max(head_line) - anchor_line.min(head_line) + 1;
+        let mut offset = height;
+        while anchor_line.max(head_line) + offset < buffer.len_lines() {
+            let (a, h) = (anchor_line + offset, head_line + offset);
+            if anchor_col <= buffer.line_len(a) && head_col <= buffer.line_len(h) {
+                selections.push(Selection { anchor: buffer.pos_to_char(a, anchor_col), head: buffer.pos_to_char(h, head_col) });
+                let primary = selections.len() - 1;
+                return self.set_selections(selections, primary);
+            }
+            offset += height;
+        }
+    }
+
+    fn indent_selections(&mut self, selections: Vec<Selection>, indent: bool) {
+        let buffer = self.doc().buffer.as_ref();
+        let mut lines = Vec::new();
+        for s in &selections {
+            for line in buffer.char_to_line(s.start())..=buffer.char_to_line(s.end() - 1) {
+                if lines.last() != Some(&line) {
+                    lines.push(line);
+                }
+            }
+        }
+        let edits: Vec<(Range<usize>, String)> = lines.into_iter().filter_map(|line| {
+            let text = buffer.line(line);
+            let start = buffer.line_to_char(line);
+            if indent {
+                (!text.is_empty()).then(|| (start..start, "    ".to_string()))
+            } else {
+                let width = if text.starts_with('\t') {
+                    1
+                } else {
+                    text.chars().take(crate::unicode::TAB_WIDTH).take_while(|&c| c == ' ').count()
+                };
+                (width > 0).then(|| (start..start + width, String::new()))
+            }
+        }).collect();
+        // Selections move along with the text in front of them.
+        let shift = |pos: usize| {
+            let mut delta = 0isize;
+            for (range, text) in &edits {
+                if range.end <= pos {
+                    delta += text.chars().count() as isize - range.len() as isize;
+                } else if range.start <= pos {
+                    return (range.start as isize + delta) as usize;
+                }
+            }
+            (pos as isize + delta) as usize
+        };
+        let moved = selections.iter().map(|s| Selection { anchor: shift(s.anchor), head: shift(s.head) }).collect();
+        self.doc_mut().history.group();
+        self.replace_each(edits);
+        self.doc_mut().history.seal();
+        self.set_selections(moved, self.primary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rlua::Lua;
+
+    use crate::document::Document;
+    use crate::Atto;
+
+    // An editor on `text` with kakoune commands.
+    fn kakoune(text: &str) -> Atto {
+        let mut document = Document::new(None);
+        document.buffer.insert(0, text);
+        let lua = Lua::new();
+        crate::plugin::install(&lua).unwrap();
+        Atto::new(vec![document], "atto", true, "kakoune", lua)
+    }
+
+    fn selected(atto: &Atto) -> Vec<String> {
+        let buffer = atto.doc().buffer.as_ref();
+        atto.selections.iter().map(|s| buffer.slice(s.start()..s.end())).collect()
+    }
+
+    #[test]
+    fn select_regex() {
+        let mut atto = kakoune("foo bar\nbaz foo");
+        atto.type_keys("%sfoo<Enter>");
+        assert_eq!(selected(&atto), ["foo", "foo"]);
+        // The last match is the main selection, with the cursor on its end.
+        assert_eq!((atto.primary, atto.cursor_y, atto.cursor_x), (1, 1, 6));
+        // Only matches inside the selections count.
+        atto.type_keys("sb<Enter>");
+        assert!(atto.status_message.as_deref() == Some("Nothing selected"));
+        assert_eq!(selected(&atto), ["foo", "foo"]);
+        atto.type_keys("so<Enter>");
+        assert_eq!(selected(&atto), ["o", "o", "o", "o"]);
+    }
+
+    #[test]
+    fn copy_selection_below() {
+        let mut atto = kakoune("abc\nd\nefg\nhij");
+        atto.type_keys("llC");
+        // The short line is skipped.
+        assert_eq!(atto.selections.len(), 2);
+        assert_eq!((atto.cursor_y, atto.cursor_x), (2, 2));
+        atto.type_keys("C");
+        assert_eq!((atto.primary, atto.cursor_y, atto.cursor_x), (2, 3, 2));
+        assert_eq!(selected(&atto), ["c", "g", "j"]);
+    }
+
+    #[test]
+    fn multiple_selections() {
+        let mut atto = kakoune("one two\none two");
+        atto.type_keys("%stwo<Enter>d");
+        assert_eq!(atto.doc().buffer.text(), "one \none ");
+        atto.type_keys("u%stwo<Enter>cthree<Esc>");
+        assert_eq!(atto.doc().buffer.text(), "one three\none three");
+        // Each change is one undo.
+        atto.type_keys("u");
+        assert_eq!(atto.doc().buffer.text(), "one two\none two");
+        atto.type_keys("%sone<Enter>yp");
+        assert_eq!(atto.doc().buffer.text(), "oneone two\noneone two");
+        assert_eq!(selected(&atto), ["one", "one"]);
+        atto.type_keys("u%sone<Enter>;P");
+        assert_eq!(atto.doc().buffer.text(), "ononee two\nononee two");
+    }
+
+    #[test]
+    fn insert_at_every_selection() {
+        let mut atto = kakoune("ab\ncd\nef");
+        atto.type_keys("lCCix<Esc>");
+        assert_eq!(atto.doc().buffer.text(), "axb\ncxd\nexf");
+        atto.type_keys("ay<BS>z<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "axbz\n\ncxdz\n\nexfz\n");
+        atto.type_keys("<Esc>u");
+        assert_eq!(atto.doc().buffer.text(), "axb\ncxd\nexf");
+    }
+}
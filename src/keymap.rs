@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+pub type Key = (KeyCode, KeyModifiers);
+
+// An action is an editor command name ("save", "move_up", ...) or an ex
+// command; Lua functions bound with `atto.map` are looked up by mode and keys.
+#[derive(Clone)]
+pub enum Binding {
+    Action(String),
+    Plugin(String, String),
+}
+
+pub enum Lookup {
+    Found(Binding),
+    Pending,
+    Unbound,
+}
+
+const MOVEMENT: &[(&str, &str)] = &[
+    ("<Up>", "move_up"),
+    ("<Down>", "move_down"),
+    ("<Left>", "move_left"),
+    ("<Right>", "move_right"),
+    ("<PageUp>", "page_up"),
+    ("<PageDown>", "page_down"),
+];
+
+const EDITING: &[(&str, &str)] = &[
+    ("<Tab>", "tab"),
+    ("<BS>", "backspace"),
+    ("<Enter>", "newline"),
+    ("<C-Space>", "complete"),
+];
+
+// Shift+arrows select, F2 shows the file explorer, F3 finds again, F4 steps
+// through grep results, F7 and F8 set and visit bookmarks, F1, F6 and F12
+// ask the language server and Ctrl-r reloads the file in every preset (emacs
+// takes it back for searching).
+const COMMON: &[(&str, &str)] = &[
+    ("<S-Up>", "select_up"),
+    ("<S-Down>", "select_down"),
+    ("<S-Left>", "select_left"),
+    ("<S-Right>", "select_right"),
+    ("<F3>", "search_next"),
+    ("<S-F3>", "search_prev"),
+    ("<F4>", "quickfix_next"),
+    ("<S-F4>", "quickfix_prev"),
+    ("<F7>", "toggle_bookmark"),
+    ("<F8>", "next_bookmark"),
+    ("<S-F8>", "prev_bookmark"),
+    ("<F2>", "toggle_explorer"),
+    ("<F1>", "hover"),
+    ("<F6>", "rename_symbol"),
+    ("<F12>", "goto_definition"),
+    ("<S-F12>", "find_references"),
+    ("<C-r>", "reload"),
+];
+
+const ATTO: &[(&str, &str)] = &[
+    ("<C-w>", "save"),
+    ("<C-q>", "quit"),
+    ("<C-z>", "undo"),
+    ("<C-y>", "redo"),
+    ("<C-e>", "command_line"),
+    ("<C-c>", "copy"),
+    ("<C-x>", "cut"),
+    ("<C-v>", "paste"),
+    ("<C-f>", "search_forward"),
+    ("<C-n>", "search_next"),
+    ("<C-p>", "search_prev"),
+    ("<C-o>", "find_file"),
+];
+
+const NANO: &[(&str, &str)] = &[
+    ("<C-o>", "save"),
+    ("<C-x>", "quit"),
+    ("<A-u>", "undo"),
+    ("<A-e>", "redo"),
+    ("<C-y>", "page_up"),
+    ("<C-v>", "page_down"),
+    ("<A-x>", "command_line"),
+    ("<A-6>", "copy"),
+    ("<C-k>", "cut"),
+    ("<C-u>", "paste"),
+    ("<C-w>", "search_forward"),
+    ("<A-w>", "search_next"),
+    ("<A-q>", "search_prev"),
+    ("<C-t>", "find_file"),
+];
+
+const MICRO: &[(&str, &str)] = &[
+    ("<C-s>", "save"),
+    ("<C-q>", "quit"),
+    ("<C-z>", "undo"),
+    ("<C-y>", "redo"),
+    ("<C-e>", "command_line"),
+    ("<C-c>", "copy"),
+    ("<C-x>", "cut"),
+    ("<C-v>", "paste"),
+    ("<C-f>", "search_forward"),
+    ("<C-n>", "search_next"),
+    ("<C-p>", "search_prev"),
+    ("<C-o>", "find_file"),
+];
+
+const EMACS: &[(&str, &str)] = &[
+    ("<C-x><C-s>", "save"),
+    ("<C-x><C-c>", "quit"),
+    ("<C-x><C-f>", "find_file"),
+    ("<A-x>", "command_line"),
+    ("<C-p>", "move_up"),
+    ("<C-n>", "move_down"),
+    ("<C-b>", "move_left"),
+    ("<C-f>", "move_right"),
+    ("<A-v>", "page_up"),
+    ("<C-v>", "page_down"),
+    ("<C-/>", "undo"),
+    ("<C-x>u", "undo"),
+    ("<A-/>", "redo"),
+    ("<C-x>2", "split"),
+    ("<C-x>3", "vsplit"),
+    ("<C-x>0", "close_window"),
+    ("<C-x>o", "next_window"),
+    ("<C-x><Right>", "next_buffer"),
+    ("<C-x><Left>", "prev_buffer"),
+    ("<C-x><C-b>", "list_buffers"),
+    ("<A-w>", "copy"),
+    ("<C-w>", "cut"),
+    ("<C-y>", "paste"),
+    ("<C-s>", "search_forward"),
+    ("<C-r>", "search_backward"),
+];
+
+const DEFAULT: &[(&str, &str)] = &[
+    ("<C-t>", "save"),
+    ("<C-w>", "quit"),
+    ("<C-k>", "move_up"),
+    ("<C-j>", "move_down"),
+    ("<C-h>", "move_left"),
+    ("<C-l>", "move_right"),
+    ("<C-z>", "undo"),
+    ("<C-y>", "redo"),
+    ("<C-e>", "command_line"),
+    ("<C-c>", "copy"),
+    ("<C-x>", "cut"),
+    ("<C-v>", "paste"),
+    ("<C-f>", "search_forward"),
+    ("<C-n>", "search_next"),
+    ("<C-p>", "search_prev"),
+    ("<C-o>", "find_file"),
+];
+
+// Motions, operators and the like are parsed by the vim grammar; only the
+// keys outside of it are bound here.
+const VIM_NORMAL: &[(&str, &str)] = &[
+    (":", "command_line"),
+    ("u", "undo"),
+    ("<C-r>", "redo"),
+    ("/", "search_forward"),
+    ("?", "search_backward"),
+    ("n", "search_next"),
+    ("N", "search_prev"),
+    ("v", "visual"),
+    ("V", "visual_line"),
+    ("<C-v>", "visual_block"),
+    ("<C-p>", "find_file"),
+    ("<C-n>", "toggle_explorer"),
+    ("K", "hover"),
+    ("gd", "goto_definition"),
+    ("gr", "find_references"),
+    ("gR", "rename_symbol"),
+    ("mm", "toggle_bookmark"),
+    ("mn", "next_bookmark"),
+    ("mp", "prev_bookmark"),
+    ("<C-w>s", "split"),
+    ("<C-w>v", "vsplit"),
+    ("<C-w>c", "close_window"),
+    ("<C-w>q", "close_window"),
+    ("<C-w>w", "next_window"),
+    ("<C-w>h", "focus_left"),
+    ("<C-w>j", "focus_down"),
+    ("<C-w>k", "focus_up"),
+    ("<C-w>l", "focus_right"),
+    ("<C-w><Left>", "focus_left"),
+    ("<C-w><Down>", "focus_down"),
+    ("<C-w><Up>", "focus_up"),
+    ("<C-w><Right>", "focus_right"),
+];
+
+// As with vim, the selection commands themselves are handled by the
+// kakoune module.
+const KAKOUNE_NORMAL: &[(&str, &str)] = &[
+    (":", "command_line"),
+    ("u", "undo"),
+    ("U", "redo"),
+    ("/", "search_forward"),
+    ("?", "search_backward"),
+    ("n", "search_next"),
+    ("N", "search_prev"),
+    ("<C-p>", "find_file"),
+    ("<C-n>", "toggle_explorer"),
+    ("gd", "goto_definition"),
+    ("gr", "find_references"),
+];
+
+const VIM_INSERT: &[(&str, &str)] = &[
+    ("<Esc>", "normal_mode"),
+    ("<C-n>", "complete"),
+    ("<C-p>", "complete"),
+];
+
+// Operators on the selection are parsed by the vim module as well.
+const VIM_VISUAL: &[(&str, &str)] = &[
+    ("<Esc>", "normal_mode"),
+    ("v", "visual"),
+    ("V", "visual_line"),
+    ("<C-v>", "visual_block"),
+];
+
+pub struct Keymap {
+    modes: HashMap<String, HashMap<Vec<Key>, Binding>>,
+}
+
+impl Keymap {
+    pub fn preset(preset: &str, vim_mode: bool, command_style: &str) -> Self {
+        let mut keymap = Keymap { modes: HashMap::new() };
+        if vim_mode {
+            keymap.bind_all("normal", MOVEMENT);
+            keymap.bind_all("normal", if command_style == "kakoune" { KAKOUNE_NORMAL } else { VIM_NORMAL });
+            keymap.bind_all("insert", MOVEMENT);
+            keymap.bind_all("insert", EDITING);
+            keymap.bind_all("insert", VIM_INSERT);
+            keymap.bind_all("visual", MOVEMENT);
+            keymap.bind_all("visual", VIM_VISUAL);
+        } else {
+            let table = match preset {
+                "atto" => ATTO,
+                "nano" => NANO,
+                "micro" => MICRO,
+                "emacs" => EMACS,
+                _ => DEFAULT,
+            };
+            keymap.bind_all("insert", MOVEMENT);
+            keymap.bind_all("insert", EDITING);
+            keymap.bind_all("insert", COMMON);
+            keymap.bind_all("insert", table);
+        }
+        keymap
+    }
+
+    fn bind_all(&mut self, mode: &str, table: &[(&str, &str)]) {
+        for (keys, action) in table {
+            self.bind(mode, keys, Binding::Action(action.to_string()));
+        }
+    }
+
+    // Returns false if `keys` does not parse.
+    pub fn bind(&mut self, mode: &str, keys: &str, binding: Binding) -> bool {
+        match parse_keys(keys) {
+            Some(keys) => {
+                self.modes.entry(mode.to_string()).or_default().insert(keys, binding);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn lookup(&self, mode: &str, pending: &[Key]) -> Lookup {
+        let bindings = match self.modes.get(mode) {
+            Some(bindings) => bindings,
+            None => return Lookup::Unbound,
+        };
+        if let Some(binding) = bindings.get(pending) {
+            return Lookup::Found(binding.clone());
+        }
+        if bindings.keys().any(|keys| keys.len() > pending.len() && keys.starts_with(pending)) {
+            Lookup::Pending
+        } else {
+            Lookup::Unbound
+        }
+    }
+}
+
+// Shift is implied by the character itself, and by BackTab, which is what
+// terminals send for Shift-Tab; they also report Ctrl-/ and Ctrl-_ as Ctrl-7.
+pub fn normalize(code: KeyCode, modifiers: KeyModifiers) -> Key {
+    match code {
+        KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => (KeyCode::BackTab, modifiers - KeyModifiers::SHIFT),
+        KeyCode::BackTab => (code, modifiers - KeyModifiers::SHIFT),
+        KeyCode::Char('/') | KeyCode::Char('_') if modifiers.contains(KeyModifiers::CONTROL) => {
+            (KeyCode::Char('7'), modifiers - KeyModifiers::SHIFT)
+        }
+        KeyCode::Char(_) => (code, modifiers - KeyModifiers::SHIFT),
+        _ => (code, modifiers),
+    }
+}
+
+pub fn key_of(event: KeyEvent) -> Key {
+    normalize(event.code, event.modifiers)
+}
+
+// Parses sequences like "<C-x><C-s>", "<C-w>v" or "gg".
+pub fn parse_keys(text: &str) -> Option<Vec<Key>> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                if end > 1 {
+                    keys.push(parse_key(&rest[1..end])?);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        keys.push((KeyCode::Char(c), KeyModifiers::NONE));
+        rest = &rest[c.len_utf8()..];
+    }
+    (!keys.is_empty()).then_some(keys)
+}
+
+fn parse_key(text: &str) -> Option<Key> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut name = text;
+    while name.len() > 2 && name.as_bytes()[1] == b'-' {
+        modifiers |= match name.as_bytes()[0].to_ascii_uppercase() {
+            b'C' => KeyModifiers::CONTROL,
+            b'A' | b'M' => KeyModifiers::ALT,
+            b'S' => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+        name = &name[2..];
+    }
+    let code = match name.to_ascii_lowercase().as_str() {
+        "space" => KeyCode::Char(' '),
+        "enter" | "cr" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "bs" | "backspace" => KeyCode::Backspace,
+        "del" | "delete" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "lt" => KeyCode::Char('<'),
+        lower if lower.len() > 1 && lower.starts_with('f') => KeyCode::F(lower[1..].parse().ok()?),
+        _ => {
+            let mut chars = name.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            KeyCode::Char(c)
+        }
+    };
+    Some(normalize(code, modifiers))
+}
+
+// The inverse of `parse_keys` for a single key: "x", "<C-s>", "<A-Enter>".
+pub fn key_name((code, modifiers): Key) -> String {
+    let base = match code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char('<') => "lt".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "Enter".to_string(),
+        KeyCode::Esc => "Esc".to_string(),
+        KeyCode::Tab => "Tab".to_string(),
+        KeyCode::BackTab => "S-Tab".to_string(),
+        KeyCode::Backspace => "BS".to_string(),
+        KeyCode::Delete => "Del".to_string(),
+        KeyCode::Insert => "Insert".to_string(),
+        KeyCode::Up => "Up".to_string(),
+        KeyCode::Down => "Down".to_string(),
+        KeyCode::Left => "Left".to_string(),
+        KeyCode::Right => "Right".to_string(),
+        KeyCode::Home => "Home".to_string(),
+        KeyCode::End => "End".to_string(),
+        KeyCode::PageUp => "PageUp".to_string(),
+        KeyCode::PageDown => "PageDown".to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        _ => return String::new(),
+    };
+    let mut prefix = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        prefix.push_str("C-");
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        prefix.push_str("A-");
+    }
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        prefix.push_str("S-");
+    }
+    if prefix.is_empty() && base.chars().count() == 1 {
+        base
+    } else {
+        format!("<{}{}>", prefix, base)
+    }
+}
+
+pub fn keys_name(keys: &[Key]) -> String {
+    keys.iter().map(|&key| key_name(key)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(keymap: &Keymap, mode: &str, keys: &str) -> Option<String> {
+        match keymap.lookup(mode, &parse_keys(keys).unwrap()) {
+            Lookup::Found(Binding::Action(action)) => Some(action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn shift_tab() {
+        let event = KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT);
+        assert_eq!(parse_keys("<S-Tab>"), Some(vec![key_of(event)]));
+        assert_eq!(key_of(KeyEvent::new(KeyCode::Tab, KeyModifiers::SHIFT)), key_of(event));
+        assert_eq!(keys_name(&[key_of(event)]), "<S-Tab>");
+        let mut keymap = Keymap::preset("atto", false, "vim");
+        keymap.bind("insert", "<S-Tab>", Binding::Action("dedent".to_string()));
+        assert!(matches!(keymap.lookup("insert", &[key_of(event)]), Lookup::Found(_)));
+    }
+
+    #[test]
+    fn reload_key() {
+        assert_eq!(action(&Keymap::preset("atto", false, "vim"), "insert", "<C-r>").as_deref(), Some("reload"));
+        assert_eq!(action(&Keymap::preset("emacs", false, "vim"), "insert", "<C-r>").as_deref(), Some("search_backward"));
+        let vim = Keymap::preset("atto", true, "vim");
+        assert_eq!(action(&vim, "insert", "<C-r>"), None);
+        assert_eq!(action(&vim, "normal", "<C-r>").as_deref(), Some("redo"));
+    }
+}
@@ -0,0 +1,982 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+use tui::backend::Backend;
+use tui::layout::Rect;
+use tui::style::{Color, Style};
+use tui::text::Span;
+use tui::widgets::{Block, Borders, Clear, Paragraph, Wrap};
+
+use crate::buffer::TextBuffer;
+use crate::completion::Item;
+use crate::grep::{GrepMatch, Quickfix};
+use crate::Atto;
+
+// Language ids, which also name the servers in config.lua, and the file
+// extensions they are used for.
+const LANGUAGES: &[(&str, &[&str])] = &[
+    ("rust", &["rs"]),
+    ("python", &["py", "pyi"]),
+    ("c", &["c", "h"]),
+    ("cpp", &["cc", "cpp", "cxx", "hh", "hpp", "hxx"]),
+    ("go", &["go"]),
+    ("javascript", &["js", "mjs", "cjs"]),
+    ("typescript", &["ts", "tsx"]),
+    ("json", &["json"]),
+    ("markdown", &["md"]),
+    ("lua", &["lua"]),
+    ("shellscript", &["sh", "bash"]),
+];
+
+const HOVER_WIDTH: u16 = 72;
+const HOVER_HEIGHT: u16 = 12;
+
+// CompletionItemKind names, from 1.
+const KINDS: &[&str] = &[
+    "text", "method", "function", "constructor", "field", "variable", "class", "interface", "module", "property",
+    "unit", "value", "enum", "keyword", "snippet", "color", "file", "reference", "folder", "enum member",
+    "constant", "struct", "event", "operator", "type parameter",
+];
+
+// A line and UTF-16 code unit offset, as LSP counts them.
+pub type Position = (usize, usize);
+
+// An edit to send with the next didChange: `range` None replaces the whole
+// document.
+pub struct Change {
+    pub range: Option<(Position, Position)>,
+    pub text: String,
+}
+
+pub struct Diagnostic {
+    // Chars of the document.
+    pub range: Range<usize>,
+    // 1 error, 2 warning, 3 information, 4 hint.
+    pub severity: u64,
+    pub message: String,
+}
+
+pub struct ServerConfig {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+// What a request was for, to know what to do with its response.
+enum Request {
+    Initialize,
+    Hover,
+    Definition,
+    References,
+    Rename,
+    Formatting(String),
+    // The char index of the word being completed.
+    Completion(usize),
+}
+
+struct Client {
+    language: String,
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Value>,
+    next_id: u64,
+    pending: HashMap<u64, Request>,
+    initialized: bool,
+    // TextDocumentSyncKind: 0 none, 1 the full text, 2 incremental.
+    sync: u64,
+    // The characters completing after, when the server completes at all.
+    triggers: Option<Vec<char>>,
+    // Documents opened with the server, by uri.
+    opened: HashSet<String>,
+}
+
+// The language servers configured per language in config.lua, started when
+// a file of that language is first opened.
+#[derive(Default)]
+pub struct Lsp {
+    configs: HashMap<String, ServerConfig>,
+    clients: Vec<Client>,
+    // Languages whose server could not be started, so it isn't retried.
+    failed: HashSet<String>,
+    pub hover: Option<String>,
+}
+
+pub fn language_id(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename).extension()?.to_str()?;
+    LANGUAGES.iter().find(|(_, extensions)| extensions.contains(&extension)).map(|(language, _)| *language)
+}
+
+pub fn position(buffer: &dyn TextBuffer, pos: usize) -> Position {
+    let (line, col) = buffer.char_to_pos(pos);
+    (line, buffer.line(line).chars().take(col).map(char::len_utf16).sum())
+}
+
+// The char index of an LSP position, clamped to the document.
+fn char_index(buffer: &dyn TextBuffer, (line, character): Position) -> usize {
+    if line >= buffer.len_lines() {
+        return buffer.len_chars();
+    }
+    let mut units = 0;
+    let mut col = 0;
+    for c in buffer.line(line).chars() {
+        if units >= character {
+            break;
+        }
+        units += c.len_utf16();
+        col += 1;
+    }
+    buffer.pos_to_char(line, col)
+}
+
+fn position_json((line, character): Position) -> Value {
+    json!({ "line": line, "character": character })
+}
+
+fn parse_position(value: &Value) -> Position {
+    let field = |name| value[name].as_u64().unwrap_or(0) as usize;
+    (field("line"), field("character"))
+}
+
+fn parse_range(value: &Value) -> (Position, Position) {
+    (parse_position(&value["start"]), parse_position(&value["end"]))
+}
+
+pub fn uri(filename: &str) -> String {
+    let path = env::current_dir().map(|dir| dir.join(filename)).unwrap_or_else(|_| PathBuf::from(filename));
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+// The path of a file uri, relative to the working directory when it is
+// under it so it names the same file as the buffers do.
+fn uri_path(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let decoded = (encoded[i] == b'%').then(|| encoded.get(i + 1..i + 3)).flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    let path = PathBuf::from(String::from_utf8(bytes).ok()?);
+    let relative = env::current_dir().ok().and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf));
+    Some(relative.unwrap_or(path).display().to_string())
+}
+
+// Hover contents come as a string, a MarkedString, MarkupContent or a list
+// of either of the first two.
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(hover_text).filter(|text| !text.is_empty()).collect::<Vec<_>>().join("\n\n"),
+        Value::Object(object) => object.get("value").and_then(Value::as_str).unwrap_or("").to_string(),
+        _ => String::new(),
+    }
+}
+
+// Reads messages framed by a Content-Length header until the server goes.
+fn read_messages(stdout: impl Read, sender: mpsc::Sender<Value>) {
+    let mut reader = BufReader::new(stdout);
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            match reader.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let mut body = vec![0; length.unwrap_or(0)];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        if let Ok(message) = serde_json::from_slice(&body) {
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Client {
+    fn start(language: &str, config: &ServerConfig) -> io::Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || read_messages(stdout, sender));
+        let mut client = Self {
+            language: language.to_string(),
+            child,
+            stdin,
+            messages,
+            next_id: 0,
+            pending: HashMap::new(),
+            initialized: false,
+            sync: 1,
+            triggers: None,
+            opened: HashSet::new(),
+        };
+        let root = env::current_dir().map(|dir| uri(&dir.display().to_string())).ok();
+        client.request("initialize", json!({
+            "processId": std::process::id(),
+            "rootUri": root,
+            "workspaceFolders": root.as_ref().map(|root| json!([{ "uri": root, "name": "root" }])),
+            "capabilities": {
+                "general": { "positionEncodings": ["utf-16"] },
+                "textDocument": {
+                    "synchronization": { "didSave": true },
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "definition": {},
+                    "references": {},
+                    "rename": {},
+                    "formatting": {},
+                    "completion": { "completionItem": { "snippetSupport": true } },
+                    "publishDiagnostics": {},
+                },
+                "workspace": { "applyEdit": true, "workspaceEdit": { "documentChanges": true } },
+            },
+        }), Request::Initialize)?;
+        Ok(client)
+    }
+
+    fn send(&mut self, message: Value) -> io::Result<()> {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.stdin.flush()
+    }
+
+    fn request(&mut self, method: &str, params: Value, request: Request) -> io::Result<()> {
+        self.next_id += 1;
+        self.pending.insert(self.next_id, request);
+        self.send(json!({ "jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params }))
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn respond(&mut self, id: Value, result: Value) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.notify("exit", Value::Null);
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Lsp {
+    pub fn new(configs: HashMap<String, ServerConfig>) -> Self {
+        Self { configs, ..Self::default() }
+    }
+
+    pub fn running(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    fn client(&self, language: &str) -> Option<usize> {
+        self.clients.iter().position(|client| client.language == language)
+    }
+}
+
+impl Atto {
+    // Starts servers for newly opened documents, tells them about edits and
+    // handles what they sent since the last call.
+    pub fn lsp_update(&mut self) {
+        let mut messages = Vec::new();
+        let mut exited = Vec::new();
+        for client in &self.lsp.clients {
+            loop {
+                match client.messages.try_recv() {
+                    Ok(message) => messages.push((client.language.clone(), message)),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        exited.push(client.language.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        for (language, message) in messages {
+            self.lsp_handle(&language, message);
+        }
+        for language in exited {
+            self.lsp_stop(&language, "exited");
+        }
+        for index in 0..self.documents.len() {
+            self.lsp_sync(index);
+        }
+    }
+
+    fn lsp_stop(&mut self, language: &str, reason: &str) {
+        if let Some(index) = self.lsp.client(language) {
+            self.lsp.clients.remove(index);
+            self.lsp.failed.insert(language.to_string());
+            self.status_message = Some(format!("Language server for {} {}", language, reason));
+            for document in self.documents.iter_mut() {
+                if document.filename.as_deref().and_then(language_id) == Some(language) {
+                    document.changes = None;
+                    document.diagnostics.clear();
+                }
+            }
+        }
+    }
+
+    fn lsp_send(&mut self, language: &str, send: impl FnOnce(&mut Client) -> io::Result<()>) {
+        let result = match self.lsp.client(language) {
+            Some(index) => send(&mut self.lsp.clients[index]),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.lsp_stop(language, &format!("stopped: {}", e));
+        }
+    }
+
+    // Opens the document with its server, starting the server first if need
+    // be, or sends the edits made since it was last synced.
+    fn lsp_sync(&mut self, index: usize) {
+        let filename = match self.documents[index].filename.clone() {
+            Some(filename) => filename,
+            None => return,
+        };
+        let language = match language_id(&filename) {
+            Some(language) if self.lsp.configs.contains_key(language) && !self.lsp.failed.contains(language) => language,
+            _ => return,
+        };
+        let client = match self.lsp.client(language) {
+            Some(client) => client,
+            None => {
+                match Client::start(language, &self.lsp.configs[language]) {
+                    Ok(client) => self.lsp.clients.push(client),
+                    Err(e) => {
+                        self.lsp.failed.insert(language.to_string());
+                        self.status_message = Some(format!("Could not start {}: {}", self.lsp.configs[language].command, e));
+                    }
+                }
+                return;
+            }
+        };
+        let client = &self.lsp.clients[client];
+        let uri = uri(&filename);
+        if !client.initialized {
+            return;
+        }
+        let document = &mut self.documents[index];
+        if !client.opened.contains(&uri) || document.changes.is_none() {
+            document.changes = Some(Vec::new());
+            document.version += 1;
+            let params = json!({
+                "textDocument": { "uri": uri, "languageId": language, "version": document.version, "text": document.buffer.text() },
+            });
+            self.lsp_send(language, |client| {
+                client.opened.insert(uri);
+                client.notify("textDocument/didOpen", params)
+            });
+            return;
+        }
+        let changes = match document.changes.as_mut() {
+            Some(changes) if !changes.is_empty() => std::mem::take(changes),
+            _ => return,
+        };
+        // Taken all the same when the server isn't told about edits, so they
+        // don't pile up.
+        if client.sync == 0 {
+            return;
+        }
+        document.version += 1;
+        let content_changes: Vec<Value> = if client.sync == 2 {
+            changes.into_iter().map(|change| match change.range {
+                Some((start, end)) => json!({ "range": { "start": position_json(start), "end": position_json(end) }, "text": change.text }),
+                None => json!({ "text": change.text }),
+            }).collect()
+        } else {
+            vec![json!({ "text": document.buffer.text() })]
+        };
+        let params = json!({
+            "textDocument": { "uri": uri, "version": document.version },
+            "contentChanges": content_changes,
+        });
+        self.lsp_send(language, |client| client.notify("textDocument/didChange", params));
+    }
+
+    pub fn lsp_saved(&mut self) {
+        let filename = match self.doc().filename.clone() {
+            Some(filename) => filename,
+            None => return,
+        };
+        if let Some(language) = language_id(&filename) {
+            let params = json!({ "textDocument": { "uri": uri(&filename) } });
+            self.lsp_send(language, |client| client.notify("textDocument/didSave", params));
+        }
+    }
+
+    fn lsp_handle(&mut self, language: &str, message: Value) {
+        let method = message["method"].as_str().map(str::to_string);
+        match (method, message.get("id").cloned()) {
+            (Some(method), Some(id)) => self.lsp_server_request(language, &method, id, &message["params"]),
+            (Some(method), None) => self.lsp_notification(&method, &message["params"]),
+            (None, Some(id)) => {
+                let request = match (self.lsp.client(language), id.as_u64()) {
+                    (Some(index), Some(id)) => self.lsp.clients[index].pending.remove(&id),
+                    _ => None,
+                };
+                if let Some(error) = message.get("error") {
+                    let text = error["message"].as_str().unwrap_or("request failed");
+                    self.status_message = Some(format!("{}: {}", language, text));
+                } else if let Some(request) = request {
+                    self.lsp_response(language, request, &message["result"]);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    // Requests from the server; anything not handled gets a null result so
+    // the server isn't left waiting.
+    fn lsp_server_request(&mut self, language: &str, method: &str, id: Value, params: &Value) {
+        let result = match method {
+            "workspace/applyEdit" => {
+                self.apply_workspace_edit(&params["edit"]);
+                json!({ "applied": true })
+            }
+            "workspace/configuration" => {
+                let items = params["items"].as_array().map_or(0, Vec::len);
+                Value::Array(vec![Value::Null; items])
+            }
+            _ => Value::Null,
+        };
+        self.lsp_send(language, |client| client.respond(id, result));
+    }
+
+    fn lsp_notification(&mut self, method: &str, params: &Value) {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                let uri = params["uri"].as_str().unwrap_or("");
+                let document = match self.documents.iter_mut().find(|d| d.filename.as_deref().map(self::uri).as_deref() == Some(uri)) {
+                    Some(document) => document,
+                    None => return,
+                };
+                let buffer = document.buffer.as_ref();
+                document.diagnostics = params["diagnostics"].as_array().map(|diagnostics| {
+                    diagnostics.iter().map(|diagnostic| {
+                        let (start, end) = parse_range(&diagnostic["range"]);
+                        Diagnostic {
+                            range: char_index(buffer, start)..char_index(buffer, end),
+                            severity: diagnostic["severity"].as_u64().unwrap_or(1),
+                            message: diagnostic["message"].as_str().unwrap_or("").to_string(),
+                        }
+                    }).collect()
+                }).unwrap_or_default();
+            }
+            "window/showMessage" => {
+                if let Some(message) = params["message"].as_str() {
+                    self.status_message = Some(message.lines().next().unwrap_or("").to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn lsp_response(&mut self, language: &str, request: Request, result: &Value) {
+        match request {
+            Request::Initialize => {
+                let sync = &result["capabilities"]["textDocumentSync"];
+                let kind = sync.as_u64().or_else(|| sync["change"].as_u64()).unwrap_or(0);
+                let completion = &result["capabilities"]["completionProvider"];
+                let triggers = completion.is_object().then(|| {
+                    completion["triggerCharacters"].as_array().into_iter().flatten()
+                        .filter_map(|c| c.as_str()?.chars().next())
+                        .collect()
+                });
+                self.lsp_send(language, |client| {
+                    client.initialized = true;
+                    client.sync = kind;
+                    client.triggers = triggers;
+                    client.notify("initialized", json!({}))
+                });
+            }
+            Request::Hover => {
+                let text = hover_text(&result["contents"]);
+                if text.trim().is_empty() {
+                    self.status_message = Some("No hover information".to_string());
+                } else {
+                    self.lsp.hover = Some(text.trim().to_string());
+                }
+            }
+            Request::Definition => {
+                // A Location, a list of them or a list of LocationLinks.
+                let location = result.as_array().and_then(|locations| locations.first()).unwrap_or(result);
+                let uri = location.get("targetUri").or_else(|| location.get("uri")).and_then(Value::as_str);
+                let range = location.get("targetSelectionRange").or_else(|| location.get("range"));
+                match (uri.and_then(uri_path), range) {
+                    (Some(path), Some(range)) => {
+                        let start = parse_range(range).0;
+                        self.edit_file(&path);
+                        if self.doc().filename.as_deref() == Some(path.as_str()) {
+                            let pos = char_index(self.doc().buffer.as_ref(), start);
+                            self.set_cursor_char(pos);
+                        }
+                    }
+                    _ => self.status_message = Some("No definition found".to_string()),
+                }
+            }
+            Request::References => {
+                let matches: Vec<GrepMatch> = result.as_array().into_iter().flatten().filter_map(|location| {
+                    let path = uri_path(location["uri"].as_str()?)?;
+                    let (line, character) = parse_range(&location["range"]).0;
+                    let text = self.line_of(&path, line);
+                    let col = text.chars().scan(0, |units, c| {
+                        *units += c.len_utf16();
+                        Some(*units)
+                    }).take_while(|&units| units <= character).count();
+                    Some(GrepMatch { path, line, col, text: text.trim().to_string() })
+                }).collect();
+                if matches.is_empty() {
+                    self.status_message = Some("No references found".to_string());
+                } else {
+                    let title = format!("references ({})", matches.len());
+                    self.quickfix = Some(Quickfix::new(title, matches));
+                }
+            }
+            Request::Rename => self.apply_workspace_edit(result),
+            Request::Formatting(uri) => {
+                if let Some(path) = uri_path(&uri) {
+                    self.apply_text_edits(&path, result);
+                }
+            }
+            Request::Completion(start) => {
+                // A list of CompletionItems or a CompletionList.
+                let items = result.as_array().or_else(|| result["items"].as_array());
+                let items = items.into_iter().flatten().filter_map(|item| {
+                    let label = item["label"].as_str()?.to_string();
+                    let text = item["textEdit"]["newText"].as_str().or_else(|| item["insertText"].as_str()).unwrap_or(&label).to_string();
+                    let kind = item["kind"].as_u64().and_then(|kind| KINDS.get((kind as usize).checked_sub(1)?)).copied();
+                    let detail = item["detail"].as_str().or(kind).unwrap_or("").lines().next().unwrap_or("").to_string();
+                    Some(Item { label, text, detail, snippet: item["insertTextFormat"].as_u64() == Some(2) })
+                }).collect();
+                self.add_completions(start, items);
+            }
+        }
+    }
+
+    // A line of a file, from its buffer when it is open.
+    fn line_of(&self, path: &str, line: usize) -> String {
+        match self.documents.iter().find(|d| d.filename.as_deref() == Some(path)) {
+            Some(document) if line < document.buffer.len_lines() => document.buffer.line(line),
+            Some(_) => String::new(),
+            None => fs::read_to_string(path).ok().and_then(|text| text.lines().nth(line).map(str::to_string)).unwrap_or_default(),
+        }
+    }
+
+    fn apply_workspace_edit(&mut self, edit: &Value) {
+        let mut files = Vec::new();
+        if let Some(changes) = edit["changes"].as_object() {
+            files.extend(changes.iter().map(|(uri, edits)| (uri.clone(), edits.clone())));
+        }
+        for change in edit["documentChanges"].as_array().into_iter().flatten() {
+            if let Some(uri) = change["textDocument"]["uri"].as_str() {
+                files.push((uri.to_string(), change["edits"].clone()));
+            }
+        }
+        let count = files.len();
+        for (uri, edits) in files {
+            if let Some(path) = uri_path(&uri) {
+                self.apply_text_edits(&path, &edits);
+            }
+        }
+        if count > 1 {
+            self.status_message = Some(format!("Changed {} files", count));
+        }
+    }
+
+    // Applies a list of TextEdits to a file, opening it if need be, as one
+    // undo step.
+    fn apply_text_edits(&mut self, path: &str, edits: &Value) {
+        let edits = match edits.as_array() {
+            Some(edits) if !edits.is_empty() => edits,
+            _ => return,
+        };
+        let (previous, cursor) = (self.current, (self.cursor_y, self.cursor_x));
+        self.edit_file(path);
+        if self.doc().filename.as_deref() != Some(path) {
+            return;
+        }
+        let buffer = self.doc().buffer.as_ref();
+        let mut edits: Vec<(Range<usize>, String)> = edits.iter().map(|edit| {
+            let (start, end) = parse_range(&edit["range"]);
+            let text = edit["newText"].as_str().unwrap_or("").to_string();
+            (char_index(buffer, start)..char_index(buffer, end), text)
+        }).collect();
+        edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+        self.doc_mut().history.group();
+        for (range, text) in edits {
+            if !range.is_empty() {
+                self.remove_text(range.clone());
+            }
+            if !text.is_empty() {
+                self.insert_text(range.start, &text);
+            }
+        }
+        self.doc_mut().history.seal();
+        if self.current == previous {
+            self.set_cursor(cursor);
+        } else {
+            self.switch_buffer(previous);
+        }
+    }
+
+    // The server for the current document and where the cursor is, for a
+    // request about it.
+    fn lsp_target(&mut self) -> Option<(&'static str, Value)> {
+        let filename = self.doc().filename.clone();
+        let language = filename.as_deref().and_then(language_id).filter(|language| self.lsp.client(language).is_some());
+        let language = match language {
+            Some(language) => language,
+            None => {
+                self.status_message = Some("No language server for this file".to_string());
+                return None;
+            }
+        };
+        let position = position(self.doc().buffer.as_ref(), self.cursor_char());
+        Some((language, json!({
+            "textDocument": { "uri": uri(filename.as_deref().unwrap_or("")) },
+            "position": position_json(position),
+        })))
+    }
+
+    pub fn lsp_hover(&mut self) {
+        if let Some((language, params)) = self.lsp_target() {
+            self.lsp_send(language, |client| client.request("textDocument/hover", params, Request::Hover));
+        }
+    }
+
+    pub fn goto_definition(&mut self) {
+        if let Some((language, params)) = self.lsp_target() {
+            self.lsp_send(language, |client| client.request("textDocument/definition", params, Request::Definition));
+        }
+    }
+
+    pub fn find_references(&mut self) {
+        if let Some((language, mut params)) = self.lsp_target() {
+            params["context"] = json!({ "includeDeclaration": true });
+            self.lsp_send(language, |client| client.request("textDocument/references", params, Request::References));
+        }
+    }
+
+    pub fn rename_symbol(&mut self, name: &str) {
+        if name.is_empty() {
+            self.status_message = Some("Usage: rename <new name>".to_string());
+            return;
+        }
+        if let Some((language, mut params)) = self.lsp_target() {
+            params["newName"] = json!(name);
+            self.lsp_send(language, |client| client.request("textDocument/rename", params, Request::Rename));
+        }
+    }
+
+    pub fn format_document(&mut self) {
+        if let Some((language, mut params)) = self.lsp_target() {
+            let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+            params.as_object_mut().unwrap().remove("position");
+            params["options"] = json!({ "tabSize": crate::unicode::TAB_WIDTH, "insertSpaces": true });
+            self.lsp_send(language, |client| client.request("textDocument/formatting", params, Request::Formatting(uri)));
+        }
+    }
+
+    // Asks the server of the current document to complete the word starting
+    // at `start`, after sending it what was just typed.
+    pub fn lsp_complete(&mut self, start: usize) {
+        let filename = match self.doc().filename.clone() {
+            Some(filename) => filename,
+            None => return,
+        };
+        let language = match language_id(&filename) {
+            Some(language) if self.lsp_triggers(language).is_some() => language,
+            _ => return,
+        };
+        self.lsp_sync(self.current);
+        let position = position(self.doc().buffer.as_ref(), self.cursor_char());
+        let params = json!({ "textDocument": { "uri": uri(&filename) }, "position": position_json(position) });
+        self.lsp_send(language, |client| client.request("textDocument/completion", params, Request::Completion(start)));
+    }
+
+    fn lsp_triggers(&self, language: &str) -> Option<&[char]> {
+        let client = &self.lsp.clients[self.lsp.client(language)?];
+        client.triggers.as_deref().filter(|_| client.initialized)
+    }
+
+    // Whether the server of the current document completes after `c`.
+    pub fn lsp_triggers_completion(&self, c: char) -> bool {
+        let language = self.doc().filename.as_deref().and_then(language_id);
+        language.and_then(|language| self.lsp_triggers(language)).is_some_and(|triggers| triggers.contains(&c))
+    }
+
+    // Whether a completion request is still waiting for its answer.
+    pub fn lsp_completing(&self) -> bool {
+        self.lsp.clients.iter().any(|client| client.pending.values().any(|request| matches!(request, Request::Completion(_))))
+    }
+
+    // The message of the most severe diagnostic on the cursor line.
+    pub fn diagnostic_at_cursor(&self) -> Option<&str> {
+        let doc = self.doc();
+        let buffer = doc.buffer.as_ref();
+        let start = buffer.line_to_char(self.cursor_y);
+        let end = start + buffer.line_len(self.cursor_y);
+        doc.diagnostics.iter()
+            .filter(|d| d.range.start <= end && d.range.end >= start)
+            .min_by_key(|d| d.severity)
+            .map(|d| d.message.lines().next().unwrap_or(""))
+    }
+
+    // The hover text in a box under the cursor, or over it when there is no
+    // room below.
+    pub fn render_hover<B: Backend>(&self, f: &mut tui::Frame<B>, size: Rect) {
+        let text = match &self.lsp.hover {
+            Some(text) => text,
+            None => return,
+        };
+        let (x, y) = self.cursor_screen_position();
+        let width = HOVER_WIDTH.min(size.width);
+        let lines = text.lines().map(|line| (line.chars().count() as u16).div_ceil(width.saturating_sub(2).max(1)).max(1)).sum::<u16>();
+        let height = (lines + 2).min(HOVER_HEIGHT);
+        let below = y + 1 + height < size.height;
+        let y = if below { y + 1 } else { y.saturating_sub(height) };
+        let area = Rect::new(x.min(size.width - width), y, width, height.min(size.height));
+        f.render_widget(Clear, area);
+        let paragraph = Paragraph::new(text.as_str())
+            .block(Block::default().borders(Borders::ALL).title(Span::raw("hover")))
+            .style(Style::default().fg(Color::White))
+            .wrap(Wrap { trim: false });
+        f.render_widget(paragraph, area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process, thread};
+
+    use rlua::Lua;
+
+    use super::{Lsp, ServerConfig};
+    use crate::document::Document;
+    use crate::Atto;
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    // The mock server from tests/support, which cargo builds next to the test
+    // binary for the integration tests.
+    fn mock_server() -> PathBuf {
+        let exe = env::current_exe().unwrap();
+        let path = exe.parent().unwrap().parent().unwrap().join(format!("mock-lsp{}", env::consts::EXE_SUFFIX));
+        assert!(path.exists(), "{} is missing, build it with `cargo build --bin mock-lsp`", path.display());
+        path
+    }
+
+    // An editor on a Rust file holding `text`, once the mock server, started
+    // with `args`, has it open.
+    fn editor(text: &str, args: &[&str]) -> Atto {
+        let dir = env::temp_dir().join(format!("atto-lsp-{}-{}", process::id(), FILES.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.rs");
+        fs::write(&path, text).unwrap();
+        let document = Document::open(&path.display().to_string()).unwrap();
+        // The buffer is all the tests need from here on.
+        fs::remove_dir_all(&dir).unwrap();
+        let mut atto = Atto::new(vec![document], "atto", false, "vim", Lua::new());
+        let config = ServerConfig {
+            command: mock_server().display().to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        atto.lsp = Lsp::new(HashMap::from([("rust".to_string(), config)]));
+        wait(&mut atto, |atto| atto.doc().changes.is_some());
+        atto
+    }
+
+    // Handles what the server sends until `done`.
+    fn wait(atto: &mut Atto, done: impl Fn(&Atto) -> bool) {
+        let start = Instant::now();
+        while !done(atto) {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out waiting for the language server");
+            atto.lsp_update();
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // The server's copy of the document, which the mock answers hover with.
+    fn server_text(atto: &mut Atto) -> String {
+        atto.lsp_update();
+        atto.lsp.hover = None;
+        atto.lsp_hover();
+        wait(atto, |atto| atto.lsp.hover.is_some());
+        atto.lsp.hover.take().unwrap()
+    }
+
+    fn text(atto: &Atto) -> String {
+        atto.doc().buffer.text()
+    }
+
+    #[test]
+    fn initialize_and_open() {
+        let mut atto = editor("fn main() {}\n", &[]);
+        let client = &atto.lsp.clients[0];
+        assert!(client.initialized);
+        assert_eq!(client.sync, 2);
+        assert_eq!(server_text(&mut atto), "fn main() {}");
+    }
+
+    #[test]
+    fn incremental_sync() {
+        let mut atto = editor("fn main() {\n    let x = 1;\n}\n", &[]);
+        atto.insert_text(16, "é🦀 ");
+        atto.insert_text(0, "// top\n");
+        let end = atto.doc().buffer.len_chars();
+        atto.insert_text(end, "fn more() {}\n");
+        // Joins the first two lines.
+        atto.remove_text(6..8);
+        assert_eq!(server_text(&mut atto), text(&atto).trim());
+        // An edit after the astral char counts it as two UTF-16 units.
+        let pos = text(&atto).chars().position(|c| c == '🦀').unwrap() + 1;
+        atto.remove_text(pos..pos + 1);
+        atto.undo();
+        atto.undo();
+        assert_eq!(server_text(&mut atto), text(&atto).trim());
+    }
+
+    #[test]
+    fn full_sync() {
+        let mut atto = editor("fn main() {}\n", &["--sync", "1"]);
+        atto.insert_text(11, " let x = 1; ");
+        assert_eq!(server_text(&mut atto), "fn main() { let x = 1; }");
+    }
+
+    #[test]
+    fn publish_diagnostics() {
+        let mut atto = editor("fn main() {\n    let bad = 1;\n}\n", &[]);
+        wait(&mut atto, |atto| !atto.doc().diagnostics.is_empty());
+        assert_eq!(atto.doc().diagnostics[0].range, 20..23);
+        assert_eq!(atto.doc().diagnostics[0].severity, 1);
+        atto.set_cursor((1, 0));
+        assert_eq!(atto.diagnostic_at_cursor(), Some("bad word"));
+        atto.remove_text(20..23);
+        atto.insert_text(20, "good");
+        wait(&mut atto, |atto| atto.doc().diagnostics.is_empty());
+    }
+
+    #[test]
+    fn no_sync() {
+        let mut atto = editor("fn main() {}\n", &["--sync", "0"]);
+        atto.insert_text(11, " let x = 1; ");
+        atto.lsp_update();
+        assert!(atto.doc().changes.as_ref().is_some_and(Vec::is_empty));
+        assert_eq!(server_text(&mut atto), "fn main() {}");
+    }
+
+    #[test]
+    fn diagnostics_follow_edits() {
+        let mut atto = editor("fn main() {\n    let bad = 1;\n}\n", &[]);
+        wait(&mut atto, |atto| !atto.doc().diagnostics.is_empty());
+        atto.insert_text(0, "// x\n");
+        assert_eq!(atto.doc().diagnostics[0].range, 25..28);
+        // Typed right after it.
+        atto.insert_text(28, "ly");
+        assert_eq!(atto.doc().diagnostics[0].range, 25..28);
+        atto.remove_text(24..26);
+        assert_eq!(atto.doc().diagnostics[0].range, 24..26);
+        atto.undo();
+        assert_eq!(atto.doc().diagnostics[0].range, 26..28);
+    }
+
+    #[test]
+    fn hover() {
+        let mut atto = editor("fn main() {}\n", &[]);
+        atto.lsp_hover();
+        wait(&mut atto, |atto| atto.lsp.hover.is_some());
+        assert_eq!(atto.lsp.hover.as_deref(), Some("fn main() {}"));
+    }
+
+    #[test]
+    fn definition() {
+        let mut atto = editor("fn foo() {}\nfn main() { foo(); }\n", &[]);
+        atto.set_cursor((1, 13));
+        atto.goto_definition();
+        wait(&mut atto, |atto| (atto.cursor_y, atto.cursor_x) == (0, 3));
+    }
+
+    #[test]
+    fn references() {
+        let mut atto = editor("fn foo() {}\nfn main() { foo(); }\n", &[]);
+        atto.set_cursor((0, 4));
+        atto.find_references();
+        wait(&mut atto, |atto| atto.quickfix.is_some());
+        atto.quickfix_jump(1);
+        assert_eq!((atto.cursor_y, atto.cursor_x), (1, 12));
+        assert_eq!(atto.status_message.as_deref(), Some("(2 of 2)"));
+    }
+
+    #[test]
+    fn rename() {
+        let mut atto = editor("fn foo() {}\nfn main() { foo(); }\n", &[]);
+        atto.set_cursor((1, 12));
+        atto.rename_symbol("renamed");
+        wait(&mut atto, |atto| text(atto).contains("renamed"));
+        assert_eq!(text(&atto), "fn renamed() {}\nfn main() { renamed(); }");
+        assert_eq!(server_text(&mut atto), text(&atto).trim());
+        // One undo step for the whole rename.
+        atto.undo();
+        assert_eq!(text(&atto), "fn foo() {}\nfn main() { foo(); }");
+    }
+
+    #[test]
+    fn formatting() {
+        let mut atto = editor("fn main() {  \n    let x = 1;\t\n}\n", &[]);
+        atto.set_cursor((1, 4));
+        atto.format_document();
+        wait(&mut atto, |atto| atto.doc().modified());
+        assert_eq!(text(&atto), "fn main() {\n    let x = 1;\n}");
+        assert_eq!((atto.cursor_y, atto.cursor_x), (1, 4));
+    }
+}
@@ -1,152 +1,421 @@
+mod buffer;
+mod clipboard;
+mod completion;
+mod document;
+mod encoding;
+mod explorer;
+mod git;
+mod grep;
+mod gutter;
+mod highlight;
+mod history;
+mod kakoune;
+mod keymap;
+mod lsp;
+mod motion;
+mod picker;
+mod plugin;
+mod register;
+mod search;
+mod selection;
+mod swap;
+mod unicode;
+mod vim;
+mod watch;
+mod window;
+mod wrap;
+
+use std::collections::HashMap;
 use std::fs;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, MouseEventKind},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     cursor::{MoveTo, Show},
 };
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use tui::{
     backend::{Backend, CrosstermBackend},
-    style::{Color, Style},
+    layout::{Direction, Rect},
+    style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, Paragraph},
     Terminal,
 };
-use rlua::{Lua, RluaCompat, Table};
+use rlua::{Lua, Table, Value};
 use std::path::Path;
-use std::process::Command;
+use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
 use crossterm::event::{KeyEvent, KeyModifiers};
-
+use document::Document;
+use history::Edit;
+use std::ops::Range;
+use window::{FocusDirection, Layout, Window};
+use keymap::{Binding, Key, Keymap, Lookup};
+use clipboard::Clipboard;
+use register::Registers;
+use search::{Incremental, Search, Substitute};
+use selection::Selection;
+
+#[derive(Clone, Copy, PartialEq)]
 enum Mode {
     Normal,
     Insert,
+    Visual(Visual),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Visual {
+    Char,
+    Line,
+    Block,
+}
 
-struct KeyBindings {
-    save: (KeyCode, KeyModifiers),
-    quit: (KeyCode, KeyModifiers),
-    move_up: (KeyCode, KeyModifiers),
-    move_down: (KeyCode, KeyModifiers),
-    move_left: (KeyCode, KeyModifiers),
-    move_right: (KeyCode, KeyModifiers),
+enum Prompt {
+    Command,
+    Select,
+    // Forward or backward.
+    Search(bool),
+    // File operations in the explorer.
+    NewFile,
+    Rename,
+    Delete,
+    RenameSymbol,
+    // The file whose swap file was found.
+    Recover(String),
+    // Whether the file changed on disk, rather than reloading by hand.
+    Reload(String, bool),
+    Quit,
 }
 
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::Command => ":",
+            Prompt::Select => "select: ",
+            Prompt::Search(true) => "/",
+            Prompt::Search(false) => "?",
+            Prompt::NewFile => "new file: ",
+            Prompt::Rename => "rename to: ",
+            Prompt::Delete => "delete? (y/n) ",
+            Prompt::RenameSymbol => "rename symbol: ",
+            Prompt::Recover(_) => "recover swap file? (r/d/v) ",
+            Prompt::Reload(_, true) => "changed on disk, reload? (r/k/v) ",
+            Prompt::Reload(_, false) => "unsaved changes, reload? (r/k/v) ",
+            Prompt::Quit => "save changes? (y/n/c) ",
+        }
+    }
+}
+
+
 struct Atto {
     cursor_x: usize,
     cursor_y: usize,
-    cursor_offset_x: u16,
     cursor_offset_y: u16,
-    buffer: Vec<String>,
-    terminal_height: usize,
-    terminal_width: usize,
-    filename: Option<String>,
-    show_binds: bool,
+    view_height: usize,
+    view_width: usize,
+    documents: Vec<Document>,
+    current: usize,
     scroll_offset: usize,
     horizontal_scroll_offset: usize,
-    key_bindings: KeyBindings,
-    command_mode: bool,
+    wrap: wrap::Wrap,
+    gutter: gutter::Gutter,
+    keymap: Keymap,
+    pending_keys: Vec<Key>,
+    normal_keys: String,
+    registers: Registers,
+    repeat: Option<vim::Repeat>,
+    insert_start: Option<usize>,
+    prompt: Option<Prompt>,
     command_input: String,
-    vim_mode: bool,
+    search: Option<Search>,
+    incremental: Option<Incremental>,
+    substitution: Option<Substitute>,
+    quickfix: Option<grep::Quickfix>,
+    picker: Option<picker::Picker>,
+    explorer: Option<explorer::Explorer>,
+    lsp: lsp::Lsp,
+    swaps: swap::Swaps,
+    watch: watch::Watch,
+    completion: Option<completion::Completion>,
+    snippet: Option<completion::Snippet>,
     command_style: String,
     mode: Mode,
     selection_start: Option<(usize, usize)>,
+    selections: Vec<Selection>,
+    primary: usize,
+    status_message: Option<String>,
+    windows: Vec<Window>,
+    focus: usize,
+    layout: Layout,
+    lua: Option<Lua>,
+    changed: bool,
+    quit: bool,
 }
 
 impl Atto {
-    fn new(filename: Option<String>, preset: &str, vim_mode: bool, command_style: &str) -> Self {
-        let (width, height) = crossterm::terminal::size().unwrap();
-        let key_bindings = match preset {
-            "atto" => KeyBindings {
-                save: (KeyCode::Char('w'), KeyModifiers::CONTROL),
-                quit: (KeyCode::Char('q'), KeyModifiers::CONTROL),
-                move_up: (KeyCode::Up, KeyModifiers::NONE),
-                move_down: (KeyCode::Down, KeyModifiers::NONE),
-                move_left: (KeyCode::Left, KeyModifiers::NONE),
-                move_right: (KeyCode::Right, KeyModifiers::NONE),
-            },
-            "nano" => KeyBindings {
-                save: (KeyCode::Char('o'), KeyModifiers::CONTROL),
-                quit: (KeyCode::Char('x'), KeyModifiers::CONTROL),
-                move_up: (KeyCode::Up, KeyModifiers::NONE),
-                move_down: (KeyCode::Down, KeyModifiers::NONE),
-                move_left: (KeyCode::Left, KeyModifiers::NONE),
-                move_right: (KeyCode::Right, KeyModifiers::NONE),
-            },
-            "micro" => KeyBindings {
-                save: (KeyCode::Char('s'), KeyModifiers::CONTROL),
-                quit: (KeyCode::Char('q'), KeyModifiers::CONTROL),
-                move_up: (KeyCode::Up, KeyModifiers::NONE),
-                move_down: (KeyCode::Down, KeyModifiers::NONE),
-                move_left: (KeyCode::Left, KeyModifiers::NONE),
-                move_right: (KeyCode::Right, KeyModifiers::NONE),
-            },
-            "emacs" => KeyBindings {
-                save: (KeyCode::Char('x'), KeyModifiers::CONTROL),
-                quit: (KeyCode::Char('c'), KeyModifiers::CONTROL),
-                move_up: (KeyCode::Char('p'), KeyModifiers::CONTROL),
-                move_down: (KeyCode::Char('n'), KeyModifiers::CONTROL),
-                move_left: (KeyCode::Char('b'), KeyModifiers::CONTROL),
-                move_right: (KeyCode::Char('f'), KeyModifiers::CONTROL),
-            },
-            _ => KeyBindings {
-                save: (KeyCode::Char('t'), KeyModifiers::CONTROL),
-                quit: (KeyCode::Char('w'), KeyModifiers::CONTROL),
-                move_up: (KeyCode::Char('k'), KeyModifiers::CONTROL),
-                move_down: (KeyCode::Char('j'), KeyModifiers::CONTROL),
-                move_left: (KeyCode::Char('h'), KeyModifiers::CONTROL),
-                move_right: (KeyCode::Char('l'), KeyModifiers::CONTROL),
-            }
-        };
+    fn new(documents: Vec<Document>, preset: &str, vim_mode: bool, command_style: &str, lua: Lua) -> Self {
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
         Self {
             cursor_y: 0,
             cursor_x: 0,
-            cursor_offset_x: 5,
             cursor_offset_y: 1,
-            buffer: vec![String::new()],
-            terminal_height: height as usize,
-            terminal_width: width as usize,
-            filename,
-            show_binds: false,
+            view_height: (height as usize).saturating_sub(2),
+            view_width: width as usize,
+            documents,
+            current: 0,
             scroll_offset: 0,
             horizontal_scroll_offset: 0,
-            key_bindings,
+            wrap: wrap::Wrap::default(),
+            gutter: gutter::Gutter::default(),
+            keymap: Keymap::preset(preset, vim_mode, command_style),
+            pending_keys: Vec::new(),
+            normal_keys: String::new(),
+            registers: Registers::default(),
+            repeat: None,
+            insert_start: None,
             command_input: String::new(),
-            command_mode: false,
-            vim_mode,
+            prompt: None,
+            search: None,
+            incremental: None,
+            substitution: None,
+            quickfix: None,
+            picker: None,
+            explorer: None,
+            lsp: lsp::Lsp::default(),
+            swaps: swap::Swaps::default(),
+            watch: watch::Watch::default(),
+            completion: None,
+            snippet: None,
             command_style: command_style.to_string(),
-            mode: Mode::Normal,
+            mode: if vim_mode { Mode::Normal } else { Mode::Insert },
             selection_start: None,
+            selections: Vec::new(),
+            primary: 0,
+            status_message: None,
+            windows: vec![Window::new(0)],
+            focus: 0,
+            layout: Layout::Window(0),
+            lua: Some(lua),
+            changed: false,
+            quit: false,
         }
     }
 
-    fn read_file(&mut self) -> io::Result<()> {
-        if let Some(ref filename) = self.filename {
-            let mut file = File::open(filename)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            self.buffer = if contents.is_empty() {
-                vec![String::new()]
-            } else {
-                contents.lines().map(|line| line.to_string()).collect()
-            };
-            self.cursor_x = 0;
-            self.cursor_y = 0;
+    fn doc(&self) -> &Document {
+        &self.documents[self.current]
+    }
+
+    fn doc_mut(&mut self) -> &mut Document {
+        &mut self.documents[self.current]
+    }
+
+    // Saves the current document; false, with the error in the status bar,
+    // if that failed. A file changed on disk since it was read is only
+    // overwritten with `force`.
+    fn write_file(&mut self, force: bool) -> bool {
+        let name = self.doc().display_name();
+        let doc = self.doc();
+        let current = doc.filename.as_deref().and_then(watch::stamp);
+        if !force && current.is_some() && (doc.stale || current != doc.disk) {
+            self.status_message = Some(format!("{} changed on disk since it was read (add ! to overwrite)", name));
+            return false;
         }
-        Ok(())
+        if let Err(e) = self.doc_mut().write() {
+            self.status_message = Some(format!("Error writing {}: {}", name, e));
+            return false;
+        }
+        self.remove_swap(self.current);
+        self.lsp_saved();
+        self.fire("on_save", name);
+        true
     }
 
-    fn write_file(&self) -> io::Result<()> {
-        if let Some(ref filename) = self.filename {
-            let mut file = OpenOptions::new().write(true).truncate(true).open(filename)?;
-            for line in &self.buffer {
-                writeln!(file, "{}", line)?;
+    // Quits unless a buffer has unsaved changes, which `force` throws away.
+    fn quit(&mut self, force: bool) {
+        match self.documents.iter().find(|d| d.modified()) {
+            Some(document) if !force => {
+                self.status_message = Some(format!("{} has unsaved changes (add ! to quit anyway)", document.display_name()));
             }
+            _ => self.quit = true,
         }
-        Ok(())
+    }
+
+    // The answer to the prompt the quit key shows when there are unsaved
+    // changes: save them all, throw them away or keep editing.
+    fn answer_quit(&mut self, answer: &str) {
+        match answer.trim() {
+            "y" | "yes" => {
+                let modified: Vec<usize> = (0..self.documents.len()).filter(|&i| self.documents[i].modified()).collect();
+                for index in modified {
+                    self.switch_buffer(index);
+                    if !self.write_file(false) {
+                        return;
+                    }
+                }
+                self.quit = true;
+            }
+            "n" | "no" => self.quit = true,
+            _ => {}
+        }
+    }
+
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.current || index >= self.documents.len() {
+            return;
+        }
+        let (cursor, scroll_offset) = ((self.cursor_y, self.cursor_x), self.scroll_offset);
+        let doc = self.doc_mut();
+        doc.cursor = cursor;
+        doc.scroll_offset = scroll_offset;
+        self.current = index;
+        self.selections.clear();
+        self.clear_selection();
+        self.scroll_offset = self.doc().scroll_offset;
+        self.horizontal_scroll_offset = 0;
+        self.set_cursor(self.doc().cursor);
+    }
+
+    fn next_buffer(&mut self) {
+        self.switch_buffer((self.current + 1) % self.documents.len());
+    }
+
+    fn prev_buffer(&mut self) {
+        self.switch_buffer((self.current + self.documents.len() - 1) % self.documents.len());
+    }
+
+    fn edit_file(&mut self, path: &str) {
+        if path.is_empty() {
+            self.status_message = Some("No file name".to_string());
+            return;
+        }
+        if let Some(index) = self.documents.iter().position(|d| d.filename.as_deref() == Some(path)) {
+            self.switch_buffer(index);
+            return;
+        }
+        match Document::open(path) {
+            Ok(document) => {
+                self.documents.push(document);
+                self.switch_buffer(self.documents.len() - 1);
+                self.check_swap(self.current);
+                self.fire("on_open", path.to_string());
+            }
+            Err(e) => self.status_message = Some(format!("Error opening {}: {}", path, e)),
+        }
+    }
+
+    fn select_buffer(&mut self, arg: &str) {
+        let index = match arg.parse::<usize>() {
+            Ok(n) => n.checked_sub(1),
+            Err(_) => self.documents.iter().position(|d| d.display_name() == arg),
+        };
+        match index.filter(|&i| i < self.documents.len()) {
+            Some(index) => self.switch_buffer(index),
+            None => self.status_message = Some(format!("No such buffer: {}", arg)),
+        }
+    }
+
+    fn list_buffers(&mut self) {
+        let list = self.documents.iter().enumerate().map(|(i, d)| {
+            let current = if i == self.current { "%" } else { "" };
+            let modified = if d.modified() { " [+]" } else { "" };
+            format!("{}{}: {}{}", i + 1, current, d.display_name(), modified)
+        }).collect::<Vec<_>>();
+        self.status_message = Some(list.join("  "));
+    }
+
+    fn window_view(&self, index: usize) -> Window {
+        let window = self.windows[index];
+        if index != self.focus {
+            return window;
+        }
+        Window {
+            document: self.current,
+            cursor: (self.cursor_y, self.cursor_x),
+            scroll_offset: self.scroll_offset,
+            horizontal_scroll_offset: self.horizontal_scroll_offset,
+            ..window
+        }
+    }
+
+    fn store_view(&mut self) {
+        self.windows[self.focus] = self.window_view(self.focus);
+        let (cursor, scroll_offset) = ((self.cursor_y, self.cursor_x), self.scroll_offset);
+        let doc = self.doc_mut();
+        doc.cursor = cursor;
+        doc.scroll_offset = scroll_offset;
+    }
+
+    fn load_view(&mut self) {
+        let window = self.windows[self.focus];
+        self.selections.clear();
+        self.clear_selection();
+        self.current = window.document;
+        self.scroll_offset = window.scroll_offset;
+        self.horizontal_scroll_offset = window.horizontal_scroll_offset;
+        self.set_cursor(window.cursor);
+    }
+
+    fn focus_window(&mut self, index: usize) {
+        if index != self.focus && index < self.windows.len() {
+            self.store_view();
+            self.focus = index;
+            self.load_view();
+        }
+    }
+
+    fn next_window(&mut self) {
+        self.focus_window((self.focus + 1) % self.windows.len());
+    }
+
+    fn focus_direction(&mut self, direction: FocusDirection) {
+        if let Some(index) = window::neighbour(&self.windows, self.focus, direction) {
+            self.focus_window(index);
+        }
+    }
+
+    fn split_window(&mut self, direction: Direction) {
+        self.store_view();
+        let index = self.windows.len();
+        self.windows.push(self.windows[self.focus]);
+        self.layout.split(self.focus, index, direction);
+        self.focus = index;
+    }
+
+    fn close_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.status_message = Some("Cannot close the last window".to_string());
+            return;
+        }
+        self.layout.remove(self.focus);
+        self.windows.remove(self.focus);
+        self.focus = self.focus.min(self.windows.len() - 1);
+        self.load_view();
+    }
+
+    fn arrange_windows(&mut self, size: Rect) {
+        let panel = self.quickfix.as_ref().map_or(0, |quickfix| quickfix.height(size.height));
+        let height = size.height.saturating_sub(1 + panel);
+        let sidebar = match self.explorer.as_mut() {
+            Some(explorer) => {
+                let width = explorer.width(size.width);
+                explorer.area = Rect::new(0, 0, width, height);
+                width
+            }
+            None => 0,
+        };
+        let area = Rect::new(sidebar, 0, size.width - sidebar, height);
+        let mut arranged = Vec::new();
+        self.layout.arrange(area, false, &mut arranged);
+        for (index, area, separator) in arranged {
+            self.windows[index].area = area;
+            self.windows[index].separator = separator;
+        }
+        let window = self.windows[self.focus];
+        self.view_height = window.area.height.saturating_sub(self.cursor_offset_y) as usize;
+        self.view_width = window.area.width.saturating_sub(window.separator as u16) as usize;
     }
 
     fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
@@ -154,55 +423,54 @@ impl Atto {
         execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture, Show)?;
 
         loop {
+            self.lsp_update();
+            self.grep_update();
+            self.write_swaps(false);
+            self.check_files();
+            self.offer_recovery();
+            self.offer_reload();
+            self.update_git_signs();
+            self.fire_change();
+            self.arrange_windows(terminal.size()?);
+            self.scroll_to_cursor_row();
+            for document in self.documents.iter_mut() {
+                document.update_syntax();
+            }
             terminal.draw(|f| self.render(f))?;
 
-            execute!(io::stdout(), MoveTo(self.cursor_x as u16 + self.cursor_offset_x, self.cursor_y as u16 - self.scroll_offset as u16 + self.cursor_offset_y), Show)?;
-
-            if let Event::Key(key) = event::read()? {
-                if self.vim_mode == true {
-                    match self.mode {
-                        Mode::Normal => self.handle_normal_mode(key),
-                        Mode::Insert => self.handle_insert_mode(key),
-                    }
-                    match (key.code, key.modifiers) {
-
-                        (KeyCode::Esc, _) => {
-                            self.toggle_command_mode();
+            if let Some((x, y)) = self.picker_cursor(terminal.size()?) {
+                execute!(io::stdout(), MoveTo(x, y), Show)?;
+            } else {
+                let (x, y) = self.cursor_screen_position();
+                execute!(io::stdout(), MoveTo(x, y), Show)?;
+            }
 
-                        },
-                        (KeyCode::Char(v), _) => {
-                            self.handle_command_input(v);
-                        },
-                        _ => {}
-                    }
-                } else {
-                    match (key.code, key.modifiers) {
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.quit => break,
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.save => self.write_file()?,
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.move_up => self.move_up(),
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.move_down => self.move_down(),
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.move_left => self.move_left(),
-                        (code, modifiers) if (code, modifiers) == self.key_bindings.move_right => self.move_right(),
-                        (KeyCode::Up, _) => self.move_up(),
-                        (KeyCode::Down, _) => self.move_down(),
-                        (KeyCode::Left, _) => self.move_left(),
-                        (KeyCode::Right, _) => self.move_right(),
-                        (KeyCode::Char('r'), KeyModifiers::CONTROL) => self.read_file()?,
-                        (KeyCode::Tab, _) => self.input_tab(),
-                        (KeyCode::PageUp, _) => self.page_up(),
-                        (KeyCode::PageDown, _) => self.page_down(),
-                        (KeyCode::Backspace, _) => self.backspace(),
-                        (KeyCode::Enter, _) => self.new_line(),
-                        (KeyCode::Char(v), _) => self.input_char(v),
-                        _ => {}
+            // Language servers and `:grep` answer in the background, so with
+            // any running the screen is redrawn every so often to show what
+            // they sent, and edits get their git signs and go to the swap files
+            // when typing pauses, and open files are checked for changes on
+            // disk.
+            let searching = self.quickfix.as_ref().is_some_and(grep::Quickfix::searching);
+            let background = (self.lsp.running() || searching).then(|| Duration::from_millis(50));
+            let timeouts = [background, self.swap_timeout(), self.git_timeout(), Some(self.check_timeout())];
+            let timeout = timeouts.into_iter().flatten().min().unwrap();
+            if !event::poll(timeout)? {
+                continue;
+            }
+            match event::read()? {
+                Event::Key(key) => {
+                    self.status_message = None;
+                    self.handle_key(key)?;
+                    self.update_completion(key);
+                    if self.quit {
+                        break;
                     }
                 }
-            } else if let Event::Mouse(mouse_event) = event::read()? {
-                match mouse_event.kind {
-                    MouseEventKind::ScrollUp => self.scroll_up(),
-                    MouseEventKind::ScrollDown => self.scroll_down(),
-                    _ => {}
-                }
+                Event::Mouse(mouse_event) => self.handle_mouse(mouse_event),
+                _ => {}
+            }
+            if let Some(error) = self.registers.take_error() {
+                self.status_message = Some(error);
             }
         }
 
@@ -211,125 +479,425 @@ impl Atto {
         Ok(())
     }
 
-    fn handle_normal_mode(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Char('i') => self.mode = Mode::Insert,
-            KeyCode::Char(':') => self.command_mode = true,
-            KeyCode::Backspace => self.command_mode = self.command_input.pop().is_some(),
-            KeyCode::Enter => if self.command_mode {
-                self.execute_command();
-            }
-            KeyCode::Up => self.move_up(),
-            KeyCode::Down => self.move_down(),
-            KeyCode::Left => self.move_left(),
-            KeyCode::Right => self.move_right(),
-            KeyCode::Char('h') => self.move_left(),
-            KeyCode::Char('j') => self.move_down(),
-            KeyCode::Char('k') => self.move_up(),
-            KeyCode::Char('l') => self.move_right(),
-            _ => {}
+    fn cursor_screen_position(&self) -> (u16, u16) {
+        if self.wrap.enabled {
+            return self.wrapped_cursor_position();
         }
+        let area = self.windows[self.focus].area;
+        let x = area.x + (self.display_x() - self.horizontal_scroll_offset) as u16 + self.gutter_width();
+        let y = area.y + (self.cursor_y - self.scroll_offset) as u16 + self.cursor_offset_y;
+        (x, y)
     }
 
-    fn handle_insert_mode(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Esc => self.mode = Mode::Normal,
-            KeyCode::Enter => self.new_line(),
-            KeyCode::Backspace => self.backspace(),
-            KeyCode::Char(c) => self.input_char(c),
-            _ => {}
+    fn mode_name(&self) -> &'static str {
+        match self.mode {
+            Mode::Normal => "normal",
+            Mode::Insert => "insert",
+            Mode::Visual(_) => "visual",
         }
     }
 
+    fn handle_key(&mut self, key: KeyEvent) -> io::Result<()> {
+        let key_name = keymap::key_name(keymap::key_of(key));
+        if !key_name.is_empty() && self.fire("on_key", key_name) {
+            return Ok(());
+        }
+        self.lsp.hover = None;
+        if self.substitution.is_some() {
+            self.confirm_key(key);
+            return Ok(());
+        }
+        if self.picker.is_some() {
+            self.picker_key(key);
+            return Ok(());
+        }
+        if self.prompt.is_some() {
+            self.handle_command_line(key);
+            return Ok(());
+        }
+        if self.quickfix.as_ref().is_some_and(|quickfix| quickfix.open && quickfix.focused) {
+            self.quickfix_key(key);
+            return Ok(());
+        }
+        if self.explorer.as_ref().is_some_and(|explorer| explorer.open && explorer.focused) {
+            self.explorer_key(key);
+            return Ok(());
+        }
+        // A count goes on to a key the keymap binds, as in `3u`.
+        if !self.normal_keys.is_empty() && !self.typing_count() {
+            self.normal_key(keymap::key_of(key));
+            return Ok(());
+        }
+        if let (Mode::Insert, true) = (&self.mode, self.selections.len() > 1) {
+            self.kakoune_insert_key(key);
+            return Ok(());
+        }
+        if self.pending_keys.is_empty() && self.completion_key(key) {
+            return Ok(());
+        }
+        self.pending_keys.push(keymap::key_of(key));
+        match self.keymap.lookup(self.mode_name(), &self.pending_keys) {
+            Lookup::Pending => {}
+            Lookup::Found(binding) => {
+                self.pending_keys.clear();
+                let keeps_selection = matches!(&binding, Binding::Action(action) if action.starts_with("select_") || action.starts_with("search_") || action == "copy" || action == "tab");
+                for _ in 0..self.binding_count(&binding) {
+                    self.run_binding(binding.clone())?;
+                }
+                if self.mode == Mode::Insert && !keeps_selection {
+                    self.selection_start = None;
+                }
+                // Anything that moved the cursor behind the selections' back
+                // leaves just the cursor selected.
+                if self.selections.get(self.primary).is_some_and(|s| s.head != self.cursor_char()) {
+                    self.selections.clear();
+                }
+            }
+            Lookup::Unbound => {
+                let pending = std::mem::take(&mut self.pending_keys);
+                if let Mode::Normal | Mode::Visual(_) = self.mode {
+                    for key in pending {
+                        self.normal_key(key);
+                    }
+                    return Ok(());
+                } else if pending.len() > 1 {
+                    self.status_message = Some(format!("{} is not bound", keymap::keys_name(&pending)));
+                } else if let (Mode::Insert, KeyCode::Char(c)) = (&self.mode, key.code) {
+                    if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                        self.delete_selection();
+                        self.input_char(c);
+                    }
+                }
+            }
+        }
+        if let (Mode::Normal | Mode::Visual(_), false) = (self.mode, self.kakoune()) {
+            self.clamp_to_line();
+        }
+        Ok(())
+    }
 
+    fn kakoune(&self) -> bool {
+        self.command_style == "kakoune"
+    }
 
+    // Keys not bound in normal mode go to the editing grammar of the
+    // command style.
+    fn normal_key(&mut self, key: Key) {
+        if self.kakoune() {
+            self.kakoune_key(key);
+        } else if let Mode::Visual(_) = self.mode {
+            self.visual_key(key);
+            self.clamp_to_line();
+        } else {
+            self.vim_key(key);
+            self.clamp_to_line();
+        }
+    }
 
-    fn toggle_command_mode(&mut self) {
-        self.command_mode = !self.command_mode;
-        if !self.command_mode {
-            self.command_input.clear();
+    fn handle_command_line(&mut self, key: KeyEvent) {
+        let search = match self.prompt {
+            Some(Prompt::Search(forward)) => Some(forward),
+            _ => None,
+        };
+        match key.code {
+            KeyCode::Backspace if self.command_input.is_empty() => self.close_prompt(),
+            KeyCode::Esc => self.close_prompt(),
+            KeyCode::Enter => match self.prompt.take() {
+                Some(Prompt::Select) => {
+                    let pattern = std::mem::take(&mut self.command_input);
+                    self.select_regex(&pattern);
+                }
+                Some(Prompt::Search(forward)) => self.finish_search(forward),
+                Some(Prompt::Recover(filename)) => {
+                    let answer = std::mem::take(&mut self.command_input);
+                    self.answer_recovery(filename, &answer);
+                }
+                Some(Prompt::Reload(filename, _)) => {
+                    let answer = std::mem::take(&mut self.command_input);
+                    self.answer_reload(filename, &answer);
+                }
+                Some(Prompt::Quit) => {
+                    let answer = std::mem::take(&mut self.command_input);
+                    self.answer_quit(&answer);
+                }
+                Some(Prompt::RenameSymbol) => {
+                    let name = std::mem::take(&mut self.command_input);
+                    self.rename_symbol(name.trim());
+                }
+                Some(prompt @ (Prompt::NewFile | Prompt::Rename | Prompt::Delete)) => {
+                    let input = std::mem::take(&mut self.command_input);
+                    match prompt {
+                        Prompt::NewFile => self.explorer_create(&input),
+                        Prompt::Rename => self.explorer_rename(&input),
+                        _ => self.explorer_delete(&input),
+                    }
+                }
+                _ => self.execute_command(),
+            },
+            KeyCode::Backspace => {
+                self.command_input.pop();
+            }
+            KeyCode::Char(c) => self.command_input.push(c),
+            _ => return,
+        }
+        if let (Some(forward), Some(_)) = (search, &self.prompt) {
+            self.update_search(forward);
         }
     }
 
-    fn handle_command_input(&mut self, c: char) {
-        if self.command_mode {
-            self.command_input.push(c);
+    fn close_prompt(&mut self) {
+        self.command_input.clear();
+        if let Some(Prompt::Search(_)) = self.prompt.take() {
+            self.cancel_search();
         }
     }
 
-    fn reset_terminal() {
-        let _ = Command::new("reset").status();
+    fn run_binding(&mut self, binding: Binding) -> io::Result<()> {
+        match binding {
+            Binding::Action(action) => {
+                if !self.run_action(&action)? {
+                    self.run_command(&action);
+                }
+            }
+            Binding::Plugin(mode, keys) => self.run_plugin_map(&mode, &keys),
+        }
+        Ok(())
+    }
+
+    // Runs a named editor action; false if there is no action called `name`.
+    fn run_action(&mut self, name: &str) -> io::Result<bool> {
+        match name {
+            "save" => {
+                self.write_file(false);
+            }
+            "quit" if self.documents.iter().any(|d| d.modified()) => {
+                self.prompt = Some(Prompt::Quit);
+                self.command_input.clear();
+            }
+            "quit" => self.quit = true,
+            "reload" => self.reload_file(),
+            "undo" => self.undo(),
+            "redo" => self.redo(),
+            "move_up" => self.move_up(),
+            "move_down" => self.move_down(),
+            "toggle_wrap" => self.toggle_wrap(),
+            "toggle_bookmark" => self.toggle_bookmark(),
+            "next_bookmark" => self.jump_bookmark(true),
+            "prev_bookmark" => self.jump_bookmark(false),
+            "move_left" => self.move_left(),
+            "move_right" => self.move_right(),
+            "page_up" => self.page_up(),
+            "page_down" => self.page_down(),
+            "scroll_up" => self.scroll_up(),
+            "scroll_down" => self.scroll_down(),
+            "tab" if self.next_snippet_stop() => {}
+            "tab" => {
+                self.delete_selection();
+                self.input_tab();
+            }
+            "backspace" => {
+                if !self.delete_selection() {
+                    self.backspace();
+                }
+            }
+            "newline" => {
+                self.delete_selection();
+                self.new_line();
+            }
+            "command_line" => {
+                self.prompt = Some(Prompt::Command);
+                self.command_input.clear();
+            }
+            "normal_mode" if self.kakoune() => self.kakoune_leave_insert(),
+            "normal_mode" if matches!(self.mode, Mode::Visual(_)) => self.clear_selection(),
+            "normal_mode" => self.leave_insert(),
+            "insert_mode" => self.mode = Mode::Insert,
+            "visual" => self.toggle_visual(Visual::Char),
+            "visual_line" => self.toggle_visual(Visual::Line),
+            "visual_block" => self.toggle_visual(Visual::Block),
+            "select_up" => self.select_with(Atto::move_up),
+            "select_down" => self.select_with(Atto::move_down),
+            "select_left" => self.select_with(Atto::move_left),
+            "select_right" => self.select_with(Atto::move_right),
+            "copy" => self.copy(false),
+            "cut" => self.copy(true),
+            "paste" => self.paste(),
+            "search_forward" => self.start_search(true),
+            "search_backward" => self.start_search(false),
+            "search_next" => self.search_next(false),
+            "search_prev" => self.search_next(true),
+            "quickfix_next" => self.quickfix_jump(1),
+            "quickfix_prev" => self.quickfix_jump(-1),
+            "quickfix_open" => self.quickfix_show(true),
+            "quickfix_close" => self.quickfix_show(false),
+            "find_file" => self.open_picker(""),
+            "toggle_explorer" => self.toggle_explorer(),
+            "hover" => self.lsp_hover(),
+            "goto_definition" => self.goto_definition(),
+            "find_references" => self.find_references(),
+            "rename_symbol" => {
+                let buffer = self.doc().buffer.as_ref();
+                let word = motion::word_object(buffer, self.cursor_char(), false).map(|(start, end)| buffer.slice(start..end));
+                self.prompt = Some(Prompt::RenameSymbol);
+                self.command_input = word.unwrap_or_default();
+            }
+            "format" => self.format_document(),
+            "complete" => self.open_completion(true),
+            "clear_search" => {
+                if let Some(search) = self.search.as_mut() {
+                    search.highlight = false;
+                }
+            }
+            "split" => self.split_window(Direction::Vertical),
+            "vsplit" => self.split_window(Direction::Horizontal),
+            "close_window" => self.close_window(),
+            "next_window" => self.next_window(),
+            "focus_left" => self.focus_direction(FocusDirection::Left),
+            "focus_down" => self.focus_direction(FocusDirection::Down),
+            "focus_up" => self.focus_direction(FocusDirection::Up),
+            "focus_right" => self.focus_direction(FocusDirection::Right),
+            "next_buffer" => self.next_buffer(),
+            "prev_buffer" => self.prev_buffer(),
+            "list_buffers" => self.list_buffers(),
+            _ => return Ok(false),
+        }
+        Ok(true)
     }
 
 
     fn execute_command(&mut self) {
-        if self.command_style == "kakoune" {
-            match self.command_input.trim() {
-                ":quit" => {
-                    Atto::reset_terminal();
-                    std::process::exit(0);
+        let input = self.command_input.trim().to_string();
+        let (command, arg) = match input.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (input.as_str(), ""),
+        };
+        if let Some(substitute) = search::parse_substitute(&input) {
+            self.substitute(substitute);
+        } else if self.command_style == "kakoune" {
+            match command {
+                "quit" => self.quit(false),
+                "quit!" => self.quit(true),
+                "write" => {
+                    self.write_file(false);
                 }
-                ":write" => {
-                    if let Err(e) = self.write_file() {
-                        eprintln!("Error writing file: {}", e);
-                    }
+                "write!" => {
+                    self.write_file(true);
                 }
-                ":write-quit" => {
-                    if let Err(e) = self.write_file() {
-                        eprintln!("Error writing file: {}", e);
+                "write-quit" => {
+                    if self.write_file(false) {
+                        self.quit(false);
                     }
-                    Atto::reset_terminal();
-                    std::process::exit(0);
                 }
-                _ => {}
+                "edit" => self.edit_file(arg),
+                "buffer" => self.select_buffer(arg),
+                "buffer-next" => self.next_buffer(),
+                "buffer-previous" => self.prev_buffer(),
+                "buffers" => self.list_buffers(),
+                "split" => self.split_window(Direction::Vertical),
+                "vsplit" => self.split_window(Direction::Horizontal),
+                "close" => self.close_window(),
+                "window-next" => self.next_window(),
+                "grep" => self.grep_command(arg),
+                "grep-next-match" => self.quickfix_jump(1),
+                "grep-previous-match" => self.quickfix_jump(-1),
+                "find-file" => self.open_picker(arg),
+                "explorer" => self.toggle_explorer(),
+                "lsp-hover" => self.lsp_hover(),
+                "lsp-definition" => self.goto_definition(),
+                "lsp-references" => self.find_references(),
+                "lsp-rename" => self.rename_symbol(arg),
+                "lsp-formatting" => self.format_document(),
+                "eolformat" => self.set_line_ending(arg),
+                "encoding" => self.set_encoding(arg),
+                "endofline" => self.set_final_newline(arg),
+                _ => self.run_action_or_plugin(command, arg),
             }
         } else {
-            match self.command_input.trim() {
-                ":q" => {
-                    Atto::reset_terminal();
-                    std::process::exit(0);
+            match command {
+                "q" | "quit" => self.quit(false),
+                "q!" | "quit!" => self.quit(true),
+                "w" => {
+                    self.write_file(false);
                 }
-                ":w" => {
-                    if let Err(e) = self.write_file() {
-                        eprintln!("Error writing file: {}", e);
-                    }
+                "w!" => {
+                    self.write_file(true);
                 }
-                ":wq" => {
-                    if let Err(e) = self.write_file() {
-                        eprintln!("Error writing file: {}", e);
+                "wq" | "x" => {
+                    if self.write_file(false) {
+                        self.quit(false);
                     }
-                    Atto::reset_terminal();
-                    std::process::exit(0);
                 }
-                _ => {}
+                "e" => self.edit_file(arg),
+                "b" => self.select_buffer(arg),
+                "bn" => self.next_buffer(),
+                "bp" => self.prev_buffer(),
+                "ls" => self.list_buffers(),
+                "sp" | "split" => self.split_window(Direction::Vertical),
+                "vs" | "vsplit" => self.split_window(Direction::Horizontal),
+                "clo" | "close" => self.close_window(),
+                "gr" | "grep" => self.grep_command(arg),
+                "cn" | "cnext" => self.quickfix_jump(1),
+                "cp" | "cprev" | "cprevious" => self.quickfix_jump(-1),
+                "cc" => self.quickfix_jump(0),
+                "cope" | "copen" => self.quickfix_show(true),
+                "ccl" | "cclose" => self.quickfix_show(false),
+                "fin" | "find" => self.open_picker(arg),
+                "Lex" | "Lexplore" | "explorer" => self.toggle_explorer(),
+                "rename" => self.rename_symbol(arg),
+                "definition" => self.goto_definition(),
+                "references" => self.find_references(),
+                "ff" | "fileformat" => self.set_line_ending(arg),
+                "fenc" | "fileencoding" => self.set_encoding(arg),
+                "eol" | "endofline" => self.set_final_newline(arg),
+                "noh" | "nohlsearch" => self.run_action_or_plugin("clear_search", ""),
+                _ => self.run_action_or_plugin(command, arg),
             }
         }
 
+        // The prompt was taken before the command ran, and anything it asks
+        // now stays open.
         self.command_input.clear();
-        self.toggle_command_mode();
     }
 
+    fn run_command(&mut self, command: &str) {
+        self.command_input = command.trim_start_matches(':').to_string();
+        self.execute_command();
+    }
+
+    fn run_action_or_plugin(&mut self, name: &str, arg: &str) {
+        match self.run_action(name) {
+            Ok(true) => {}
+            Ok(false) => {
+                if self.run_plugin_command(name, arg) == Some(false) {
+                    self.status_message = Some(format!("Unknown command: {}", name));
+                }
+            }
+            Err(e) => self.status_message = Some(format!("Error: {}", e)),
+        }
+    }
 
     fn page_up(&mut self) {
+        let display_x = self.display_x();
         if self.scroll_offset > 0 {
-            let scroll_amount = std::cmp::min(self.scroll_offset, self.terminal_height);
+            let scroll_amount = std::cmp::min(self.scroll_offset, self.view_height);
             self.scroll_offset -= scroll_amount;
             self.cursor_y = self.scroll_offset;
         } else {
             self.cursor_y = 0;
         }
-        self.cursor_x = std::cmp::min(self.cursor_x, self.buffer[self.cursor_y].len());
+        self.set_display_x(display_x);
     }
 
     fn page_down(&mut self) {
-        if self.scroll_offset + self.terminal_height < self.buffer.len() {
-            let scroll_amount = std::cmp::min(self.terminal_height, self.buffer.len() - self.scroll_offset - self.terminal_height);
+        let display_x = self.display_x();
+        let len_lines = self.doc().buffer.len_lines();
+        if self.scroll_offset + self.view_height < len_lines {
+            let scroll_amount = std::cmp::min(self.view_height, len_lines - self.scroll_offset - self.view_height);
             self.scroll_offset += scroll_amount;
-            self.cursor_y = self.scroll_offset + self.terminal_height - 3;
-        } else {
-            self.cursor_y = self.buffer.len() - 3;
         }
-        self.cursor_x = std::cmp::min(self.cursor_x, self.buffer[self.cursor_y].len());
+        // The last line on screen, which is short of the view on a short buffer.
+        self.cursor_y = (self.scroll_offset + self.view_height).min(len_lines).saturating_sub(1);
+        self.set_display_x(display_x);
     }
 
     fn scroll_up(&mut self) {
@@ -342,9 +910,9 @@ impl Atto {
     }
 
     fn scroll_down(&mut self) {
-        if self.scroll_offset + self.terminal_height < self.buffer.len() {
+        if self.scroll_offset + self.view_height < self.doc().buffer.len_lines() {
             self.scroll_offset += 1;
-            if self.cursor_y < self.buffer.len() - 1 {
+            if self.cursor_y < self.doc().buffer.len_lines() - 1 {
                 self.cursor_y += 1;
             }
         }
@@ -353,40 +921,151 @@ impl Atto {
 
     fn render<B: Backend>(&self, f: &mut tui::Frame<B>) {
         let size = f.size();
-        let block = Block::default().borders(Borders::NONE).title("Atto");
-
-        let paragraph = Paragraph::new(
-            self.buffer.iter().enumerate().skip(self.scroll_offset).take(self.terminal_height).map(|(i, line)| {
-                let line_number = format!("{:>4} ", i + 1);
-                let line_with_number = format!("{}{}", line_number, line.replace("\\t", "    "));
-                let visible_line = if line_with_number.len() > self.horizontal_scroll_offset {
-                    line_with_number[self.horizontal_scroll_offset..].to_string() // Clone the string slice
-                } else {
-                    String::new()
-                };
-                Spans::from(Span::raw(visible_line))
-            }).collect::<Vec<_>>()
-        ).block(block);
-
-        f.render_widget(paragraph, size);
+        for index in 0..self.windows.len() {
+            self.render_window(f, index);
+        }
+        self.render_explorer(f);
+        if let Some(quickfix) = &self.quickfix {
+            let panel = quickfix.height(size.height);
+            self.render_quickfix(f, Rect::new(0, size.height.saturating_sub(1 + panel), size.width, panel));
+        }
         self.render_status_bar(f, size);
+        self.render_hover(f, size);
+        self.render_completion(f, size);
+        self.render_picker(f, size);
+    }
+
+    fn render_window<B: Backend>(&self, f: &mut tui::Frame<B>, index: usize) {
+        let window = self.window_view(index);
+        let doc = &self.documents[window.document];
+        let title = if self.windows.len() == 1 {
+            "Atto".to_string()
+        } else if doc.modified() {
+            format!("{} [+]", doc.display_name())
+        } else {
+            doc.display_name()
+        };
+        let title_style = if index == self.focus && self.windows.len() > 1 {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        let borders = if window.separator { Borders::RIGHT } else { Borders::NONE };
+        let block = Block::default().borders(borders).title(Span::styled(title, title_style));
+        let inner = block.inner(window.area);
+        let text_width = inner.width.saturating_sub(self.gutter.width(doc.buffer.len_lines()) as u16) as usize;
+
+        let lines = window.scroll_offset..(window.scroll_offset + inner.height as usize).min(doc.buffer.len_lines());
+        let mut styles = match doc.syntax {
+            Some(ref syntax) => syntax.highlight(doc.buffer.as_ref(), lines.clone()),
+            None => vec![Vec::new(); lines.len()],
+        };
+        if let Some(search) = self.search.as_ref().filter(|search| search.highlight) {
+            let style = Style::default().bg(Color::Yellow).fg(Color::Black);
+            for (line, line_styles) in lines.clone().zip(styles.iter_mut()) {
+                let matches = search::line_matches(&search.regex, &doc.buffer.line(line));
+                line_styles.extend(matches.into_iter().filter(|m| !m.is_empty()).map(|m| (m, style)));
+            }
+        }
+        for diagnostic in &doc.diagnostics {
+            let style = diagnostic_style(diagnostic.severity);
+            let first = doc.buffer.char_to_line(diagnostic.range.start.min(doc.buffer.len_chars()));
+            let last = doc.buffer.char_to_line(diagnostic.range.end.min(doc.buffer.len_chars()));
+            for line in first.max(lines.start)..=last.min(lines.end.saturating_sub(1)) {
+                let start = doc.buffer.line_to_char(line);
+                let len = doc.buffer.line_len(line);
+                let range = (diagnostic.range.start.max(start) - start).min(len)..(diagnostic.range.end.max(start) - start).min(len);
+                // Empty ranges still mark the char they point at.
+                let range = if range.is_empty() { range.start..range.start + 1 } else { range };
+                styles[line - lines.start].push((range, style.add_modifier(Modifier::UNDERLINED)));
+            }
+        }
+        if index == self.focus {
+            let selected = self.selected_ranges();
+            for (line, line_styles) in lines.clone().zip(styles.iter_mut()) {
+                line_styles.extend(self.selection_styles(line, &selected));
+            }
+        }
 
+        let gutter = self.gutter_spans(&window, lines.clone());
+        let mut rows = Vec::new();
+        for ((i, line_styles), mut spans) in lines.zip(styles).zip(gutter) {
+            let line = doc.buffer.line(i);
+            if !self.wrap.enabled {
+                spans.extend(unicode::visible_spans(&line, window.horizontal_scroll_offset, text_width, &line_styles));
+                rows.push(Spans::from(spans));
+                continue;
+            }
+            // Only the first row of a wrapped line is numbered.
+            for (n, row) in wrap::rows(&line, text_width, self.wrap.words).into_iter().enumerate() {
+                let mut spans = if n == 0 { spans.clone() } else { vec![self.gutter_continuation(doc)] };
+                spans.extend(unicode::visible_spans(&line, row.start, row.len(), &line_styles));
+                rows.push(Spans::from(spans));
+            }
+            if rows.len() >= inner.height as usize {
+                break;
+            }
+        }
+        let paragraph = Paragraph::new(rows).block(block);
+
+        f.render_widget(paragraph, window.area);
+    }
 
+    // Char ranges of `line` covered by `selected` (the visual or shift
+    // selection) or other selections, and the cursors of all but the primary
+    // one.
+    fn selection_styles(&self, line: usize, selected: &[Range<usize>]) -> Vec<(Range<usize>, Style)> {
+        let buffer = self.doc().buffer.as_ref();
+        let start = buffer.line_to_char(line);
+        let end = start + buffer.line_len(line) + 1;
+        let mut styles = Vec::new();
+        for range in selected {
+            if range.start < end && range.end > start {
+                let range = range.start.max(start) - start..range.end.min(end) - start;
+                styles.push((range, Style::default().bg(Color::DarkGray)));
+            }
+        }
+        for (i, selection) in self.selections.iter().enumerate() {
+            if selection.start() < end && selection.end() > start {
+                let range = selection.start().max(start) - start..selection.end().min(end) - start;
+                styles.push((range, Style::default().bg(Color::DarkGray)));
+            }
+            if i != self.primary && (start..end).contains(&selection.head) {
+                let col = selection.head - start;
+                styles.push((col..col + 1, Style::default().add_modifier(Modifier::REVERSED)));
+            }
+        }
+        styles
     }
 
     fn render_status_bar<B: Backend>(&self, f: &mut tui::Frame<B>, size: tui::layout::Rect) {
         let status_bar_area = tui::layout::Rect::new(0, size.height - 1, size.width, 1); // Bottom row for the status bar
 
-        let cursor_position = format!("Line: {}, Col: {}", self.cursor_y + 1, self.cursor_x + 1);
-        let filename = self.filename.as_ref().map_or("Untitled".to_string(), |f| f.clone());
-        let command_display = if self.command_mode {
-            format!(" {}", self.command_input)
+        let cursor_position = format!("Line: {}, Col: {} (disp {})", self.cursor_y + 1, self.cursor_x + 1, self.display_x() + 1);
+        let doc = self.doc();
+        let modified = if doc.modified() { " [+]" } else { "" };
+        let format = doc.file_format();
+        let format = if format.is_empty() { format } else { format!(" [{}]", format) };
+        let filename = format!("[{}/{}] {}{}{}", self.current + 1, self.documents.len(), doc.display_name(), modified, format);
+        let command_display = if let Some(ref prompt) = self.prompt {
+            format!(" {}{}", prompt.label(), self.command_input)
+        } else if !self.pending_keys.is_empty() {
+            format!(" {}-", keymap::keys_name(&self.pending_keys))
+        } else if !self.normal_keys.is_empty() {
+            format!(" {}", self.normal_keys)
+        } else if let Some(ref message) = self.status_message {
+            format!(" {}", message)
+        } else if let Some(message) = self.diagnostic_at_cursor() {
+            format!(" {}", message)
         } else {
             String::new()
         };
         let mode_display = match self.mode {
             Mode::Normal => "NORMAL",
             Mode::Insert => "INSERT",
+            Mode::Visual(Visual::Char) => "VISUAL",
+            Mode::Visual(Visual::Line) => "V-LINE",
+            Mode::Visual(Visual::Block) => "V-BLOCK",
         };
 
         let status_text = format!(" {} | {} | {} | {}", filename, cursor_position, mode_display, command_display);
@@ -400,113 +1079,189 @@ impl Atto {
 
 
 
+    fn insert_text(&mut self, pos: usize, text: &str) {
+        let cursor = (self.cursor_y, self.cursor_x);
+        let doc = self.doc_mut();
+        let edit = Edit { pos, removed: String::new(), inserted: text.to_string() };
+        doc.history.record(edit, cursor);
+        doc.insert(pos, text);
+        self.changed = true;
+    }
+
+    fn remove_text(&mut self, range: Range<usize>) {
+        let cursor = (self.cursor_y, self.cursor_x);
+        let doc = self.doc_mut();
+        let edit = Edit { pos: range.start, removed: doc.buffer.slice(range.clone()), inserted: String::new() };
+        doc.history.record(edit, cursor);
+        doc.remove(range);
+        self.changed = true;
+    }
+
+    fn undo(&mut self) {
+        if let Some(cursor) = self.doc_mut().undo() {
+            self.changed = true;
+            self.set_cursor(cursor);
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(cursor) = self.doc_mut().redo() {
+            self.changed = true;
+            self.set_cursor(cursor);
+        }
+    }
+
+    fn set_cursor(&mut self, (y, x): (usize, usize)) {
+        self.cursor_y = y.min(self.doc().buffer.len_lines() - 1);
+        self.cursor_x = unicode::grapheme_start(&self.doc().buffer.line(self.cursor_y), x);
+        if self.cursor_y < self.scroll_offset {
+            self.scroll_offset = self.cursor_y;
+        } else if self.cursor_y >= self.scroll_offset + self.view_height {
+            self.scroll_offset = (self.cursor_y + 1).saturating_sub(self.view_height);
+        }
+        self.scroll_to_cursor_x();
+    }
+
+    fn cursor_char(&self) -> usize {
+        self.doc().buffer.pos_to_char(self.cursor_y, self.cursor_x)
+    }
+
+    fn set_cursor_char(&mut self, pos: usize) {
+        let cursor = self.doc().buffer.char_to_pos(pos);
+        self.set_cursor(cursor);
+    }
+
     fn input_tab(&mut self) {
-        if self.cursor_y < self.buffer.len() && self.cursor_x < self.terminal_width {
-            self.buffer[self.cursor_y].insert_str(self.cursor_x, "    ");
+        if self.cursor_y < self.doc().buffer.len_lines() && (self.wrap.enabled || self.cursor_x < self.view_width) {
+            let pos = self.doc().buffer.pos_to_char(self.cursor_y, self.cursor_x);
+            self.insert_text(pos, "    ");
             self.cursor_x += 4;
+            self.scroll_to_cursor_x();
+        }
+    }
+
+    fn text_width(&self) -> usize {
+        self.view_width.saturating_sub(self.gutter_width() as usize)
+    }
+
+    fn display_x(&self) -> usize {
+        unicode::display_col(&self.doc().buffer.line(self.cursor_y), self.cursor_x)
+    }
+
+    fn set_display_x(&mut self, display_x: usize) {
+        self.cursor_x = unicode::col_at_display(&self.doc().buffer.line(self.cursor_y), display_x);
+        self.scroll_to_cursor_x();
+    }
+
+    fn scroll_to_cursor_x(&mut self) {
+        if self.wrap.enabled {
+            self.horizontal_scroll_offset = 0;
+            return;
+        }
+        let display_x = self.display_x();
+        if display_x < self.horizontal_scroll_offset {
+            self.horizontal_scroll_offset = display_x;
+        } else if display_x >= self.horizontal_scroll_offset + self.text_width() {
+            self.horizontal_scroll_offset = display_x + 7 - self.text_width();
         }
     }
 
     fn move_up(&mut self) {
-        if self.cursor_y > 0 {
+        if self.wrap.enabled {
+            self.move_row(false);
+        } else if self.cursor_y > 0 {
+            let display_x = self.display_x();
             self.cursor_y -= 1;
             if self.cursor_y < self.scroll_offset {
                 self.scroll_offset -= 1;
             }
-            self.cursor_x = std::cmp::min(self.cursor_x, self.buffer[self.cursor_y].len());
+            self.set_display_x(display_x);
         }
     }
 
     fn move_down(&mut self) {
-        if self.cursor_y < self.buffer.len() - 1 {
+        if self.wrap.enabled {
+            self.move_row(true);
+        } else if self.cursor_y < self.doc().buffer.len_lines() - 1 {
+            let display_x = self.display_x();
             self.cursor_y += 1;
-            if self.cursor_y >= self.scroll_offset + (self.terminal_height - 2) {
+            if self.cursor_y >= self.scroll_offset + self.view_height {
                 self.scroll_offset += 1;
             }
-            self.cursor_x = std::cmp::min(self.cursor_x, self.buffer[self.cursor_y].len());
+            self.set_display_x(display_x);
         }
     }
 
     fn move_left(&mut self) {
         if self.cursor_x > 0 {
-            self.cursor_x -= 1;
-            if self.cursor_x < self.horizontal_scroll_offset {
-                self.horizontal_scroll_offset -= 1;
-            }
+            self.cursor_x = unicode::prev_grapheme(&self.doc().buffer.line(self.cursor_y), self.cursor_x);
+            self.scroll_to_cursor_x();
         }
     }
 
     fn move_right(&mut self) {
-        if self.cursor_y < self.buffer.len() && self.cursor_x < self.buffer[self.cursor_y].len() {
-            self.cursor_x += 1;
-            if self.cursor_x >= self.horizontal_scroll_offset + self.terminal_width {
-                self.horizontal_scroll_offset += 7;
-            }
+        if self.cursor_y < self.doc().buffer.len_lines() && self.cursor_x < self.doc().buffer.line_len(self.cursor_y) {
+            self.cursor_x = unicode::next_grapheme(&self.doc().buffer.line(self.cursor_y), self.cursor_x);
+            self.scroll_to_cursor_x();
         }
     }
 
     fn input_char(&mut self, c: char) {
-        if self.cursor_y < self.buffer.len() && self.cursor_x < self.terminal_width - 1 {
-            self.buffer[self.cursor_y].insert(self.cursor_x, c);
+        if self.cursor_y < self.doc().buffer.len_lines() && (self.wrap.enabled || self.cursor_x < self.view_width - 1) {
+            let pos = self.doc().buffer.pos_to_char(self.cursor_y, self.cursor_x);
+            self.insert_text(pos, c.encode_utf8(&mut [0; 4]));
             self.cursor_x += 1;
+            self.scroll_to_cursor_x();
         }
     }
 
     fn new_line(&mut self) {
-        let new_line = self.buffer[self.cursor_y].split_off(self.cursor_x);
-        self.buffer.insert(self.cursor_y + 1, new_line);
+        let pos = self.doc().buffer.pos_to_char(self.cursor_y, self.cursor_x);
+        self.insert_text(pos, "\n");
         self.cursor_y += 1;
         self.cursor_x = 0;
-        if self.cursor_y >= self.scroll_offset + self.terminal_height {
+        self.horizontal_scroll_offset = 0;
+        if self.cursor_y >= self.scroll_offset + self.view_height {
             self.scroll_offset += 1;
         }
     }
 
     fn backspace(&mut self) {
         if self.cursor_x > 0 {
-            self.cursor_x -= 1;
-            self.buffer[self.cursor_y].remove(self.cursor_x);
+            let pos = self.doc().buffer.pos_to_char(self.cursor_y, self.cursor_x);
+            let prev = unicode::prev_grapheme(&self.doc().buffer.line(self.cursor_y), self.cursor_x);
+            self.remove_text(pos - (self.cursor_x - prev)..pos);
+            self.cursor_x = prev;
+            self.scroll_to_cursor_x();
         } else if self.cursor_y > 0 {
-            let current_line = self.buffer.remove(self.cursor_y);
+            let pos = self.doc().buffer.line_to_char(self.cursor_y);
+            let prev_len = self.doc().buffer.line_len(self.cursor_y - 1);
+            self.remove_text(pos - 1..pos);
             self.cursor_y -= 1;
-            self.cursor_x = self.buffer[self.cursor_y].len();
-            self.buffer[self.cursor_y].push_str(&current_line);
+            self.cursor_x = prev_len;
             if self.cursor_y < self.scroll_offset {
                 self.scroll_offset -= 1;
             }
-        }
-    }
-}
-
-struct KeyBindingHint {
-    key: String,
-    description: String,
-}
-
-impl KeyBindingHint {
-    fn new(key: &str, description: &str) -> Self {
-        Self {
-            key: key.to_string(),
-            description: description.to_string(),
+            self.scroll_to_cursor_x();
         }
     }
 }
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let filename = if args.len() < 2 {
-        None
-    } else {
-        Some(args[1].clone())
-    };
-
-    if let Some(ref file) = filename {
+    let mut documents = Vec::new();
+    for file in args.iter().skip(1) {
         if !Path::new(file).exists() {
-            let file_handle = File::create(file)?;
+            File::create(file)?;
         }
+        documents.push(Document::open(file)?);
+    }
+    if documents.is_empty() {
+        documents.push(Document::new(None));
     }
 
     let lua = Lua::new();
+    plugin::install(&lua).unwrap();
 
 
     let atto_conf = dirs::config_dir()
@@ -527,39 +1282,143 @@ fn main() -> io::Result<()> {
         create_default_config(config_path_str)?;
     }
 
-    let preset: String = lua.context(|lua_ctx| {
-        let config: Table = lua_ctx.load(&fs::read_to_string(&config_path).unwrap()).eval().unwrap();
-        config.get("key_binding_preset").unwrap()
-    });
-
-    let vim_mode: bool = lua.context(|lua_ctx| {
-        let config: Table = lua_ctx.load(&fs::read_to_string(&config_path).unwrap()).eval().unwrap();
-        config.get("vim_mode").unwrap()
-    });
+    let plugin_dir = atto_conf.join("plugins");
+    if !plugin_dir.exists() {
+        fs::create_dir_all(&plugin_dir)?;
+    }
 
-    let command_style: String = lua.context(|lua_ctx| {
-        let config: Table = lua_ctx.load(&fs::read_to_string(&config_path).unwrap()).eval().unwrap();
-        config.get("command_style").unwrap()
-    });
+    let (preset, vim_mode, command_style, bindings, clipboard, servers, wrap, gutter) = {
+        let config: Table = lua.load(fs::read_to_string(&config_path)?).eval().unwrap();
+        let preset: String = config.get("key_binding_preset").unwrap();
+        let vim_mode: bool = config.get("vim_mode").unwrap();
+        let command_style: String = config.get("command_style").unwrap();
+        let mut bindings = Vec::new();
+        if let Some(keymap) = config.get::<_, Option<Table>>("keymap").unwrap() {
+            for pair in keymap.pairs::<String, Table>() {
+                let (mode, maps) = pair.unwrap();
+                for pair in maps.pairs::<String, String>() {
+                    let (keys, action) = pair.unwrap();
+                    bindings.push((mode.clone(), keys, action));
+                }
+            }
+        }
+        let clipboard = match config.get::<_, Option<Table>>("clipboard").unwrap() {
+            Some(clipboard) => Clipboard::new(
+                clipboard.get::<_, Option<bool>>("osc52").unwrap().unwrap_or(true),
+                clipboard.get::<_, Option<bool>>("unnamed").unwrap().unwrap_or(true),
+                clipboard.get("copy").unwrap(),
+                clipboard.get("paste").unwrap(),
+            ),
+            None => Clipboard::new(true, true, None, None),
+        };
+        let mut servers = HashMap::new();
+        if let Some(languages) = config.get::<_, Option<Table>>("language_servers").unwrap() {
+            for pair in languages.pairs::<String, Table>() {
+                let (language, server) = pair.unwrap();
+                let command: String = server.get("command").unwrap();
+                let args = server.get::<_, Option<Vec<String>>>("args").unwrap().unwrap_or_default();
+                servers.insert(language, lsp::ServerConfig { command, args });
+            }
+        }
+        let wrap = match config.get::<_, Option<Table>>("wrap").unwrap() {
+            Some(wrap) => wrap::Wrap {
+                enabled: wrap.get::<_, Option<bool>>("enabled").unwrap().unwrap_or(true),
+                words: wrap.get::<_, Option<bool>>("words").unwrap().unwrap_or(true),
+                indicator: wrap.get::<_, Option<String>>("indicator").unwrap().unwrap_or_else(|| wrap::Wrap::default().indicator),
+            },
+            None => wrap::Wrap::default(),
+        };
+        let mut gutter = gutter::Gutter::default();
+        if let Some(table) = config.get::<_, Option<Table>>("gutter").unwrap() {
+            if let Some(numbers) = table.get::<_, Option<String>>("numbers").unwrap() {
+                gutter.numbers = gutter::Numbers::parse(&numbers).unwrap_or(gutter.numbers);
+            }
+            // Each entry is a column, with one source or a list of them.
+            if let Some(signs) = table.get::<_, Option<Vec<Value>>>("signs").unwrap() {
+                gutter.signs = signs.into_iter().map(|column| {
+                    let names = match column {
+                        Value::String(name) => vec![name.to_str().unwrap_or_default().to_string()],
+                        Value::Table(names) => names.sequence_values::<String>().filter_map(Result::ok).collect(),
+                        _ => Vec::new(),
+                    };
+                    names.iter().filter_map(|name| gutter::Source::parse(name)).collect()
+                }).collect();
+            }
+        }
+        (preset, vim_mode, command_style, bindings, clipboard, servers, wrap, gutter)
+    };
 
 
-    let mut atto = Atto::new(filename, &preset, vim_mode, &command_style);
-    atto.read_file()?;
+    let mut atto = Atto::new(documents, &preset, vim_mode, &command_style, lua);
+    atto.registers.clipboard = clipboard;
+    atto.lsp = lsp::Lsp::new(servers);
+    atto.wrap = wrap;
+    atto.gutter = gutter;
+    for (mode, keys, action) in bindings {
+        if !atto.keymap.bind(&mode, &keys, Binding::Action(action)) {
+            atto.status_message = Some(format!("Invalid key sequence: {}", keys));
+        }
+    }
+    atto.sync_plugin_maps();
+    atto.load_plugins(&plugin_dir);
+    for index in (0..atto.documents.len()).rev() {
+        atto.switch_buffer(index);
+        atto.check_swap(index);
+        let name = atto.doc().display_name();
+        atto.fire("on_open", name);
+    }
     let stdout = io::stdout();
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    atto.run(&mut terminal)?;
-    atto.write_file()?;
+    // A panic puts the terminal back before its message is printed, and
+    // leaves what was typed since the last swap in the swap files.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, Show);
+        default_hook(info);
+    }));
+    match panic::catch_unwind(AssertUnwindSafe(|| atto.run(&mut terminal))) {
+        Ok(result) => result?,
+        Err(panic) => {
+            atto.write_swaps(true);
+            if let Some(dir) = &atto.swaps.dir {
+                eprintln!("Unsaved changes are in {}, atto offers them back when the file is opened again", dir.display());
+            }
+            panic::resume_unwind(panic);
+        }
+    }
+    atto.remove_swaps();
     Ok(())
 }
 
+fn diagnostic_style(severity: u64) -> Style {
+    match severity {
+        1 => Style::default().fg(Color::Red),
+        2 => Style::default().fg(Color::Yellow),
+        _ => Style::default().fg(Color::Blue),
+    }
+}
+
 fn create_default_config(config_path: &str) -> io::Result<()> {
     let default_content = r#"
 -- Default configuration for Atto
 return {
-    key_binding_preset = "atto", -- Options: "nano", "micro", "atto"
+    key_binding_preset = "atto", -- Options: "nano", "micro", "emacs", "atto"
     vim_mode = false,
     command_style = "vim", -- Options: "kakoune", "vim"
+    -- Extra bindings on top of the preset, per mode ("insert", or "normal" and "visual" in vim mode):
+    -- keymap = { insert = { ["<C-x><C-s>"] = "save", ["<F5>"] = ":bn" } },
+    -- Yanks and copies reach the system clipboard through OSC 52 and whichever
+    -- of wl-copy, xclip, xsel or pbcopy is installed, unless helpers are given:
+    -- clipboard = { osc52 = true, unnamed = true, copy = "xclip -selection clipboard", paste = "xclip -selection clipboard -o" },
+    -- Language servers to start per language (rust, python, c, cpp, go, javascript, typescript, ...):
+    -- language_servers = { rust = { command = "rust-analyzer" }, python = { command = "pylsp", args = {} } },
+    -- Long lines wrap onto the next rows instead of scrolling sideways (toggle_wrap switches it):
+    -- wrap = { enabled = true, words = true, indicator = "↪" },
+    -- Line numbers ("absolute", "relative", "hybrid" or "off") and the sign columns after them, each
+    -- showing the first of its sources ("diagnostics", "bookmarks", "search", "git") with a sign on the line:
+    -- gutter = { numbers = "absolute", signs = { { "diagnostics", "bookmarks", "search" }, "git" } },
 }
 "#;
 
@@ -568,3 +1427,84 @@ return {
     println!("Created default config file at: {}", config_path);
     Ok(())
 }
+
+#[cfg(test)]
+impl Atto {
+    // An editor on an unnamed buffer holding `text`, for the tests.
+    fn scratch(text: &str, vim_mode: bool) -> Self {
+        let mut document = Document::new(None);
+        document.buffer.insert(0, text);
+        let lua = Lua::new();
+        plugin::install(&lua).unwrap();
+        Self::new(vec![document], "atto", vim_mode, "vim", lua)
+    }
+
+    // Types `keys`, written as in the keymap, the way the run loop does.
+    fn type_keys(&mut self, keys: &str) {
+        for (code, modifiers) in keymap::parse_keys(keys).unwrap() {
+            let key = KeyEvent::new(code, modifiers);
+            self.handle_key(key).unwrap();
+            self.update_completion(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn page_down_short_buffers() {
+        for text in ["", "one", "one\ntwo"] {
+            let mut atto = Atto::scratch(text, false);
+            atto.type_keys("<PageDown>");
+            assert_eq!(atto.cursor_y, atto.doc().buffer.len_lines() - 1, "{:?}", text);
+            atto.type_keys("<PageDown><PageUp>");
+            assert_eq!(atto.cursor_y, 0);
+        }
+    }
+
+    #[test]
+    fn page_down() {
+        let text: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        let mut atto = Atto::scratch(&text.join("\n"), false);
+        atto.view_height = 20;
+        atto.type_keys("<PageDown>");
+        assert_eq!((atto.scroll_offset, atto.cursor_y), (20, 39));
+        atto.type_keys("<PageDown>");
+        assert_eq!((atto.scroll_offset, atto.cursor_y), (30, 49));
+        atto.type_keys("<PageDown>");
+        assert_eq!((atto.scroll_offset, atto.cursor_y), (30, 49));
+    }
+
+    #[test]
+    fn buffer_list() {
+        let dir = env::temp_dir().join(format!("atto-buffers-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt").display().to_string(), dir.join("b.txt").display().to_string());
+        fs::write(&a, "a1\na2\n").unwrap();
+        fs::write(&b, "b1\n").unwrap();
+        let mut atto = Atto::scratch("", true);
+        atto.type_keys(&format!(":e {}<Enter>j:e {}<Enter>", a, b));
+        assert_eq!((atto.documents.len(), atto.current), (3, 2));
+        atto.type_keys("Ax<Esc>:ls<Enter>");
+        assert_eq!(atto.status_message.as_deref(), Some(format!("1: Untitled  2: {}  3%: {} [+]", a, b).as_str()));
+        // Each buffer keeps its cursor.
+        atto.type_keys(":bp<Enter>");
+        assert_eq!((atto.current, atto.cursor_y), (1, 1));
+        atto.type_keys(":bn<Enter>:bn<Enter>");
+        assert_eq!(atto.current, 0);
+        atto.type_keys(&format!(":b {}<Enter>", b));
+        assert_eq!((atto.current, atto.cursor_x), (2, 2));
+        // An open file isn't opened twice.
+        atto.type_keys(&format!(":e {}<Enter>", a));
+        assert_eq!((atto.documents.len(), atto.current), (3, 1));
+        atto.type_keys(":b 1<Enter>");
+        assert_eq!(atto.current, 0);
+        atto.type_keys(":b 4<Enter>");
+        assert_eq!((atto.current, atto.status_message.as_deref()), (0, Some("No such buffer: 4")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use crate::buffer::TextBuffer;
+
+// Buffer-level motions and text objects shared by the modal editing styles.
+// Positions are char indices into the whole buffer unless noted.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Space,
+    Word,
+    Punct,
+}
+
+fn class(c: char) -> Class {
+    if c.is_whitespace() {
+        Class::Space
+    } else if c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else {
+        Class::Punct
+    }
+}
+
+// Empty lines stop word motions as if they were a word of their own.
+fn empty_line(buffer: &dyn TextBuffer, idx: usize) -> bool {
+    buffer.char_at(idx) == '\n' && (idx == 0 || buffer.char_at(idx - 1) == '\n')
+}
+
+pub fn word_forward(buffer: &dyn TextBuffer, idx: usize) -> usize {
+    let len = buffer.len_chars();
+    let mut i = idx;
+    if i >= len {
+        return len;
+    }
+    let start = class(buffer.char_at(i));
+    if start != Class::Space {
+        while i < len && class(buffer.char_at(i)) == start {
+            i += 1;
+        }
+    }
+    while i < len && class(buffer.char_at(i)) == Class::Space {
+        if i != idx && empty_line(buffer, i) {
+            break;
+        }
+        i += 1;
+    }
+    i
+}
+
+pub fn word_end(buffer: &dyn TextBuffer, idx: usize) -> usize {
+    let len = buffer.len_chars();
+    let mut i = idx + 1;
+    while i < len && class(buffer.char_at(i)) == Class::Space {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1).max(idx);
+    }
+    let start = class(buffer.char_at(i));
+    while i + 1 < len && class(buffer.char_at(i + 1)) == start {
+        i += 1;
+    }
+    i
+}
+
+pub fn word_backward(buffer: &dyn TextBuffer, idx: usize) -> usize {
+    if idx == 0 {
+        return 0;
+    }
+    let mut i = idx - 1;
+    while i > 0 && class(buffer.char_at(i)) == Class::Space {
+        if empty_line(buffer, i) {
+            return i;
+        }
+        i -= 1;
+    }
+    let start = class(buffer.char_at(i));
+    while i > 0 && class(buffer.char_at(i - 1)) == start {
+        i -= 1;
+    }
+    i
+}
+
+// Whether `idx` is the last char of a word, run of punctuation or whitespace.
+pub fn word_boundary(buffer: &dyn TextBuffer, idx: usize) -> bool {
+    idx + 1 < buffer.len_chars() && class(buffer.char_at(idx)) != class(buffer.char_at(idx + 1))
+}
+
+pub fn first_non_blank(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).count()
+}
+
+// Column of the `count`th `target` after (or before) `col` on `line`.
+pub fn find_in_line(line: &str, col: usize, target: char, count: usize, forward: bool) -> Option<usize> {
+    let chars: Vec<char> = line.chars().collect();
+    if forward {
+        (col + 1..chars.len()).filter(|&i| chars[i] == target).nth(count - 1)
+    } else {
+        (0..col.min(chars.len())).rev().filter(|&i| chars[i] == target).nth(count - 1)
+    }
+}
+
+const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+// The bracket matching the first one at or after `idx` on its line.
+pub fn match_pair(buffer: &dyn TextBuffer, idx: usize) -> Option<usize> {
+    let (line, col) = buffer.char_to_pos(idx);
+    let start = buffer.line_to_char(line);
+    let (offset, c) = buffer.line(line).chars().enumerate().skip(col)
+        .find(|(_, c)| PAIRS.iter().any(|&(open, close)| *c == open || *c == close))?;
+    let pos = start + offset;
+    let len = buffer.len_chars();
+    let mut depth = 0;
+    if let Some(&(open, close)) = PAIRS.iter().find(|(open, _)| *open == c) {
+        for i in pos + 1..len {
+            match buffer.char_at(i) {
+                ch if ch == open => depth += 1,
+                ch if ch == close && depth == 0 => return Some(i),
+                ch if ch == close => depth -= 1,
+                _ => {}
+            }
+        }
+    } else {
+        let &(open, close) = PAIRS.iter().find(|(_, close)| *close == c)?;
+        for i in (0..pos).rev() {
+            match buffer.char_at(i) {
+                ch if ch == close => depth += 1,
+                ch if ch == open && depth == 0 => return Some(i),
+                ch if ch == open => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+// The word (or run of whitespace) under `idx`; `around` adds the whitespace
+// after it, or before it when there is none after.
+pub fn word_object(buffer: &dyn TextBuffer, idx: usize, around: bool) -> Option<(usize, usize)> {
+    let (line_idx, col) = buffer.char_to_pos(idx);
+    let chars: Vec<char> = buffer.line(line_idx).chars().collect();
+    if chars.is_empty() {
+        return None;
+    }
+    let col = col.min(chars.len() - 1);
+    let kind = class(chars[col]);
+    let mut start = col;
+    while start > 0 && class(chars[start - 1]) == kind {
+        start -= 1;
+    }
+    let mut end = col + 1;
+    while end < chars.len() && class(chars[end]) == kind {
+        end += 1;
+    }
+    if around && kind != Class::Space {
+        let trailing = end;
+        while end < chars.len() && class(chars[end]) == Class::Space {
+            end += 1;
+        }
+        if end == trailing {
+            while start > 0 && class(chars[start - 1]) == Class::Space {
+                start -= 1;
+            }
+        }
+    }
+    let line_start = buffer.line_to_char(line_idx);
+    Some((line_start + start, line_start + end))
+}
+
+// Between the `open`/`close` pair surrounding `idx`, brackets included when
+// `around` is set.
+pub fn pair_object(buffer: &dyn TextBuffer, idx: usize, open: char, close: char, around: bool) -> Option<(usize, usize)> {
+    let len = buffer.len_chars();
+    let mut depth = 0;
+    let mut start = None;
+    for i in (0..=idx.min(len.saturating_sub(1))).rev() {
+        match buffer.char_at(i) {
+            c if c == close && i != idx => depth += 1,
+            c if c == open && depth == 0 => {
+                start = Some(i);
+                break;
+            }
+            c if c == open => depth -= 1,
+            _ => {}
+        }
+    }
+    let start = start?;
+    depth = 0;
+    let end = (start + 1..len).find(|&i| match buffer.char_at(i) {
+        c if c == open => {
+            depth += 1;
+            false
+        }
+        c if c == close => {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+            false
+        }
+        _ => false,
+    })?;
+    Some(if around { (start, end + 1) } else { (start + 1, end) })
+}
+
+// The quoted string on the cursor's line that contains `idx`, or the next one.
+pub fn quote_object(buffer: &dyn TextBuffer, idx: usize, quote: char, around: bool) -> Option<(usize, usize)> {
+    let (line_idx, col) = buffer.char_to_pos(idx);
+    let quotes: Vec<usize> = buffer.line(line_idx).chars().enumerate()
+        .filter(|&(_, c)| c == quote)
+        .map(|(i, _)| i)
+        .collect();
+    let (open, close) = quotes.chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| close >= col)?;
+    let line_start = buffer.line_to_char(line_idx);
+    Some(if around {
+        (line_start + open, line_start + close + 1)
+    } else {
+        (line_start + open + 1, line_start + close)
+    })
+}
+
+// First and last line of the paragraph (or run of blank lines) at `line`;
+// `around` takes in the blank lines that follow.
+pub fn paragraph_object(buffer: &dyn TextBuffer, line: usize, around: bool) -> (usize, usize) {
+    let blank = |i: usize| buffer.line(i).trim().is_empty();
+    let last = buffer.len_lines() - 1;
+    let kind = blank(line);
+    let mut start = line;
+    while start > 0 && blank(start - 1) == kind {
+        start -= 1;
+    }
+    let mut end = line;
+    while end < last && blank(end + 1) == kind {
+        end += 1;
+    }
+    if around {
+        while end < last && blank(end + 1) != kind {
+            end += 1;
+        }
+    }
+    (start, end)
+}
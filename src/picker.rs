@@ -0,0 +1,302 @@
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use ignore::WalkBuilder;
+use tui::backend::Backend;
+use tui::layout::{Direction, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph};
+
+use crate::buffer::{RopeBuffer, TextBuffer};
+use crate::highlight::Syntax;
+use crate::{unicode, Atto};
+
+// Files past this many aren't listed, and only the start of the highlighted
+// file is read for its preview.
+const MAX_FILES: usize = 100_000;
+const PREVIEW_BYTES: u64 = 64 * 1024;
+const PREVIEW_LINES: usize = 200;
+const PAGE: usize = 10;
+
+struct Match {
+    file: usize,
+    // Char indices of the path that the query matched.
+    positions: Vec<usize>,
+}
+
+struct Preview {
+    buffer: RopeBuffer,
+    styles: Vec<Vec<(Range<usize>, Style)>>,
+}
+
+// The fuzzy file finder: every file under the working directory, filtered by
+// `query` and best match first.
+pub struct Picker {
+    files: Vec<String>,
+    query: String,
+    matches: Vec<Match>,
+    selected: usize,
+    preview: Option<Preview>,
+}
+
+// Files that .gitignore and friends don't exclude, by path.
+fn list_files(root: &Path) -> Vec<String> {
+    let mut files: Vec<String> = WalkBuilder::new(root)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .take(MAX_FILES)
+        .map(|entry| entry.path().strip_prefix(root).unwrap_or(entry.path()).display().to_string())
+        .collect();
+    files.sort();
+    files
+}
+
+fn load_preview(path: &str) -> Option<Preview> {
+    let mut bytes = Vec::new();
+    File::open(path).ok()?.take(PREVIEW_BYTES).read_to_end(&mut bytes).ok()?;
+    let text = if bytes.contains(&0) {
+        "(binary file)".into()
+    } else {
+        String::from_utf8_lossy(&bytes)
+    };
+    let buffer = RopeBuffer::from_lines(text.lines().take(PREVIEW_LINES));
+    let lines = buffer.len_lines();
+    let styles = match Syntax::for_file(path) {
+        Some(mut syntax) if !bytes.contains(&0) => {
+            syntax.update(&buffer);
+            syntax.highlight(&buffer, 0..lines)
+        }
+        _ => vec![Vec::new(); lines],
+    };
+    Some(Preview { buffer, styles })
+}
+
+fn picker_area(size: Rect) -> Rect {
+    let width = size.width.saturating_sub(4).min(160);
+    let height = size.height.saturating_sub(4);
+    Rect::new((size.width - width) / 2, (size.height.saturating_sub(1) - height) / 2, width, height)
+}
+
+impl Picker {
+    pub fn new(query: &str) -> Self {
+        let mut picker = Self {
+            files: list_files(Path::new(".")),
+            query: query.to_string(),
+            matches: Vec::new(),
+            selected: 0,
+            preview: None,
+        };
+        picker.filter();
+        picker
+    }
+
+    // Scores every file against the query; ties go to the shorter path.
+    fn filter(&mut self) {
+        let matcher = SkimMatcherV2::default().smart_case();
+        let mut scored: Vec<(i64, Match)> = self.files.iter().enumerate().filter_map(|(file, path)| {
+            let (score, positions) = matcher.fuzzy_indices(path, &self.query)?;
+            Some((score, Match { file, positions }))
+        }).collect();
+        scored.sort_by(|(a, a_match), (b, b_match)| {
+            b.cmp(a).then_with(|| self.files[a_match.file].len().cmp(&self.files[b_match.file].len()))
+        });
+        self.matches = scored.into_iter().map(|(_, m)| m).collect();
+        self.select(0);
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index.min(self.matches.len().saturating_sub(1));
+        self.preview = self.selected_path().and_then(load_preview);
+    }
+
+    fn selected_path(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(|m| self.files[m.file].as_str())
+    }
+}
+
+impl Atto {
+    pub fn open_picker(&mut self, query: &str) {
+        let picker = Picker::new(query);
+        if picker.files.is_empty() {
+            self.status_message = Some("No files under the working directory".to_string());
+            return;
+        }
+        self.picker = Some(picker);
+    }
+
+    // Keys go to the picker while it is open.
+    pub fn picker_key(&mut self, key: KeyEvent) {
+        let picker = match self.picker.as_mut() {
+            Some(picker) => picker,
+            None => return,
+        };
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let split = match key.code {
+            KeyCode::Enter => None,
+            KeyCode::Char('s' | 'x') if ctrl => Some(Direction::Vertical),
+            KeyCode::Char('v') if ctrl => Some(Direction::Horizontal),
+            _ => {
+                match key.code {
+                    KeyCode::Esc => self.picker = None,
+                    KeyCode::Char('c') if ctrl => self.picker = None,
+                    KeyCode::Up => picker.select(picker.selected.saturating_sub(1)),
+                    KeyCode::Char('p' | 'k') if ctrl => picker.select(picker.selected.saturating_sub(1)),
+                    KeyCode::Down => picker.select(picker.selected + 1),
+                    KeyCode::Char('n' | 'j') if ctrl => picker.select(picker.selected + 1),
+                    KeyCode::PageUp => picker.select(picker.selected.saturating_sub(PAGE)),
+                    KeyCode::PageDown => picker.select(picker.selected + PAGE),
+                    KeyCode::Backspace => {
+                        picker.query.pop();
+                        picker.filter();
+                    }
+                    KeyCode::Char('u') if ctrl => {
+                        picker.query.clear();
+                        picker.filter();
+                    }
+                    KeyCode::Char(c) if !ctrl && !key.modifiers.contains(KeyModifiers::ALT) => {
+                        picker.query.push(c);
+                        picker.filter();
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        };
+        let path = match picker.selected_path() {
+            Some(path) => path.to_string(),
+            None => return,
+        };
+        self.picker = None;
+        if let Some(direction) = split {
+            self.split_window(direction);
+        }
+        self.edit_file(&path);
+    }
+
+    // Where the terminal cursor goes while the picker is open: after the query.
+    pub fn picker_cursor(&self, size: Rect) -> Option<(u16, u16)> {
+        let picker = self.picker.as_ref()?;
+        let area = picker_area(size);
+        let x = area.x + 3 + unicode::display_col(&picker.query, picker.query.chars().count()) as u16;
+        Some((x.min(area.x + area.width / 2 - 2), area.y + 1))
+    }
+
+    // A popup over the windows: the query and matches on the left, the
+    // start of the highlighted file on the right.
+    pub fn render_picker<B: Backend>(&self, f: &mut tui::Frame<B>, size: Rect) {
+        let picker = match &self.picker {
+            Some(picker) => picker,
+            None => return,
+        };
+        let area = picker_area(size);
+        let list_area = Rect::new(area.x, area.y, area.width / 2, area.height);
+        let preview_area = Rect::new(area.x + list_area.width, area.y, area.width - list_area.width, area.height);
+        f.render_widget(Clear, area);
+
+        let title = format!("Files {}/{}", picker.matches.len(), picker.files.len());
+        let block = Block::default().borders(Borders::ALL).title(Span::raw(title));
+        let inner = block.inner(list_area);
+        f.render_widget(block, list_area);
+        if inner.height == 0 {
+            return;
+        }
+        let query = Paragraph::new(format!("> {}", picker.query));
+        f.render_widget(query, Rect::new(inner.x, inner.y, inner.width, 1));
+
+        let results = Rect::new(inner.x, inner.y + 1, inner.width, inner.height - 1);
+        let visible = (results.height as usize).max(1);
+        let first = (picker.selected + 1).saturating_sub(visible);
+        let matched = Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+        let items: Vec<ListItem> = picker.matches[first..].iter().take(visible).map(|m| {
+            let path = &picker.files[m.file];
+            let styles: Vec<_> = m.positions.iter().map(|&i| (i..i + 1, matched)).collect();
+            ListItem::new(Spans::from(unicode::visible_spans(path, 0, results.width as usize, &styles)))
+        }).collect();
+        let list = List::new(items).highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default();
+        state.select((!picker.matches.is_empty()).then(|| picker.selected - first));
+        f.render_stateful_widget(list, results, &mut state);
+
+        let title = picker.selected_path().unwrap_or("").to_string();
+        let block = Block::default().borders(Borders::ALL).title(Span::raw(title));
+        let inner = block.inner(preview_area);
+        let lines = match &picker.preview {
+            Some(preview) => (0..preview.buffer.len_lines().min(inner.height as usize)).map(|i| {
+                let line = preview.buffer.line(i);
+                Spans::from(unicode::visible_spans(&line, 0, inner.width as usize, &preview.styles[i]))
+            }).collect(),
+            None => Vec::new(),
+        };
+        f.render_widget(Paragraph::new(lines).block(block), preview_area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs, process};
+
+    use super::{list_files, Picker};
+    use crate::Atto;
+
+    fn filtered(files: &[&str], query: &str) -> Picker {
+        let mut picker = Picker {
+            files: files.iter().map(|f| f.to_string()).collect(),
+            query: query.to_string(),
+            matches: Vec::new(),
+            selected: 0,
+            preview: None,
+        };
+        picker.filter();
+        picker
+    }
+
+    fn listed(picker: &Picker) -> Vec<&str> {
+        picker.matches.iter().map(|m| picker.files[m.file].as_str()).collect()
+    }
+
+    #[test]
+    fn scores() {
+        let files = ["lib.rs", "maintenance/notes.rs", "src/main.rs", "main.rs"];
+        let picker = filtered(&files, "main");
+        // The same score goes to the shorter path first.
+        assert_eq!(listed(&picker), ["main.rs", "maintenance/notes.rs", "src/main.rs"]);
+        assert_eq!(picker.matches[2].positions, [4, 5, 6, 7]);
+        assert_eq!(listed(&filtered(&files, "")).len(), 4);
+        // Upper case in the query has to match.
+        assert!(listed(&filtered(&files, "Main")).is_empty());
+    }
+
+    #[test]
+    fn open_in_split() {
+        let dir = env::temp_dir().join(format!("atto-picker-{}", process::id()));
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.join("one.txt"), "one\n").unwrap();
+        fs::write(dir.join("debug.log"), "").unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("two.txt"), "two\n").unwrap();
+        let files = list_files(&dir);
+        assert_eq!(files, ["one.txt", Path::new("sub").join("two.txt").to_str().unwrap()]);
+
+        let mut atto = Atto::scratch("", true);
+        let paths: Vec<String> = files.iter().map(|f| dir.join(f).display().to_string()).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        atto.picker = Some(filtered(&paths, ""));
+        atto.type_keys("tw<C-v>");
+        assert!(atto.picker.is_none());
+        assert_eq!(atto.windows.len(), 2);
+        assert_eq!(atto.doc().buffer.text(), "two");
+        // The window it was opened from still shows the buffer from before.
+        assert_eq!(atto.windows[0].document, 0);
+        assert_eq!((atto.focus, atto.current), (1, 1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
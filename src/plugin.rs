@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+
+use rlua::{Function, Lua, Table, Value};
+
+use crate::completion::Item;
+use crate::keymap::Binding;
+use crate::Atto;
+
+// Registration helpers only touch Lua tables, so they work from config.lua
+// as well as from plugins. Everything that reaches into the editor is bound
+// per call by `Atto::with_lua`.
+const PRELUDE: &str = r#"
+atto = {
+    _commands = {},
+    _maps = {},
+    _new_maps = {},
+    _completers = {},
+    _hooks = { on_open = {}, on_save = {}, on_key = {}, on_change = {} },
+}
+
+function atto.command(name, fn)
+    atto._commands[name] = fn
+end
+
+function atto.map(mode, keys, action)
+    atto._maps[mode] = atto._maps[mode] or {}
+    atto._maps[mode][keys] = action
+    table.insert(atto._new_maps, { mode, keys })
+end
+
+function atto.on(event, fn)
+    local hooks = atto._hooks[event]
+    if hooks == nil then
+        error("unknown event: " .. tostring(event))
+    end
+    table.insert(hooks, fn)
+end
+
+function atto.completer(fn)
+    table.insert(atto._completers, fn)
+end
+"#;
+
+pub fn install(lua: &Lua) -> rlua::Result<()> {
+    lua.load(PRELUDE).set_name("atto").exec()
+}
+
+fn api(lua: &Lua) -> rlua::Result<Table<'_>> {
+    lua.globals().get("atto")
+}
+
+fn hooks<'lua>(lua: &'lua Lua, event: &str) -> rlua::Result<Table<'lua>> {
+    api(lua)?.get::<_, Table>("_hooks")?.get(event)
+}
+
+impl Atto {
+    // Runs `f` with the `atto` table bound to this editor. Calls made while
+    // Lua is already running (a hook editing the buffer, say) are skipped
+    // rather than re-entering.
+    pub fn with_lua<R>(&mut self, f: impl FnOnce(&Lua) -> rlua::Result<R>) -> Option<R> {
+        let lua = self.lua.take()?;
+        let result = {
+            let editor = RefCell::new(&mut *self);
+            lua.scope(|scope| {
+                let atto = api(&lua)?;
+                atto.set("line_count", scope.create_function(|_, ()| {
+                    Ok(editor.borrow().doc().buffer.len_lines())
+                })?)?;
+                atto.set("get_line", scope.create_function(|_, line: usize| {
+                    let editor = editor.borrow();
+                    let buffer = &editor.doc().buffer;
+                    Ok((line >= 1 && line <= buffer.len_lines()).then(|| buffer.line(line - 1)))
+                })?)?;
+                atto.set("set_line", scope.create_function(|_, (line, text): (usize, String)| {
+                    let mut editor = editor.borrow_mut();
+                    if line < 1 || line > editor.doc().buffer.len_lines() {
+                        return Err(rlua::Error::RuntimeError(format!("line {} out of range", line)));
+                    }
+                    let start = editor.doc().buffer.line_to_char(line - 1);
+                    let end = start + editor.doc().buffer.line_len(line - 1);
+                    // One undo step, unless it is part of a larger one.
+                    let grouping = editor.doc().history.grouping();
+                    if !grouping {
+                        editor.doc_mut().history.group();
+                    }
+                    editor.remove_text(start..end);
+                    editor.insert_text(start, &text);
+                    if !grouping {
+                        editor.doc_mut().history.seal();
+                    }
+                    let cursor = (editor.cursor_y, editor.cursor_x);
+                    editor.set_cursor(cursor);
+                    Ok(())
+                })?)?;
+                atto.set("get_text", scope.create_function(|_, ()| {
+                    Ok(editor.borrow().doc().buffer.text())
+                })?)?;
+                atto.set("insert", scope.create_function(|_, text: String| {
+                    let mut editor = editor.borrow_mut();
+                    let pos = editor.doc().buffer.pos_to_char(editor.cursor_y, editor.cursor_x);
+                    editor.insert_text(pos, &text);
+                    let cursor = editor.doc().buffer.char_to_pos(pos + text.chars().count());
+                    editor.set_cursor(cursor);
+                    Ok(())
+                })?)?;
+                atto.set("get_cursor", scope.create_function(|_, ()| {
+                    let editor = editor.borrow();
+                    Ok((editor.cursor_y + 1, editor.cursor_x + 1))
+                })?)?;
+                atto.set("set_cursor", scope.create_function(|_, (line, col): (usize, usize)| {
+                    editor.borrow_mut().set_cursor((line.saturating_sub(1), col.saturating_sub(1)));
+                    Ok(())
+                })?)?;
+                atto.set("filename", scope.create_function(|_, ()| {
+                    Ok(editor.borrow().doc().filename.clone())
+                })?)?;
+                atto.set("message", scope.create_function(|_, text: String| {
+                    editor.borrow_mut().status_message = Some(text);
+                    Ok(())
+                })?)?;
+                atto.set("exec", scope.create_function(|lua, command: String| {
+                    let command = command.trim_start_matches(':');
+                    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+                    let commands: Table = api(lua)?.get("_commands")?;
+                    if let Some(plugin_command) = commands.get::<_, Option<Function>>(name)? {
+                        return plugin_command.call(arg.trim());
+                    }
+                    editor.borrow_mut().run_command(command);
+                    Ok(())
+                })?)?;
+                f(&lua)
+            })
+        };
+        self.lua = Some(lua);
+        self.sync_plugin_maps();
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.status_message = Some(format!("Lua error: {}", e));
+                None
+            }
+        }
+    }
+
+    pub fn load_plugins(&mut self, dir: &Path) {
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+                .collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        paths.sort();
+        for path in paths {
+            match fs::read_to_string(&path) {
+                Ok(source) => {
+                    let name = path.display().to_string();
+                    self.with_lua(|lua| lua.load(&source).set_name(name).exec());
+                }
+                Err(e) => self.status_message = Some(format!("Error loading {}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    // Calls every hook registered for `event`; true if any of them returned
+    // true. on_key fires on every key, so an event without hooks doesn't bind
+    // the editor at all.
+    pub fn fire(&mut self, event: &str, arg: String) -> bool {
+        let hooked = self.lua.as_ref().and_then(|lua| hooks(lua, event).ok()).is_some_and(|hooks| hooks.raw_len() > 0);
+        if !hooked {
+            return false;
+        }
+        self.with_lua(|lua| {
+            let mut handled = false;
+            for hook in hooks(lua, event)?.sequence_values::<Function>() {
+                let result: Value = hook?.call(arg.clone())?;
+                handled |= matches!(result, Value::Boolean(true));
+            }
+            Ok(handled)
+        })
+        .unwrap_or(false)
+    }
+
+    // Tells the on_change hooks about edits made since the last call.
+    pub fn fire_change(&mut self) {
+        if self.changed {
+            let name = self.doc().display_name();
+            self.fire("on_change", name);
+            self.changed = false;
+        }
+    }
+
+    // Some(true) if a plugin command ran, Some(false) if none is registered.
+    pub fn run_plugin_command(&mut self, name: &str, arg: &str) -> Option<bool> {
+        self.with_lua(|lua| {
+            let commands: Table = api(lua)?.get("_commands")?;
+            match commands.get::<_, Option<Function>>(name)? {
+                Some(command) => {
+                    command.call::<_, ()>(arg)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    // Moves bindings made with `atto.map` since the last call into the keymap.
+    pub fn sync_plugin_maps(&mut self) {
+        let maps = match self.lua.as_ref().map(new_maps) {
+            Some(Ok(maps)) => maps,
+            Some(Err(e)) => {
+                self.status_message = Some(format!("Lua error: {}", e));
+                return;
+            }
+            None => return,
+        };
+        for (mode, keys, binding) in maps {
+            if !self.keymap.bind(&mode, &keys, binding) {
+                self.status_message = Some(format!("Invalid key sequence: {}", keys));
+            }
+        }
+    }
+
+    // Candidates from the `atto.completer` functions for the word being typed:
+    // each returns a list of strings or of { label, insert, detail, snippet }.
+    pub fn plugin_completions(&mut self, prefix: &str) -> Vec<Item> {
+        self.with_lua(|lua| {
+            let mut items = Vec::new();
+            for completer in api(lua)?.get::<_, Table>("_completers")?.sequence_values::<Function>() {
+                let candidates: Option<Table> = completer?.call(prefix)?;
+                for candidate in candidates.into_iter().flat_map(|t| t.sequence_values::<Value>()) {
+                    let item = match candidate? {
+                        Value::String(label) => {
+                            let label = label.to_str()?.to_string();
+                            Item { text: label.clone(), label, detail: "plugin".to_string(), snippet: false }
+                        }
+                        Value::Table(table) => {
+                            let label: String = table.get("label")?;
+                            Item {
+                                text: table.get::<_, Option<String>>("insert")?.unwrap_or_else(|| label.clone()),
+                                label,
+                                detail: table.get::<_, Option<String>>("detail")?.unwrap_or_else(|| "plugin".to_string()),
+                                snippet: table.get::<_, Option<bool>>("snippet")?.unwrap_or(false),
+                            }
+                        }
+                        _ => continue,
+                    };
+                    items.push(item);
+                }
+            }
+            Ok(items)
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn run_plugin_map(&mut self, mode: &str, keys: &str) {
+        self.with_lua(|lua| {
+            let maps: Table = api(lua)?.get::<_, Table>("_maps")?.get(mode)?;
+            maps.get::<_, Function>(keys)?.call::<_, ()>(())
+        });
+    }
+}
+
+fn new_maps(lua: &Lua) -> rlua::Result<Vec<(String, String, Binding)>> {
+    let atto = api(lua)?;
+    let maps: Table = atto.get("_maps")?;
+    let mut bindings = Vec::new();
+    for entry in atto.get::<_, Table>("_new_maps")?.sequence_values::<Table>() {
+        let entry = entry?;
+        let (mode, keys): (String, String) = (entry.get(1)?, entry.get(2)?);
+        let binding = match maps.get::<_, Table>(mode.as_str())?.get::<_, Value>(keys.as_str())? {
+            Value::String(action) => Binding::Action(action.to_str()?.to_string()),
+            Value::Function(_) => Binding::Plugin(mode.clone(), keys.clone()),
+            _ => continue,
+        };
+        bindings.push((mode, keys, binding));
+    }
+    atto.set("_new_maps", lua.create_table()?)?;
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{env, fs, process};
+
+    use rlua::{Lua, Table};
+
+    use crate::Atto;
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    const PLUGIN: &str = r#"
+        events = {}
+        for _, event in ipairs({ "on_open", "on_save", "on_change" }) do
+            atto.on(event, function(name)
+                table.insert(events, event .. " " .. name:match("[^/]*$"))
+            end)
+        end
+        atto.on("on_key", function(key)
+            if key == "Z" then
+                atto.message("no Z")
+                return true
+            end
+        end)
+        atto.command("shout", function(arg)
+            atto.set_line(1, atto.get_line(1):upper() .. arg)
+        end)
+        atto.command("goto", function()
+            atto.set_cursor(2, 3)
+        end)
+        atto.map("normal", "<C-u>", function()
+            atto.insert("[" .. atto.line_count() .. "]")
+        end)
+        atto.map("normal", "Q", ":shout !")
+    "#;
+
+    fn with_plugin(text: &str) -> Atto {
+        let mut atto = Atto::scratch(text, true);
+        atto.with_lua(|lua| lua.load(PLUGIN).exec()).unwrap();
+        atto
+    }
+
+    fn events(atto: &mut Atto) -> Vec<String> {
+        atto.with_lua(|lua| {
+            let events: Table = lua.globals().get("events")?;
+            events.sequence_values().collect()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn buffer_functions() {
+        let mut atto = with_plugin("hello\nworld");
+        let line = atto.with_lua(|lua| lua.load("return atto.get_line(2), atto.get_line(3)").eval::<(String, Option<String>)>());
+        assert_eq!(line, Some(("world".to_string(), None)));
+        atto.type_keys(":goto<Enter>");
+        assert_eq!((atto.cursor_y, atto.cursor_x), (1, 2));
+        atto.type_keys(":shout ?<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "HELLO?\nworld");
+        // The cursor stays put, and the change is one undo.
+        assert_eq!((atto.cursor_y, atto.cursor_x), (1, 2));
+        atto.type_keys("u");
+        assert_eq!(atto.doc().buffer.text(), "hello\nworld");
+        atto.with_lua(|lua| lua.load("atto.set_line(3, 'x')").exec());
+        assert!(atto.status_message.as_deref().unwrap().contains("line 3 out of range"));
+    }
+
+    #[test]
+    fn maps() {
+        let mut atto = with_plugin("ab");
+        atto.type_keys("l<C-u>");
+        assert_eq!(atto.doc().buffer.text(), "a[1]b");
+        assert_eq!(atto.cursor_x, 4);
+        atto.type_keys("Q");
+        assert_eq!(atto.doc().buffer.text(), "A[1]B!");
+    }
+
+    #[test]
+    fn hooks() {
+        let dir = env::temp_dir().join(format!("atto-plugin-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hooked.txt");
+        fs::write(&path, "one\n").unwrap();
+        let mut atto = with_plugin("");
+        atto.edit_file(&path.display().to_string());
+        atto.type_keys("Ax<Esc>");
+        atto.fire_change();
+        // Nothing changed since.
+        atto.fire_change();
+        atto.type_keys(":w<Enter>");
+        assert_eq!(events(&mut atto), ["on_open hooked.txt", "on_change hooked.txt", "on_save hooked.txt"]);
+        // A hook returning true swallows the key.
+        atto.type_keys("Z");
+        assert_eq!(atto.status_message.as_deref(), Some("no Z"));
+        assert_eq!(atto.doc().buffer.text(), "onex");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unhooked_events_skip_lua() {
+        let mut atto = Atto::scratch("", true);
+        // Without the atto table, binding the editor would fail.
+        atto.lua = Some(Lua::new());
+        assert!(!atto.fire("on_key", "a".to_string()));
+        assert!(atto.status_message.is_none());
+    }
+}
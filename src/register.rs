@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use crate::clipboard::Clipboard;
+
+// Text yanked or deleted for a later put. Linewise text holds whole lines,
+// each ending in '\n', and is put on lines of its own.
+#[derive(Clone, Default)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool,
+}
+
+// Registers are named by a char as in vim: '"' is the unnamed register every
+// yank and delete goes to, '0' holds the last yank, '+' and '*' are the
+// system clipboard, and an upper case name appends to its lower case register.
+#[derive(Default)]
+pub struct Registers {
+    registers: HashMap<char, Register>,
+    pub clipboard: Clipboard,
+    // The unnamed register holds text the clipboard wasn't given, from a
+    // delete or a named register, so putting it doesn't ask the clipboard.
+    unsynced: bool,
+    error: Option<String>,
+}
+
+fn clipboard_register(name: char) -> bool {
+    name == '+' || name == '*'
+}
+
+impl Registers {
+    pub fn store(&mut self, name: Option<char>, register: Register, yank: bool) {
+        self.unsynced = true;
+        match name.filter(|&name| name != '"') {
+            Some(name) if name.is_ascii_uppercase() => {
+                let name = name.to_ascii_lowercase();
+                let mut appended = self.registers.remove(&name).unwrap_or_default();
+                if register.linewise && !appended.text.is_empty() && !appended.text.ends_with('\n') {
+                    appended.text.push('\n');
+                }
+                appended.text.push_str(&register.text);
+                appended.linewise |= register.linewise;
+                self.registers.insert(name, appended.clone());
+                self.registers.insert('"', appended);
+                return;
+            }
+            Some(name) if clipboard_register(name) => {
+                self.copy_to_clipboard(&register.text);
+                self.unsynced = false;
+                self.registers.insert('+', register.clone());
+            }
+            Some(name) => {
+                self.registers.insert(name, register.clone());
+            }
+            // Deletes stay out of the clipboard, to not run the helper on
+            // every `x`.
+            None if yank => {
+                self.registers.insert('0', register.clone());
+                if self.clipboard.unnamed {
+                    self.copy_to_clipboard(&register.text);
+                    self.unsynced = false;
+                }
+            }
+            None => {}
+        }
+        self.registers.insert('"', register);
+    }
+
+    // The clipboard wins over what was last stored when it has changed
+    // since, e.g. by copying in another program.
+    pub fn get(&mut self, name: Option<char>) -> Option<Register> {
+        let name = name.unwrap_or('"').to_ascii_lowercase();
+        let from_clipboard = clipboard_register(name) || (name == '"' && self.clipboard.unnamed && !self.unsynced);
+        let name = if clipboard_register(name) { '+' } else { name };
+        let stored = self.registers.get(&name).filter(|register| !register.text.is_empty()).cloned();
+        if !from_clipboard {
+            return stored;
+        }
+        match self.clipboard.paste() {
+            Some(Ok(text)) if !text.is_empty() && stored.as_ref().is_none_or(|stored| stored.text != text) => {
+                let linewise = text.ends_with('\n');
+                Some(Register { text, linewise })
+            }
+            Some(Err(e)) => {
+                self.error = Some(format!("Clipboard error: {}", e));
+                stored
+            }
+            _ => stored,
+        }
+    }
+
+    fn copy_to_clipboard(&mut self, text: &str) {
+        if let Err(e) = self.clipboard.copy(text) {
+            self.error = Some(format!("Clipboard error: {}", e));
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take().or_else(|| Some(format!("Clipboard error: {}", self.clipboard.take_error()?)))
+    }
+}
@@ -0,0 +1,542 @@
+use std::ops::Range;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use regex::{Regex, RegexBuilder};
+
+use crate::buffer::TextBuffer;
+use crate::motion;
+use crate::selection::Selection;
+use crate::{Atto, Mode, Prompt};
+
+#[derive(Clone)]
+pub struct Search {
+    pub regex: Regex,
+    pub forward: bool,
+    // Cleared by `:noh` until the next search.
+    pub highlight: bool,
+}
+
+// Where the cursor was and what the last search was when the prompt opened,
+// restored when it is cancelled.
+pub struct Incremental {
+    origin: (usize, usize),
+    previous: Option<Search>,
+}
+
+pub enum LineRange {
+    Current,
+    All,
+    Lines(Address, Address),
+}
+
+#[derive(Clone, Copy)]
+pub enum Address {
+    Current,
+    Last,
+    Line(usize),
+}
+
+// A parsed `:s/pattern/replacement/flags`.
+pub struct SubstituteCommand {
+    range: LineRange,
+    pattern: String,
+    replacement: String,
+    flags: String,
+}
+
+// An in-progress substitution; `line` and `col` are where to look for the
+// next match, `after_match` whether the last match ended there.
+pub struct Substitute {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+    confirm: bool,
+    line: usize,
+    col: usize,
+    after_match: bool,
+    last: usize,
+    count: usize,
+    lines: usize,
+    last_changed: Option<usize>,
+    current: Option<Found>,
+}
+
+struct Found {
+    line: usize,
+    cols: Range<usize>,
+    text: String,
+}
+
+// Smart case: a pattern without upper case letters ignores case unless told
+// otherwise.
+pub fn compile(pattern: &str, ignore_case: Option<bool>) -> Result<Regex, regex::Error> {
+    let ignore_case = ignore_case.unwrap_or(!pattern.chars().any(char::is_uppercase));
+    RegexBuilder::new(pattern).case_insensitive(ignore_case).build()
+}
+
+fn char_col(line: &str, byte: usize) -> usize {
+    line[..byte].chars().count()
+}
+
+fn byte_col(line: &str, col: usize) -> Option<usize> {
+    line.char_indices().map(|(i, _)| i).chain(std::iter::once(line.len())).nth(col)
+}
+
+// Char columns of the matches of `regex` in `line`, empty ones included.
+pub fn line_matches(regex: &Regex, line: &str) -> Vec<Range<usize>> {
+    let (mut byte, mut col) = (0, 0);
+    regex.find_iter(line).map(|m| {
+        col += line[byte..m.start()].chars().count();
+        byte = m.start();
+        col..col + m.as_str().chars().count()
+    }).collect()
+}
+
+// The next match after (or before) `(line, col)`, wrapping around the end of
+// the buffer; true along with it if it wrapped.
+fn find(buffer: &dyn TextBuffer, regex: &Regex, (line, col): (usize, usize), forward: bool) -> Option<((usize, Range<usize>), bool)> {
+    let count = buffer.len_lines();
+    for i in 0..=count {
+        let y = if forward { (line + i) % count } else { (line + count - i % count) % count };
+        let matches = line_matches(regex, &buffer.line(y));
+        let found = if forward {
+            matches.into_iter().find(|m| i > 0 || m.start > col)
+        } else {
+            matches.into_iter().rev().find(|m| i > 0 || m.start < col)
+        };
+        if let Some(m) = found {
+            let wrapped = if forward { line + i >= count } else { i > line };
+            return Some(((y, m), wrapped));
+        }
+    }
+    None
+}
+
+// Parses `[range]s/pattern/replacement/[flags]`, with any punctuation as the
+// delimiter. None if `input` is not a substitute command.
+pub fn parse_substitute(input: &str) -> Option<SubstituteCommand> {
+    let (range, rest) = parse_range(input);
+    let rest = rest.strip_prefix("substitute").or_else(|| rest.strip_prefix('s'))?;
+    let delimiter = rest.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '"' || delimiter == '\\' {
+        return None;
+    }
+    let mut parts = vec![String::new()];
+    let mut chars = rest[delimiter.len_utf8()..].chars();
+    while let Some(c) = chars.next() {
+        let last = parts.len() == 3;
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter && !last => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+    parts.resize(3, String::new());
+    let flags = parts.pop().unwrap().trim().to_string();
+    let replacement = parts.pop().unwrap();
+    let pattern = parts.pop().unwrap();
+    Some(SubstituteCommand { range, pattern, replacement, flags })
+}
+
+fn parse_range(input: &str) -> (LineRange, &str) {
+    if let Some(rest) = input.strip_prefix('%') {
+        return (LineRange::All, rest);
+    }
+    let (first, rest) = match parse_address(input) {
+        Some(parsed) => parsed,
+        None => return (LineRange::Current, input),
+    };
+    match rest.strip_prefix(',').and_then(parse_address) {
+        Some((last, rest)) => (LineRange::Lines(first, last), rest),
+        None => (LineRange::Lines(first, first), rest),
+    }
+}
+
+fn parse_address(input: &str) -> Option<(Address, &str)> {
+    if let Some(rest) = input.strip_prefix('.') {
+        return Some((Address::Current, rest));
+    }
+    if let Some(rest) = input.strip_prefix('$') {
+        return Some((Address::Last, rest));
+    }
+    let digits = input.len() - input.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let line = input[..digits].parse().ok()?;
+    Some((Address::Line(line), &input[digits..]))
+}
+
+// Turns vim's `&`, `\1` and `\n` in a replacement into regex's `${0}`, `${1}`
+// and a line break; a literal `$` has to be doubled.
+fn replacement(text: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => expanded.push_str(&format!("${{{}}}", d)),
+                Some('n' | 'r') => expanded.push('\n'),
+                Some('t') => expanded.push('\t'),
+                Some('$') => expanded.push_str("$$"),
+                Some(other) => expanded.push(other),
+                None => expanded.push('\\'),
+            },
+            '&' => expanded.push_str("${0}"),
+            '$' => expanded.push_str("$$"),
+            c => expanded.push(c),
+        }
+    }
+    expanded
+}
+
+fn confirm_message(found: &Found) -> String {
+    format!("replace with {} (y/n/a/q/l)?", found.text.replace('\n', "\\n"))
+}
+
+impl Substitute {
+    fn next(&self, buffer: &dyn TextBuffer) -> Option<Found> {
+        let (mut line, mut col, mut after_match) = (self.line, self.col, self.after_match);
+        while line <= self.last && line < buffer.len_lines() {
+            let text = buffer.line(line);
+            let byte = byte_col(&text, col);
+            if let Some(captures) = byte.and_then(|byte| self.regex.captures_at(&text, byte)) {
+                let m = captures.get(0).unwrap();
+                // As in vim, an empty match right where the last one ended
+                // doesn't count.
+                if after_match && m.is_empty() && Some(m.start()) == byte {
+                    col += 1;
+                    after_match = false;
+                    continue;
+                }
+                let mut expanded = String::new();
+                captures.expand(&self.replacement, &mut expanded);
+                let start = char_col(&text, m.start());
+                return Some(Found { line, cols: start..start + m.as_str().chars().count(), text: expanded });
+            }
+            line += 1;
+            col = 0;
+            after_match = false;
+        }
+        None
+    }
+
+    // Moves past a match that `inserted` now stands in for.
+    fn advance(&mut self, found: &Found, inserted: &str) {
+        let breaks = inserted.matches('\n').count();
+        self.last += breaks;
+        if !self.global {
+            self.line = found.line + breaks + 1;
+            self.col = 0;
+            return;
+        }
+        let tail = inserted.rsplit('\n').next().unwrap_or("").chars().count();
+        let start = if breaks > 0 { 0 } else { found.cols.start };
+        self.line = found.line + breaks;
+        self.col = start + tail + found.cols.is_empty() as usize;
+        self.after_match = !found.cols.is_empty();
+    }
+}
+
+impl Atto {
+    pub fn start_search(&mut self, forward: bool) {
+        self.prompt = Some(Prompt::Search(forward));
+        self.command_input.clear();
+        self.incremental = Some(Incremental { origin: (self.cursor_y, self.cursor_x), previous: self.search.clone() });
+    }
+
+    // Jumps to the first match of what has been typed so far.
+    pub fn update_search(&mut self, forward: bool) {
+        let origin = match &self.incremental {
+            Some(incremental) => incremental.origin,
+            None => return,
+        };
+        self.set_cursor(origin);
+        if self.command_input.is_empty() {
+            self.search = self.incremental.as_ref().and_then(|incremental| incremental.previous.clone());
+            return;
+        }
+        // An unfinished pattern like `foo(` keeps the last one that compiled.
+        if let Ok(regex) = compile(&self.command_input, None) {
+            self.search = Some(Search { regex, forward, highlight: true });
+            self.search_jump(forward);
+        }
+    }
+
+    pub fn cancel_search(&mut self) {
+        if let Some(incremental) = self.incremental.take() {
+            self.search = incremental.previous;
+            self.selections.clear();
+            self.set_cursor(incremental.origin);
+        }
+    }
+
+    pub fn finish_search(&mut self, forward: bool) {
+        let incremental = match self.incremental.take() {
+            Some(incremental) => incremental,
+            None => return,
+        };
+        let pattern = std::mem::take(&mut self.command_input);
+        if pattern.is_empty() {
+            // An empty pattern searches for the last one again.
+            self.search = incremental.previous.map(|search| Search { forward, highlight: true, ..search });
+            self.search_jump(forward);
+        } else if let Err(e) = compile(&pattern, None) {
+            self.search = incremental.previous;
+            self.set_cursor(incremental.origin);
+            self.status_message = Some(format!("Invalid pattern: {}", e));
+        }
+    }
+
+    // `n` searches in the direction of the last search, `N` the other way.
+    pub fn search_next(&mut self, reverse: bool) {
+        match self.search.as_mut() {
+            Some(search) => {
+                search.highlight = true;
+                let forward = search.forward != reverse;
+                self.search_jump(forward);
+            }
+            None => self.status_message = Some("No previous search".to_string()),
+        }
+    }
+
+    fn search_jump(&mut self, forward: bool) {
+        let regex = match &self.search {
+            Some(search) => search.regex.clone(),
+            None => return,
+        };
+        let buffer = self.doc().buffer.as_ref();
+        let ((line, cols), wrapped) = match find(buffer, &regex, (self.cursor_y, self.cursor_x), forward) {
+            Some(found) => found,
+            None => {
+                self.status_message = Some(format!("Pattern not found: {}", regex.as_str()));
+                return;
+            }
+        };
+        let start = buffer.pos_to_char(line, cols.start);
+        let end = buffer.pos_to_char(line, cols.end);
+        if wrapped {
+            self.status_message = Some(if forward { "Search wrapped to the top" } else { "Search wrapped to the bottom" }.to_string());
+        }
+        if self.kakoune() {
+            self.set_selections(vec![Selection { anchor: start, head: end.saturating_sub(1).max(start) }], 0);
+        } else if self.mode == Mode::Insert && end > start {
+            // The presets select the match, so typing replaces it.
+            self.selection_start = Some((line, cols.start));
+            self.set_cursor((line, cols.end));
+        } else {
+            self.set_cursor((line, cols.start));
+        }
+    }
+
+    pub fn substitute(&mut self, command: SubstituteCommand) {
+        let last_line = self.doc().buffer.len_lines() - 1;
+        let address = |address: Address| match address {
+            Address::Current => self.cursor_y,
+            Address::Last => last_line,
+            Address::Line(line) => line.saturating_sub(1).min(last_line),
+        };
+        let (first, last) = match command.range {
+            LineRange::Current => (self.cursor_y, self.cursor_y),
+            LineRange::All => (0, last_line),
+            LineRange::Lines(a, b) => (address(a).min(address(b)), address(a).max(address(b))),
+        };
+        let mut ignore_case = None;
+        for flag in command.flags.chars() {
+            match flag {
+                'g' | 'c' | 'n' => {}
+                'i' => ignore_case = Some(true),
+                'I' => ignore_case = Some(false),
+                _ => {
+                    self.status_message = Some(format!("Invalid flag: {}", flag));
+                    return;
+                }
+            }
+        }
+        let regex = if command.pattern.is_empty() {
+            match &self.search {
+                Some(search) => search.regex.clone(),
+                None => {
+                    self.status_message = Some("No previous search".to_string());
+                    return;
+                }
+            }
+        } else {
+            match compile(&command.pattern, ignore_case) {
+                Ok(regex) => regex,
+                Err(e) => {
+                    self.status_message = Some(format!("Invalid pattern: {}", e));
+                    return;
+                }
+            }
+        };
+        self.search = Some(Search { regex: regex.clone(), forward: true, highlight: true });
+        let global = command.flags.contains('g');
+        if command.flags.contains('n') {
+            let buffer = self.doc().buffer.as_ref();
+            let counts: Vec<usize> = (first..=last)
+                .map(|line| line_matches(&regex, &buffer.line(line)).len())
+                .filter(|&count| count > 0)
+                .collect();
+            let matches = if global { counts.iter().sum() } else { counts.len() };
+            self.status_message = Some(format!("{} matches on {} lines", matches, counts.len()));
+            return;
+        }
+        self.doc_mut().history.group();
+        self.substitution = Some(Substitute {
+            regex,
+            replacement: replacement(&command.replacement),
+            global,
+            confirm: command.flags.contains('c'),
+            line: first,
+            col: 0,
+            after_match: false,
+            last,
+            count: 0,
+            lines: 0,
+            last_changed: None,
+            current: None,
+        });
+        self.substitute_step();
+    }
+
+    // Replaces matches until one needs confirming or there are none left.
+    fn substitute_step(&mut self) {
+        while let Some(mut state) = self.substitution.take() {
+            let found = match state.next(self.doc().buffer.as_ref()) {
+                Some(found) => found,
+                None => return self.finish_substitute(state),
+            };
+            if state.confirm {
+                self.set_cursor((found.line, found.cols.start));
+                self.status_message = Some(confirm_message(&found));
+                state.current = Some(found);
+                self.substitution = Some(state);
+                return;
+            }
+            self.replace_found(&mut state, found);
+            self.substitution = Some(state);
+        }
+    }
+
+    fn replace_found(&mut self, state: &mut Substitute, found: Found) {
+        let start = self.doc().buffer.pos_to_char(found.line, found.cols.start);
+        self.replace_each(vec![(start..start + found.cols.len(), found.text.clone())]);
+        state.count += 1;
+        if state.last_changed != Some(found.line) {
+            state.lines += 1;
+        }
+        state.last_changed = Some(found.line + found.text.matches('\n').count());
+        state.advance(&found, &found.text);
+    }
+
+    fn finish_substitute(&mut self, state: Substitute) {
+        self.doc_mut().history.seal();
+        self.status_message = Some(if state.count == 0 {
+            format!("Pattern not found: {}", state.regex.as_str())
+        } else {
+            format!("{} substitutions on {} lines", state.count, state.lines)
+        });
+        if let (Some(line), false) = (state.last_changed, state.confirm) {
+            let x = motion::first_non_blank(&self.doc().buffer.line(line));
+            self.set_cursor((line, x));
+        }
+    }
+
+    // Answers the "replace with ...?" question of a confirmed substitution.
+    pub fn confirm_key(&mut self, key: KeyEvent) {
+        let mut state = match self.substitution.take() {
+            Some(state) => state,
+            None => return,
+        };
+        let found = match state.current.take() {
+            Some(found) => found,
+            None => return self.finish_substitute(state),
+        };
+        match key.code {
+            KeyCode::Char('y') => self.replace_found(&mut state, found),
+            KeyCode::Char('l') => {
+                self.replace_found(&mut state, found);
+                return self.finish_substitute(state);
+            }
+            KeyCode::Char('n') => {
+                let skipped = self.doc().buffer.line(found.line).chars()
+                    .skip(found.cols.start)
+                    .take(found.cols.len())
+                    .collect::<String>();
+                state.advance(&found, &skipped);
+            }
+            KeyCode::Char('a') => {
+                state.confirm = false;
+                self.replace_found(&mut state, found);
+            }
+            KeyCode::Char('q') | KeyCode::Esc => return self.finish_substitute(state),
+            _ => {
+                self.status_message = Some(confirm_message(&found));
+                state.current = Some(found);
+                self.substitution = Some(state);
+                return;
+            }
+        }
+        self.substitution = Some(state);
+        self.substitute_step();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let command = parse_substitute("s/a/b/g").unwrap();
+        assert!(matches!(command.range, LineRange::Current));
+        assert_eq!((command.pattern.as_str(), command.replacement.as_str(), command.flags.as_str()), ("a", "b", "g"));
+        let command = parse_substitute("%substitute#a/b#c\\#d").unwrap();
+        assert!(matches!(command.range, LineRange::All));
+        assert_eq!((command.pattern.as_str(), command.replacement.as_str(), command.flags.as_str()), ("a/b", "c#d", ""));
+        // Other escapes are left for the regex.
+        let command = parse_substitute("3,$s/\\d\\//x").unwrap();
+        assert!(matches!(command.range, LineRange::Lines(Address::Line(3), Address::Last)));
+        assert_eq!((command.pattern.as_str(), command.replacement.as_str()), ("\\d/", "x"));
+        let command = parse_substitute(".s/a").unwrap();
+        assert!(matches!(command.range, LineRange::Lines(Address::Current, Address::Current)));
+        assert_eq!((command.pattern.as_str(), command.replacement.as_str()), ("a", ""));
+        assert!(parse_substitute("set").is_none());
+        assert!(parse_substitute("s a b").is_none());
+        assert!(parse_substitute("w").is_none());
+    }
+
+    #[test]
+    fn replacements() {
+        assert_eq!(replacement("<&>\\1\\n$"), "<${0}>${1}\n$$");
+    }
+
+    fn substitute(text: &str, command: &str) -> String {
+        let mut atto = Atto::scratch(text, true);
+        atto.type_keys(&format!(":{}<Enter>", command));
+        atto.doc().buffer.text()
+    }
+
+    #[test]
+    fn empty_matches() {
+        assert_eq!(substitute("abc", "s/x*/-/g"), "-a-b-c-");
+        assert_eq!(substitute("abbc", "s/b*/-/g"), "-a-c-");
+        assert_eq!(substitute("one\ntwo", "%s/^/# /"), "# one\n# two");
+        assert_eq!(substitute("one\ntwo", "%s/$/;/g"), "one;\ntwo;");
+        assert_eq!(substitute("a b", "s/\\b/|/g"), "|a| |b|");
+    }
+
+    #[test]
+    fn substitutes() {
+        assert_eq!(substitute("aaa\naaa", "s/a/b/"), "baa\naaa");
+        assert_eq!(substitute("aaa\naaa", "%s/a/bb/g"), "bbbbbb\nbbbbbb");
+        assert_eq!(substitute("a,b", "s/,/\\n/"), "a\nb");
+        assert_eq!(substitute("ab ab", "s/(a)b/&\\1/g"), "aba aba");
+    }
+}
@@ -0,0 +1,296 @@
+use std::ops::Range;
+
+use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
+
+use crate::register::Register;
+use crate::unicode;
+use crate::vim::Operator;
+use crate::{Atto, Mode, Visual};
+
+// A selection covers the chars from `anchor` to `head` inclusive, in either
+// order; `head` is where the cursor sits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Selection {
+    pub anchor: usize,
+    pub head: usize,
+}
+
+impl Selection {
+    pub fn at(pos: usize) -> Self {
+        Self { anchor: pos, head: pos }
+    }
+
+    pub fn start(&self) -> usize {
+        self.anchor.min(self.head)
+    }
+
+    // One past the last selected char.
+    pub fn end(&self) -> usize {
+        self.anchor.max(self.head) + 1
+    }
+}
+
+// Sorts `selections` and merges overlapping ones, returning where the
+// selection that was at `primary` ended up.
+pub fn normalize(selections: &mut Vec<Selection>, primary: usize) -> usize {
+    let head = selections[primary].head;
+    selections.sort_by_key(|s| s.start());
+    let mut merged: Vec<Selection> = Vec::with_capacity(selections.len());
+    let mut primary = 0;
+    for selection in selections.drain(..) {
+        match merged.last_mut() {
+            Some(last) if selection.start() < last.end() => {
+                let end = last.end().max(selection.end()) - 1;
+                *last = Selection { anchor: last.start(), head: end };
+            }
+            _ => merged.push(selection),
+        }
+        if selection.head == head {
+            primary = merged.len() - 1;
+        }
+    }
+    *selections = merged;
+    primary
+}
+
+impl Atto {
+    // Char ranges of the visual or shift selection: one per line for a block,
+    // whole lines without the last line break for a linewise one. Visual
+    // selections include the char under the cursor, shift selections stop
+    // short of it.
+    pub fn selected_ranges(&self) -> Vec<Range<usize>> {
+        let buffer = self.doc().buffer.as_ref();
+        let (anchor_y, anchor_x) = match self.selection_start {
+            Some((y, x)) => {
+                let y = y.min(buffer.len_lines() - 1);
+                (y, x.min(buffer.line_len(y)))
+            }
+            None => return Vec::new(),
+        };
+        let (first, last) = (anchor_y.min(self.cursor_y), anchor_y.max(self.cursor_y));
+        let anchor = buffer.pos_to_char(anchor_y, anchor_x);
+        let cursor = self.cursor_char();
+        let (start, end) = (anchor.min(cursor), anchor.max(cursor));
+        let range = match self.mode {
+            Mode::Visual(Visual::Char) => start..(end + 1).min(buffer.len_chars()),
+            Mode::Visual(Visual::Line) => buffer.line_to_char(first)..buffer.line_to_char(last) + buffer.line_len(last),
+            Mode::Visual(Visual::Block) => {
+                let anchor_col = unicode::display_col(&buffer.line(anchor_y), anchor_x);
+                let (left, right) = (anchor_col.min(self.display_x()), anchor_col.max(self.display_x()));
+                return (first..=last).filter_map(|y| {
+                    let line = buffer.line(y);
+                    let len = line.chars().count();
+                    if unicode::display_col(&line, len) < left {
+                        return None;
+                    }
+                    let line_start = buffer.line_to_char(y);
+                    let start = unicode::col_at_display(&line, left);
+                    let end = unicode::next_grapheme(&line, unicode::col_at_display(&line, right));
+                    Some(line_start + start..line_start + end.max(start))
+                }).collect();
+            }
+            _ => start..end,
+        };
+        vec![range]
+    }
+
+    // Drops the visual or shift selection, leaving visual mode.
+    pub fn clear_selection(&mut self) {
+        self.selection_start = None;
+        if let Mode::Visual(_) = self.mode {
+            self.mode = Mode::Normal;
+        }
+    }
+
+    // Starts a visual selection of the given kind, switches to it from
+    // another kind, or ends it when it is already active.
+    pub fn toggle_visual(&mut self, visual: Visual) {
+        match self.mode {
+            Mode::Visual(current) if current == visual => self.clear_selection(),
+            Mode::Visual(_) => self.mode = Mode::Visual(visual),
+            _ => {
+                self.selection_start = Some((self.cursor_y, self.cursor_x));
+                self.mode = Mode::Visual(visual);
+            }
+        }
+    }
+
+    // Extends the selection with a cursor movement, starting one at the
+    // cursor if there is none.
+    pub fn select_with(&mut self, movement: impl FnOnce(&mut Atto)) {
+        if self.selection_start.is_none() {
+            self.selection_start = Some((self.cursor_y, self.cursor_x));
+            if self.mode == Mode::Normal {
+                self.mode = Mode::Visual(Visual::Char);
+            }
+        }
+        movement(self);
+    }
+
+    // Removes the shift selection before typing over it; false if there was
+    // nothing to remove.
+    pub fn delete_selection(&mut self) -> bool {
+        if self.mode != Mode::Insert {
+            return false;
+        }
+        let range = match self.selected_ranges().pop() {
+            Some(range) if !range.is_empty() => range,
+            _ => return false,
+        };
+        self.selection_start = None;
+        self.remove_text(range.clone());
+        self.set_cursor_char(range.start);
+        true
+    }
+
+    // Copies (or cuts) the selection into the unnamed register; without a
+    // selection the current line is taken.
+    pub fn copy(&mut self, cut: bool) {
+        if let Mode::Visual(_) = self.mode {
+            return self.visual_operate(if cut { Operator::Delete } else { Operator::Yank }, None);
+        }
+        let buffer = self.doc().buffer.as_ref();
+        let selected = self.selected_ranges().pop().filter(|range| !range.is_empty());
+        let register = match &selected {
+            Some(range) => Register { text: buffer.slice(range.clone()), linewise: false },
+            None => Register { text: buffer.line(self.cursor_y) + "\n", linewise: true },
+        };
+        // Cutting is how the presets move text elsewhere, so it goes to the
+        // clipboard as a yank would.
+        self.registers.store(None, register, true);
+        self.selection_start = None;
+        if cut {
+            self.doc_mut().history.seal();
+            match selected {
+                Some(range) => {
+                    self.remove_text(range.clone());
+                    self.set_cursor_char(range.start);
+                }
+                None => self.delete_lines(self.cursor_y, self.cursor_y),
+            }
+            self.doc_mut().history.seal();
+        }
+    }
+
+    // Pastes the unnamed register over the selection, or at the cursor.
+    // Linewise text goes in above the cursor line.
+    pub fn paste(&mut self) {
+        match self.mode {
+            Mode::Visual(_) => return self.visual_put(None),
+            Mode::Normal => return self.put(false, 1, None),
+            Mode::Insert => {}
+        }
+        let register = match self.registers.get(None) {
+            Some(register) => register,
+            None => return,
+        };
+        self.doc_mut().history.group();
+        self.delete_selection();
+        if register.linewise {
+            let y = self.cursor_y;
+            let pos = self.doc().buffer.line_to_char(y);
+            self.insert_text(pos, &register.text);
+            self.set_cursor((y + register.text.matches('\n').count(), self.cursor_x));
+        } else {
+            let pos = self.cursor_char();
+            self.insert_text(pos, &register.text);
+            self.set_cursor_char(pos + register.text.chars().count());
+        }
+        self.doc_mut().history.seal();
+    }
+
+    // Buffer position under a screen cell of the focused window.
+    fn mouse_position(&self, column: u16, row: u16) -> (usize, usize) {
+        if self.wrap.enabled {
+            return self.wrapped_mouse_position(column, row);
+        }
+        let area = self.windows[self.focus].area;
+        let buffer = self.doc().buffer.as_ref();
+        let y = (row.saturating_sub(area.y + self.cursor_offset_y) as usize + self.scroll_offset).min(buffer.len_lines() - 1);
+        let x = column.saturating_sub(area.x + self.gutter_width()) as usize + self.horizontal_scroll_offset;
+        (y, unicode::col_at_display(&buffer.line(y), x))
+    }
+
+    // A click focuses the window under it and places the cursor; dragging
+    // selects from there.
+    pub fn handle_mouse(&mut self, event: MouseEvent) {
+        // The popup and the snippet's placeholders belong to where the cursor
+        // was.
+        if matches!(event.kind, MouseEventKind::Down(_) | MouseEventKind::ScrollUp | MouseEventKind::ScrollDown) {
+            self.completion = None;
+            self.snippet = None;
+        }
+        match event.kind {
+            MouseEventKind::ScrollUp => self.scroll_up(),
+            MouseEventKind::ScrollDown => self.scroll_down(),
+            MouseEventKind::Down(MouseButton::Left) => {
+                let (column, row) = (event.column, event.row);
+                if self.explorer_click(column, row) {
+                    return;
+                }
+                let index = self.windows.iter().position(|window| {
+                    let area = window.area;
+                    column >= area.x && column < area.x + area.width && row >= area.y && row < area.y + area.height
+                });
+                let index = match index {
+                    Some(index) => index,
+                    None => return,
+                };
+                if let Some(explorer) = self.explorer.as_mut() {
+                    explorer.focused = false;
+                }
+                self.selections.clear();
+                self.clear_selection();
+                self.focus_window(index);
+                let position = self.mouse_position(column, row);
+                self.set_cursor(position);
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                let position = self.mouse_position(event.column, event.row);
+                if self.kakoune() {
+                    let anchor = self.selections.get(self.primary).map_or(self.cursor_char(), |s| s.anchor);
+                    let head = self.doc().buffer.pos_to_char(position.0, position.1);
+                    self.set_selections(vec![Selection { anchor, head }], 0);
+                } else {
+                    self.select_with(|atto| atto.set_cursor(position));
+                }
+            }
+            _ => return,
+        }
+        if let (Mode::Normal | Mode::Visual(_), false) = (self.mode, self.kakoune()) {
+            self.clamp_to_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(anchor: usize, head: usize) -> Selection {
+        Selection { anchor, head }
+    }
+
+    #[test]
+    fn normalize_sorts() {
+        let mut selections = vec![selection(8, 6), selection(0, 2), selection(4, 4)];
+        assert_eq!(normalize(&mut selections, 0), 2);
+        assert_eq!(selections, vec![selection(0, 2), selection(4, 4), selection(8, 6)]);
+    }
+
+    #[test]
+    fn normalize_merges() {
+        // Overlapping ones merge, ones that only touch don't.
+        let mut selections = vec![selection(0, 3), selection(5, 2), selection(6, 7), selection(9, 9)];
+        assert_eq!(normalize(&mut selections, 1), 0);
+        assert_eq!(selections, vec![selection(0, 5), selection(6, 7), selection(9, 9)]);
+        // A selection inside another keeps the outer one's end.
+        let mut selections = vec![selection(2, 3), selection(0, 9), selection(12, 12)];
+        assert_eq!(normalize(&mut selections, 2), 1);
+        assert_eq!(selections, vec![selection(0, 9), selection(12, 12)]);
+        // Cursors on the same char become one.
+        let mut selections = vec![selection(4, 4), selection(4, 4)];
+        assert_eq!(normalize(&mut selections, 1), 0);
+        assert_eq!(selections, vec![selection(4, 4)]);
+    }
+}
@@ -0,0 +1,366 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use tui::layout::Direction;
+
+use crate::buffer::RopeBuffer;
+use crate::document::Document;
+use crate::{Atto, Prompt};
+
+// Edits reach the swap file once typing pauses for this long.
+const SWAP_INTERVAL: Duration = Duration::from_secs(2);
+const HEADER: &str = "atto swap";
+
+// Swap files hold the text of modified documents under
+// ~/.local/state/atto, so it can be recovered when atto didn't exit cleanly.
+pub struct Swaps {
+    // Where the swap files go, if there is anywhere.
+    pub dir: Option<PathBuf>,
+    last_write: Instant,
+    // Files found with a swap file, waiting to be asked about.
+    recoveries: Vec<String>,
+    failed: bool,
+}
+
+impl Default for Swaps {
+    fn default() -> Self {
+        Self { dir: swap_dir(), last_write: Instant::now(), recoveries: Vec::new(), failed: false }
+    }
+}
+
+fn swap_dir() -> Option<PathBuf> {
+    let state = dirs::state_dir().or_else(|| dirs::home_dir().map(|home| home.join(".local/state")))?;
+    Some(state.join("atto"))
+}
+
+// The swap file of a file is named after its absolute path, with '/' as '%'.
+fn swap_path(dir: &Path, filename: &str) -> Option<PathBuf> {
+    let path = fs::canonicalize(filename).or_else(|_| env::current_dir().map(|dir| dir.join(filename))).ok()?;
+    let name = path.display().to_string().replace('%', "%%").replace('/', "%");
+    Some(dir.join(name + ".swp"))
+}
+
+// The process that wrote a swap file and the text it had.
+fn read_swap(path: &Path) -> Option<(u32, String)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let rest = contents.strip_prefix(HEADER)?.strip_prefix(' ')?;
+    let (pid, text) = rest.split_once('\n')?;
+    Some((pid.parse().ok()?, text.to_string()))
+}
+
+fn running(pid: u32) -> bool {
+    pid == process::id() || (cfg!(target_os = "linux") && Path::new("/proc").join(pid.to_string()).exists())
+}
+
+// `diff -u` of the file against `text`, labelled as the file's `label`.
+fn diff(filename: &str, text: &str, label: &str) -> std::io::Result<String> {
+    let mut child = Command::new("diff")
+        .args(["-u", "--label", filename, "--label", &format!("{} ({})", filename, label), "--", filename, "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    let output = child.wait_with_output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl Atto {
+    fn swap_path(&self, filename: &str) -> Option<PathBuf> {
+        swap_path(self.swaps.dir.as_deref()?, filename)
+    }
+
+    // Notes a swap file left behind for the document at `index`, to offer
+    // recovering it.
+    pub fn check_swap(&mut self, index: usize) {
+        let filename = match self.documents[index].filename.clone() {
+            Some(filename) => filename,
+            None => return,
+        };
+        let path = match self.swap_path(&filename) {
+            Some(path) if path.exists() => path,
+            _ => return,
+        };
+        match read_swap(&path) {
+            Some((pid, _)) if running(pid) => {
+                self.status_message = Some(format!("{} is also being edited by atto (pid {})", filename, pid));
+            }
+            // Nothing that isn't already in the file.
+            Some((_, text)) if text == self.documents[index].buffer.text() => {
+                let _ = fs::remove_file(&path);
+            }
+            _ => self.swaps.recoveries.push(filename),
+        }
+    }
+
+    // Asks about the next file with a swap file, once nothing else is asked.
+    pub fn offer_recovery(&mut self) {
+        if self.prompt.is_some() || self.picker.is_some() {
+            return;
+        }
+        if self.swaps.recoveries.is_empty() {
+            return;
+        }
+        let filename = self.swaps.recoveries.remove(0);
+        if let Some(index) = self.documents.iter().position(|d| d.filename.as_deref() == Some(filename.as_str())) {
+            self.switch_buffer(index);
+            self.prompt = Some(Prompt::Recover(filename));
+            self.command_input.clear();
+        }
+    }
+
+    // Anything but recovering or discarding leaves the swap file to be
+    // offered again the next time the file is opened.
+    pub fn answer_recovery(&mut self, filename: String, answer: &str) {
+        let path = match self.swap_path(&filename) {
+            Some(path) => path,
+            None => return,
+        };
+        let text = match read_swap(&path) {
+            Some((_, text)) => text,
+            None => {
+                self.status_message = Some(format!("Could not read the swap file of {}", filename));
+                return;
+            }
+        };
+        match answer.trim() {
+            "r" | "recover" => {
+                let len = self.doc().buffer.len_chars();
+                self.doc_mut().history.group();
+                self.remove_text(0..len);
+                self.insert_text(0, &text);
+                self.doc_mut().history.seal();
+                // Written again, as this editor's, with the next swaps.
+                let _ = fs::remove_file(&path);
+                self.set_cursor(self.doc().cursor);
+                self.status_message = Some(format!("Recovered {}; save it to keep the changes", filename));
+            }
+            "d" | "discard" => {
+                let _ = fs::remove_file(&path);
+            }
+            // The question comes back after the diff.
+            "v" | "diff" => {
+                if !self.show_diff(&filename, text, "swap") {
+                    return;
+                }
+                self.swaps.recoveries.insert(0, filename);
+            }
+            _ => {}
+        }
+    }
+
+    // Opens the differences between the file on disk and `text`, meant for the
+    // current document, in a split next to it; false if diff couldn't run.
+    pub fn show_diff(&mut self, filename: &str, text: String, label: &str) -> bool {
+        let text = text + if self.doc().final_newline { "\n" } else { "" };
+        match diff(filename, &text, label) {
+            Ok(diff) => {
+                let previous = self.focus;
+                let mut document = Document::new(None);
+                document.buffer = Box::new(RopeBuffer::from_lines(diff.lines()));
+                self.documents.push(document);
+                self.split_window(Direction::Horizontal);
+                self.switch_buffer(self.documents.len() - 1);
+                self.focus_window(previous);
+                true
+            }
+            Err(e) => {
+                self.status_message = Some(format!("Could not run diff: {}", e));
+                false
+            }
+        }
+    }
+
+    // Writes the text of every document edited since its last swap, at most
+    // every SWAP_INTERVAL unless `now`. Ones undone back to the saved text
+    // need their swap file no more.
+    pub fn write_swaps(&mut self, now: bool) {
+        if !now && self.swaps.last_write.elapsed() < SWAP_INTERVAL {
+            return;
+        }
+        self.swaps.last_write = Instant::now();
+        let mut error = None;
+        for index in 0..self.documents.len() {
+            let document = &self.documents[index];
+            if !document.swap_pending {
+                continue;
+            }
+            if !document.modified() {
+                self.remove_swap(index);
+                continue;
+            }
+            let path = match document.filename.as_deref().and_then(|filename| self.swap_path(filename)) {
+                Some(path) => path,
+                None => continue,
+            };
+            let document = &mut self.documents[index];
+            let contents = format!("{} {}\n{}", HEADER, process::id(), document.buffer.text());
+            let result = fs::create_dir_all(path.parent().unwrap()).and_then(|()| fs::write(&path, contents));
+            match result {
+                Ok(()) => document.swap_pending = false,
+                Err(e) => error = Some(format!("Could not write swap file {}: {}", path.display(), e)),
+            }
+        }
+        // Said once rather than every time typing pauses.
+        if let (Some(error), false) = (error, self.swaps.failed) {
+            self.status_message = Some(error);
+            self.swaps.failed = true;
+        }
+    }
+
+    // How long until the pending edits are due in the swap files, if any are.
+    pub fn swap_timeout(&self) -> Option<Duration> {
+        let pending = self.documents.iter().any(|d| d.swap_pending && d.filename.is_some());
+        pending.then(|| SWAP_INTERVAL.saturating_sub(self.swaps.last_write.elapsed()))
+    }
+
+    // Removes the document's swap file once it is saved, unless another atto
+    // wrote it or it is still to be recovered.
+    pub fn remove_swap(&mut self, index: usize) {
+        self.documents[index].swap_pending = false;
+        if let Some(path) = self.documents[index].filename.as_deref().and_then(|filename| self.swap_path(filename)) {
+            if read_swap(&path).is_some_and(|(pid, _)| pid == process::id()) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    // On a clean exit nothing is left to recover.
+    pub fn remove_swaps(&mut self) {
+        for index in 0..self.documents.len() {
+            self.remove_swap(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{env, fs, process};
+
+    use super::{read_swap, running, swap_path, HEADER};
+    use crate::document::Document;
+    use crate::{Atto, Prompt};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+    // No process has this id.
+    const DEAD: u32 = u32::MAX;
+
+    // A file holding `text` and a swap directory, both in a directory of their
+    // own.
+    fn files(text: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("atto-swap-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(dir.join("swaps")).unwrap();
+        fs::write(dir.join("file.txt"), text).unwrap();
+        dir
+    }
+
+    fn editing(dir: &Path) -> Atto {
+        let mut atto = Atto::scratch("", true);
+        atto.documents[0] = Document::open(&dir.join("file.txt").display().to_string()).unwrap();
+        atto.swaps.dir = Some(dir.join("swaps"));
+        atto
+    }
+
+    fn swap_of(dir: &Path) -> PathBuf {
+        swap_path(&dir.join("swaps"), &dir.join("file.txt").display().to_string()).unwrap()
+    }
+
+    // An editor opening the file after one editing it as `text` was killed.
+    fn crashed(dir: &Path, text: &str) -> Atto {
+        fs::write(swap_of(dir), format!("{} {}\n{}", HEADER, DEAD, text)).unwrap();
+        let mut atto = editing(dir);
+        atto.check_swap(0);
+        atto.offer_recovery();
+        atto
+    }
+
+    #[test]
+    fn paths() {
+        let path = swap_path(Path::new("/swaps"), "/no/such%dir/file.txt").unwrap();
+        assert_eq!(path, Path::new("/swaps/%no%such%%dir%file.txt.swp"));
+    }
+
+    #[test]
+    fn headers() {
+        let dir = files("");
+        let path = dir.join("header");
+        for (contents, expected) in [
+            ("atto swap 12\none\ntwo", Some((12, "one\ntwo"))),
+            ("atto swap 12\n", Some((12, ""))),
+            ("atto swap\none", None),
+            ("atto swap x\none", None),
+            ("atto swapped 12\none", None),
+            ("atto swap 12", None),
+        ] {
+            fs::write(&path, contents).unwrap();
+            let swap = read_swap(&path);
+            assert_eq!(swap.as_ref().map(|(pid, text)| (*pid, text.as_str())), expected, "{:?}", contents);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_swaps() {
+        assert!(running(process::id()));
+        assert!(!running(DEAD));
+    }
+
+    #[test]
+    fn recover() {
+        let dir = files("one\ntwo\n");
+        let mut atto = editing(&dir);
+        atto.type_keys("jAx<Esc>");
+        atto.write_swaps(true);
+        let (pid, text) = read_swap(&swap_of(&dir)).unwrap();
+        assert_eq!((pid, text.as_str()), (process::id(), "one\ntwox"));
+        // Another editor is still at it.
+        let mut other = editing(&dir);
+        other.check_swap(0);
+        other.offer_recovery();
+        assert!(other.prompt.is_none());
+        assert!(other.status_message.as_deref().unwrap().contains("also being edited"));
+
+        let mut atto = crashed(&dir, "one\ntwox");
+        assert!(matches!(atto.prompt, Some(Prompt::Recover(_))));
+        atto.type_keys("r<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwox");
+        assert!(atto.doc().modified());
+        assert!(!swap_of(&dir).exists());
+        // Back to the text in the file with one undo.
+        atto.type_keys("u");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwo");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discard() {
+        let dir = files("one\ntwo\n");
+        let mut atto = crashed(&dir, "one\ntwox");
+        atto.type_keys("d<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwo");
+        assert!(!swap_of(&dir).exists());
+        // A swap file with nothing new in it goes without asking.
+        let atto = crashed(&dir, "one\ntwo");
+        assert!(atto.prompt.is_none());
+        assert!(!swap_of(&dir).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn clean_exit() {
+        let dir = files("one\n");
+        let mut atto = editing(&dir);
+        atto.type_keys("Ax<Esc>");
+        atto.write_swaps(true);
+        assert!(swap_of(&dir).exists());
+        atto.remove_swaps();
+        assert!(!swap_of(&dir).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use std::ops::Range;
+
+use tui::style::Style;
+use tui::text::Span;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub const TAB_WIDTH: usize = 4;
+
+// Cursor columns are char indices into a line that always sit on a grapheme
+// cluster boundary; screen columns are display widths.
+
+pub fn grapheme_width(grapheme: &str) -> usize {
+    if grapheme == "\t" {
+        TAB_WIDTH
+    } else {
+        grapheme.width()
+    }
+}
+
+pub fn next_grapheme(line: &str, col: usize) -> usize {
+    let mut chars = 0;
+    for grapheme in line.graphemes(true) {
+        chars += grapheme.chars().count();
+        if chars > col {
+            return chars;
+        }
+    }
+    chars
+}
+
+pub fn prev_grapheme(line: &str, col: usize) -> usize {
+    let mut chars = 0;
+    let mut prev = 0;
+    for grapheme in line.graphemes(true) {
+        if chars >= col {
+            break;
+        }
+        prev = chars;
+        chars += grapheme.chars().count();
+    }
+    prev
+}
+
+// Start of the grapheme containing `col`.
+pub fn grapheme_start(line: &str, col: usize) -> usize {
+    let mut chars = 0;
+    for grapheme in line.graphemes(true) {
+        let next = chars + grapheme.chars().count();
+        if next > col {
+            break;
+        }
+        chars = next;
+    }
+    chars
+}
+
+pub fn display_col(line: &str, col: usize) -> usize {
+    let mut chars = 0;
+    let mut width = 0;
+    for grapheme in line.graphemes(true) {
+        if chars >= col {
+            break;
+        }
+        chars += grapheme.chars().count();
+        width += grapheme_width(grapheme);
+    }
+    width
+}
+
+// Char column of the grapheme covering `target` screen columns in.
+pub fn col_at_display(line: &str, target: usize) -> usize {
+    let mut chars = 0;
+    let mut width = 0;
+    for grapheme in line.graphemes(true) {
+        let w = grapheme_width(grapheme);
+        if width + w > target {
+            break;
+        }
+        chars += grapheme.chars().count();
+        width += w;
+    }
+    chars
+}
+
+// Lays out `width` screen columns of `line` starting `skip` columns in, as
+// spans styled by whichever `styles` ranges (char columns) cover each
+// grapheme; later ranges are patched over earlier ones. Wide graphemes split
+// by either edge are padded with spaces.
+pub fn visible_spans(line: &str, skip: usize, width: usize, styles: &[(Range<usize>, Style)]) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut text = String::new();
+    let mut current = Style::default();
+    let mut pos = 0;
+    let mut col = 0;
+    for grapheme in line.graphemes(true) {
+        let w = grapheme_width(grapheme);
+        let (start, char_col) = (pos, col);
+        pos += w;
+        col += grapheme.chars().count();
+        if pos <= skip {
+            continue;
+        }
+        if start >= skip + width {
+            break;
+        }
+        let style = styles
+            .iter()
+            .filter(|(range, _)| range.contains(&char_col))
+            .fold(Style::default(), |style, (_, s)| style.patch(*s));
+        if style != current && !text.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut text), current));
+        }
+        current = style;
+        if start < skip || pos > skip + width {
+            let shown = pos.min(skip + width) - start.max(skip);
+            text.push_str(&" ".repeat(shown));
+        } else if grapheme == "\t" {
+            text.push_str(&" ".repeat(TAB_WIDTH));
+        } else {
+            text.push_str(grapheme);
+        }
+    }
+    if !text.is_empty() {
+        spans.push(Span::styled(text, current));
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use tui::style::{Color, Modifier, Style};
+
+    use super::*;
+
+    // A CJK char and an emoji two columns wide, and an 'e' with a combining
+    // accent: one column, two chars.
+    const LINE: &str = "a中b🦀e\u{301}x";
+
+    fn shown(spans: &[Span]) -> String {
+        spans.iter().map(|span| span.content.as_ref()).collect()
+    }
+
+    #[test]
+    fn graphemes() {
+        assert_eq!(next_grapheme(LINE, 4), 6);
+        assert_eq!(next_grapheme(LINE, 6), 7);
+        assert_eq!(next_grapheme(LINE, 7), 7);
+        assert_eq!(prev_grapheme(LINE, 6), 4);
+        assert_eq!(prev_grapheme(LINE, 0), 0);
+        assert_eq!(grapheme_start(LINE, 5), 4);
+        assert_eq!(grapheme_start(LINE, 6), 6);
+    }
+
+    #[test]
+    fn display_columns() {
+        assert_eq!(display_col(LINE, 2), 3);
+        assert_eq!(display_col(LINE, 4), 6);
+        assert_eq!(display_col(LINE, 6), 7);
+        assert_eq!(display_col(LINE, 7), 8);
+        assert_eq!(display_col("\tx", 1), TAB_WIDTH);
+        // The second column of a wide char is still that char.
+        assert_eq!(col_at_display(LINE, 2), 1);
+        assert_eq!(col_at_display(LINE, 3), 2);
+        assert_eq!(col_at_display(LINE, 5), 3);
+        assert_eq!(col_at_display(LINE, 6), 4);
+        assert_eq!(col_at_display(LINE, 7), 6);
+        assert_eq!(col_at_display(LINE, 100), 7);
+    }
+
+    #[test]
+    fn spans() {
+        assert_eq!(shown(&visible_spans(LINE, 0, 100, &[])), LINE);
+        // Wide chars cut by the left or the right edge become spaces.
+        assert_eq!(shown(&visible_spans(LINE, 2, 3, &[])), " b ");
+        assert_eq!(shown(&visible_spans(LINE, 5, 3, &[])), " e\u{301}x");
+        assert_eq!(shown(&visible_spans("\tx", 2, 4, &[])), "  x");
+
+        let red = Style::default().fg(Color::Red);
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let spans = visible_spans(LINE, 2, 4, &[(1..3, red), (2..3, bold)]);
+        let styled: Vec<(&str, Style)> = spans.iter().map(|span| (span.content.as_ref(), span.style)).collect();
+        assert_eq!(styled, [(" ", red), ("b", red.patch(bold)), ("🦀", Style::default())]);
+    }
+}
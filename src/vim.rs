@@ -0,0 +1,801 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+
+use crate::keymap::{Binding, Key};
+use crate::motion;
+use crate::register::Register;
+use crate::unicode;
+use crate::{Atto, Mode, Visual};
+
+// Counts are capped here, so a stray run of digits can't have a command
+// repeat for ages.
+const MAX_COUNT: usize = 10_000;
+// Nor can a counted put fill memory.
+const MAX_PUT: usize = 64 << 20;
+
+// Actions bound in the keymap that a count typed before their key repeats,
+// as in `3u` or `2n`; other bindings run once.
+const COUNTED: &[&str] = &[
+    "undo", "redo", "search_next", "search_prev", "move_up", "move_down", "move_left", "move_right",
+    "page_up", "page_down", "next_bookmark", "prev_bookmark", "quickfix_next", "quickfix_prev",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FileStart,
+    FileEnd,
+    Find(char),
+    Till(char),
+    FindBack(char),
+    TillBack(char),
+    MatchPair,
+}
+
+#[derive(Clone, Copy)]
+pub enum Object {
+    Word,
+    Pair(char, char),
+    Quote(char),
+    Paragraph,
+}
+
+#[derive(Clone, Copy)]
+pub enum Target {
+    Motion(Motion),
+    Object(Object, bool),
+    // The operator key doubled, as in `dd`.
+    Line,
+}
+
+#[derive(Clone, Copy)]
+pub enum InsertAt {
+    Cursor,
+    After,
+    LineStart,
+    LineEnd,
+}
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    Move(Motion),
+    Operate(Operator, Target),
+    DeleteChar,
+    Put { before: bool },
+    Open { above: bool },
+    Insert(InsertAt),
+    Repeat,
+}
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    pub action: Action,
+}
+
+pub enum Parse<T> {
+    Done(T),
+    More,
+    Invalid,
+}
+
+// The last change, with whatever was typed if it ended in insert mode, for `.`.
+#[derive(Clone)]
+pub struct Repeat {
+    command: Command,
+    text: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+// Char range an operator applies to; linewise spans cover whole lines
+// without the final line break.
+struct Span {
+    start: usize,
+    end: usize,
+    linewise: bool,
+}
+
+fn count(keys: &[char], i: &mut usize) -> Option<usize> {
+    let digits = keys[*i..].iter().enumerate()
+        .take_while(|&(n, c)| c.is_ascii_digit() && (n > 0 || *c != '0'))
+        .count();
+    if digits == 0 {
+        return None;
+    }
+    let value = keys[*i..*i + digits].iter()
+        .fold(0usize, |n, c| n.saturating_mul(10).saturating_add(c.to_digit(10).unwrap() as usize));
+    *i += digits;
+    Some(value.min(MAX_COUNT))
+}
+
+fn motion(keys: &[char]) -> Parse<Motion> {
+    let motion = match keys {
+        [] => return Parse::More,
+        ['h', ..] => Motion::Left,
+        ['l', ..] => Motion::Right,
+        ['k', ..] => Motion::Up,
+        ['j', ..] => Motion::Down,
+        ['w', ..] => Motion::WordForward,
+        ['b', ..] => Motion::WordBackward,
+        ['e', ..] => Motion::WordEnd,
+        ['0', ..] => Motion::LineStart,
+        ['$', ..] => Motion::LineEnd,
+        ['G', ..] => Motion::FileEnd,
+        ['%', ..] => Motion::MatchPair,
+        ['g'] | ['f' | 't' | 'F' | 'T'] => return Parse::More,
+        ['g', 'g', ..] => Motion::FileStart,
+        ['f', c, ..] => Motion::Find(*c),
+        ['t', c, ..] => Motion::Till(*c),
+        ['F', c, ..] => Motion::FindBack(*c),
+        ['T', c, ..] => Motion::TillBack(*c),
+        _ => return Parse::Invalid,
+    };
+    Parse::Done(motion)
+}
+
+fn object(c: char) -> Option<Object> {
+    Some(match c {
+        'w' => Object::Word,
+        'p' => Object::Paragraph,
+        '(' | ')' | 'b' => Object::Pair('(', ')'),
+        '[' | ']' => Object::Pair('[', ']'),
+        '{' | '}' | 'B' => Object::Pair('{', '}'),
+        '<' | '>' => Object::Pair('<', '>'),
+        '"' | '\'' | '`' => Object::Quote(c),
+        _ => return None,
+    })
+}
+
+// A `"x` prefix naming the register a command yanks to or puts from.
+fn register(keys: &[char], i: &mut usize) -> Parse<Option<char>> {
+    match keys {
+        ['"'] => Parse::More,
+        ['"', name, ..] if name.is_ascii_alphanumeric() || matches!(name, '"' | '+' | '*') => {
+            *i = 2;
+            Parse::Done(Some(*name))
+        }
+        ['"', ..] => Parse::Invalid,
+        _ => Parse::Done(None),
+    }
+}
+
+// Parses a complete normal-mode command such as `3dw`, `ci(`, `2p` or `"ayy`.
+pub fn parse(keys: &str) -> Parse<Command> {
+    let keys: Vec<char> = keys.chars().collect();
+    let mut i = 0;
+    let register = match register(&keys, &mut i) {
+        Parse::Done(register) => register,
+        Parse::More => return Parse::More,
+        Parse::Invalid => return Parse::Invalid,
+    };
+    let first_count = count(&keys, &mut i);
+    let action = match keys.get(i) {
+        None => return Parse::More,
+        Some('x') => Action::DeleteChar,
+        Some('p') => Action::Put { before: false },
+        Some('P') => Action::Put { before: true },
+        Some('o') => Action::Open { above: false },
+        Some('O') => Action::Open { above: true },
+        Some('i') => Action::Insert(InsertAt::Cursor),
+        Some('a') => Action::Insert(InsertAt::After),
+        Some('I') => Action::Insert(InsertAt::LineStart),
+        Some('A') => Action::Insert(InsertAt::LineEnd),
+        Some('.') => Action::Repeat,
+        Some('D') => Action::Operate(Operator::Delete, Target::Motion(Motion::LineEnd)),
+        Some('C') => Action::Operate(Operator::Change, Target::Motion(Motion::LineEnd)),
+        Some(&key @ ('d' | 'c' | 'y' | '>' | '<')) => {
+            let operator = match key {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                'y' => Operator::Yank,
+                '>' => Operator::Indent,
+                _ => Operator::Outdent,
+            };
+            i += 1;
+            let second_count = count(&keys, &mut i);
+            let target = match &keys[i..] {
+                [] => return Parse::More,
+                [c, ..] if *c == key => Target::Line,
+                ['i' | 'a'] => return Parse::More,
+                [around @ ('i' | 'a'), c, ..] => match object(*c) {
+                    Some(object) => Target::Object(object, *around == 'a'),
+                    None => return Parse::Invalid,
+                },
+                rest => match motion(rest) {
+                    Parse::Done(motion) => Target::Motion(motion),
+                    Parse::More => return Parse::More,
+                    Parse::Invalid => return Parse::Invalid,
+                },
+            };
+            let count = match (first_count, second_count) {
+                (Some(a), Some(b)) => Some(a.saturating_mul(b).min(MAX_COUNT)),
+                (a, b) => a.or(b),
+            };
+            return Parse::Done(Command { count, register, action: Action::Operate(operator, target) });
+        }
+        Some(_) => match motion(&keys[i..]) {
+            Parse::Done(motion) => Action::Move(motion),
+            Parse::More => return Parse::More,
+            Parse::Invalid => return Parse::Invalid,
+        },
+    };
+    Parse::Done(Command { count: first_count, register, action })
+}
+
+impl Atto {
+    // Whether all typed of a normal mode command so far is a count, which a
+    // key bound in the keymap takes.
+    pub fn typing_count(&self) -> bool {
+        self.mode == Mode::Normal && !self.kakoune() && self.normal_keys.chars().all(|c| c.is_ascii_digit())
+    }
+
+    // How many times to run `binding` for the count typed before its keys.
+    pub fn binding_count(&mut self, binding: &Binding) -> usize {
+        let keys: Vec<char> = std::mem::take(&mut self.normal_keys).chars().collect();
+        match binding {
+            Binding::Action(action) if COUNTED.contains(&action.as_str()) => count(&keys, &mut 0).unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    pub fn vim_key(&mut self, (code, modifiers): Key) {
+        let c = match code {
+            KeyCode::Char(c) if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+            _ => {
+                self.normal_keys.clear();
+                return;
+            }
+        };
+        self.normal_keys.push(c);
+        match parse(&self.normal_keys) {
+            Parse::More => {}
+            Parse::Invalid => self.normal_keys.clear(),
+            Parse::Done(command) => {
+                self.normal_keys.clear();
+                self.vim_execute(command);
+            }
+        }
+    }
+
+    fn vim_execute(&mut self, command: Command) {
+        let count = command.count.unwrap_or(1);
+        let changes = !matches!(
+            command.action,
+            Action::Move(_) | Action::Operate(Operator::Yank, _) | Action::Repeat
+        );
+        if changes {
+            self.doc_mut().history.group();
+        }
+        match command.action {
+            Action::Move(motion) => {
+                if let Some((target, _)) = self.motion_target(motion, command.count) {
+                    self.set_cursor_char(target);
+                }
+            }
+            Action::Operate(operator, target) => {
+                if let Some(span) = self.target_span(operator, target, command.count) {
+                    self.operate(operator, span, command.register);
+                }
+            }
+            Action::DeleteChar => {
+                let line = self.doc().buffer.line(self.cursor_y);
+                let end = (0..count).fold(self.cursor_x, |col, _| unicode::next_grapheme(&line, col));
+                if end > self.cursor_x {
+                    let start = self.cursor_char();
+                    let span = Span { start, end: start + end - self.cursor_x, linewise: false };
+                    self.operate(Operator::Delete, span, command.register);
+                }
+            }
+            Action::Put { before } => self.put(before, count, command.register),
+            Action::Open { above } => {
+                let y = self.cursor_y;
+                if above {
+                    let pos = self.doc().buffer.line_to_char(y);
+                    self.insert_text(pos, "\n");
+                    self.set_cursor((y, 0));
+                } else {
+                    let pos = self.doc().buffer.line_to_char(y) + self.doc().buffer.line_len(y);
+                    self.insert_text(pos, "\n");
+                    self.set_cursor((y + 1, 0));
+                }
+                self.mode = Mode::Insert;
+            }
+            Action::Insert(at) => {
+                let line = self.doc().buffer.line(self.cursor_y);
+                let x = match at {
+                    InsertAt::Cursor => self.cursor_x,
+                    InsertAt::After => unicode::next_grapheme(&line, self.cursor_x),
+                    InsertAt::LineStart => motion::first_non_blank(&line),
+                    InsertAt::LineEnd => line.chars().count(),
+                };
+                self.set_cursor((self.cursor_y, x));
+                self.mode = Mode::Insert;
+            }
+            Action::Repeat => self.repeat_change(command.count),
+        }
+        if changes && !matches!(command.action, Action::Repeat) {
+            self.repeat = Some(Repeat { command, text: String::new() });
+        }
+        if matches!(self.mode, Mode::Insert) {
+            self.insert_start = Some(self.cursor_char());
+        } else {
+            self.doc_mut().history.seal();
+        }
+    }
+
+    // Leaves insert mode the vim way: the cursor steps back onto the last
+    // inserted char, and the typed text is kept for `.`.
+    pub fn leave_insert(&mut self) {
+        if let Some(start) = self.insert_start.take() {
+            let end = self.cursor_char();
+            if let (Some(repeat), true) = (self.repeat.as_mut(), end >= start) {
+                repeat.text = self.documents[self.current].buffer.slice(start..end);
+            }
+        }
+        self.doc_mut().history.seal();
+        self.mode = Mode::Normal;
+        self.move_left();
+    }
+
+    // Motions move the cursor end of a visual selection; operators act on
+    // the whole selection at once and end visual mode.
+    pub fn visual_key(&mut self, (code, modifiers): Key) {
+        let c = match code {
+            KeyCode::Char(c) if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+            _ => {
+                self.normal_keys.clear();
+                return;
+            }
+        };
+        self.normal_keys.push(c);
+        let keys: Vec<char> = self.normal_keys.chars().collect();
+        let mut i = 0;
+        let register = match register(&keys, &mut i) {
+            Parse::Done(register) => register,
+            Parse::More => return,
+            Parse::Invalid => return self.normal_keys.clear(),
+        };
+        let count = count(&keys, &mut i);
+        match &keys[i..] {
+            [] | ['i' | 'a'] => return,
+            [around @ ('i' | 'a'), c] => {
+                if let Some(object) = object(*c) {
+                    self.visual_object(object, *around == 'a');
+                }
+            }
+            ['d' | 'x'] => self.visual_operate(Operator::Delete, register),
+            ['c' | 's'] => self.visual_operate(Operator::Change, register),
+            ['y'] => self.visual_operate(Operator::Yank, register),
+            ['>'] => self.visual_operate(Operator::Indent, register),
+            ['<'] => self.visual_operate(Operator::Outdent, register),
+            ['p' | 'P'] => self.visual_put(register),
+            ['o' | 'O'] => {
+                if let Some(anchor) = self.selection_start.replace((self.cursor_y, self.cursor_x)) {
+                    self.set_cursor(anchor);
+                }
+            }
+            [at @ ('I' | 'A')] if self.mode == Mode::Visual(Visual::Block) => self.block_insert(*at == 'A'),
+            rest => match motion(rest) {
+                Parse::Done(motion) => {
+                    if let Some((target, _)) = self.motion_target(motion, count) {
+                        self.set_cursor_char(target);
+                    }
+                }
+                Parse::More => return,
+                Parse::Invalid => {}
+            },
+        }
+        self.normal_keys.clear();
+    }
+
+    pub fn visual_operate(&mut self, operator: Operator, register: Option<char>) {
+        let visual = match self.mode {
+            Mode::Visual(visual) => visual,
+            _ => return,
+        };
+        let ranges = self.selected_ranges();
+        self.clear_selection();
+        let (first, last) = match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => (first.clone(), last.clone()),
+            _ => return,
+        };
+        let group = operator != Operator::Yank && !self.doc().history.grouping();
+        if group {
+            self.doc_mut().history.group();
+        }
+        let blockwise = matches!(operator, Operator::Delete | Operator::Change | Operator::Yank);
+        if visual == Visual::Block && blockwise {
+            let buffer = self.doc().buffer.as_ref();
+            let text = ranges.iter().map(|range| buffer.slice(range.clone())).collect::<Vec<_>>().join("\n");
+            self.registers.store(register, Register { text, linewise: false }, operator == Operator::Yank);
+            if operator == Operator::Yank {
+                self.set_cursor_char(first.start);
+            } else {
+                let placed = self.replace_each(ranges.into_iter().map(|range| (range, String::new())).collect());
+                let points: Vec<usize> = placed.iter().map(|range| range.start).collect();
+                if operator == Operator::Change {
+                    self.insert_at_points(points);
+                } else {
+                    self.set_cursor_char(points[0]);
+                }
+            }
+        } else {
+            let span = Span { start: first.start, end: last.end, linewise: visual == Visual::Line };
+            self.operate(operator, span, register);
+            if operator == Operator::Yank {
+                self.set_cursor_char(first.start);
+            }
+        }
+        if group && self.mode != Mode::Insert {
+            self.doc_mut().history.seal();
+        }
+    }
+
+    // Replaces the visual selection with a register, which then holds what
+    // was replaced, as in vim.
+    pub fn visual_put(&mut self, register: Option<char>) {
+        let replacement = match self.registers.get(register) {
+            Some(replacement) => replacement,
+            None => return self.clear_selection(),
+        };
+        let last_line = self.selection_start.map_or(self.cursor_y, |(y, _)| y.max(self.cursor_y));
+        let after = self.mode == Mode::Visual(Visual::Line) && last_line == self.doc().buffer.len_lines() - 1;
+        self.doc_mut().history.group();
+        self.visual_operate(Operator::Delete, None);
+        self.put_register(replacement, !after, 1);
+        self.doc_mut().history.seal();
+    }
+
+    fn visual_object(&mut self, object: Object, around: bool) {
+        if let Some(span) = self.target_span(Operator::Yank, Target::Object(object, around), None) {
+            if span.linewise {
+                self.mode = Mode::Visual(Visual::Line);
+            }
+            self.selection_start = Some(self.doc().buffer.char_to_pos(span.start));
+            let end = if span.linewise { span.end } else { span.end.saturating_sub(1).max(span.start) };
+            self.set_cursor_char(end);
+        }
+    }
+
+    // `I` and `A` on a visual block insert on each of its lines at once.
+    fn block_insert(&mut self, append: bool) {
+        let points = self.selected_ranges().iter().map(|range| if append { range.end } else { range.start }).collect();
+        self.clear_selection();
+        self.insert_at_points(points);
+    }
+
+    fn repeat_change(&mut self, count: Option<usize>) {
+        let repeat = match self.repeat.clone() {
+            Some(repeat) => repeat,
+            None => return,
+        };
+        let command = Command { count: count.or(repeat.command.count), ..repeat.command };
+        self.vim_execute(command);
+        if matches!(self.mode, Mode::Insert) {
+            let pos = self.cursor_char();
+            self.insert_text(pos, &repeat.text);
+            let cursor = self.doc().buffer.char_to_pos(pos + repeat.text.chars().count());
+            self.set_cursor(cursor);
+            self.leave_insert();
+        }
+    }
+
+    // Keeps the cursor on a character, as normal mode has no position past
+    // the end of a non-empty line.
+    pub fn clamp_to_line(&mut self) {
+        if matches!(self.mode, Mode::Insert) {
+            return;
+        }
+        let len = self.doc().buffer.line_len(self.cursor_y);
+        if len > 0 && self.cursor_x >= len {
+            self.cursor_x = unicode::prev_grapheme(&self.doc().buffer.line(self.cursor_y), len);
+            self.scroll_to_cursor_x();
+        }
+    }
+
+    fn motion_target(&self, motion: Motion, count: Option<usize>) -> Option<(usize, Kind)> {
+        let buffer = self.doc().buffer.as_ref();
+        let n = count.unwrap_or(1);
+        let (y, x) = (self.cursor_y, self.cursor_x);
+        let pos = buffer.pos_to_char(y, x);
+        let line = buffer.line(y);
+        let last = buffer.len_lines() - 1;
+        let vertical = |target: usize| {
+            let col = unicode::col_at_display(&buffer.line(target), unicode::display_col(&line, x));
+            (buffer.pos_to_char(target, col), Kind::Linewise)
+        };
+        let first_non_blank = |target: usize| {
+            (buffer.pos_to_char(target, motion::first_non_blank(&buffer.line(target))), Kind::Linewise)
+        };
+        let line_start = buffer.line_to_char(y);
+        Some(match motion {
+            Motion::Left => ((0..n).fold(x, |col, _| unicode::prev_grapheme(&line, col)) + line_start, Kind::Exclusive),
+            Motion::Right => ((0..n).fold(x, |col, _| unicode::next_grapheme(&line, col)) + line_start, Kind::Exclusive),
+            Motion::Up if y > 0 => vertical(y.saturating_sub(n)),
+            Motion::Down if y < last => vertical(y.saturating_add(n).min(last)),
+            Motion::Up | Motion::Down => return None,
+            Motion::WordForward => ((0..n).fold(pos, |p, _| motion::word_forward(buffer, p)), Kind::Exclusive),
+            Motion::WordBackward => ((0..n).fold(pos, |p, _| motion::word_backward(buffer, p)), Kind::Exclusive),
+            Motion::WordEnd => ((0..n).fold(pos, |p, _| motion::word_end(buffer, p)), Kind::Inclusive),
+            Motion::LineStart => (line_start, Kind::Exclusive),
+            Motion::LineEnd => {
+                let target = y.saturating_add(n - 1).min(last);
+                let text = buffer.line(target);
+                (buffer.pos_to_char(target, unicode::prev_grapheme(&text, text.chars().count())), Kind::Inclusive)
+            }
+            Motion::FileStart => first_non_blank(count.map_or(0, |n| n - 1).min(last)),
+            Motion::FileEnd => first_non_blank(count.map_or(last, |n| n - 1).min(last)),
+            Motion::Find(c) => (line_start + motion::find_in_line(&line, x, c, n, true)?, Kind::Inclusive),
+            Motion::Till(c) => (line_start + motion::find_in_line(&line, x, c, n, true)? - 1, Kind::Inclusive),
+            Motion::FindBack(c) => (line_start + motion::find_in_line(&line, x, c, n, false)?, Kind::Exclusive),
+            Motion::TillBack(c) => (line_start + motion::find_in_line(&line, x, c, n, false)? + 1, Kind::Exclusive),
+            Motion::MatchPair => (motion::match_pair(buffer, pos)?, Kind::Inclusive),
+        })
+    }
+
+    fn target_span(&self, operator: Operator, target: Target, count: Option<usize>) -> Option<Span> {
+        let buffer = self.doc().buffer.as_ref();
+        let pos = self.cursor_char();
+        let last = buffer.len_lines() - 1;
+        let lines = |first: usize, end: usize| Span {
+            start: buffer.line_to_char(first),
+            end: buffer.line_to_char(end) + buffer.line_len(end),
+            linewise: true,
+        };
+        match target {
+            Target::Line => Some(lines(self.cursor_y, self.cursor_y.saturating_add(count.unwrap_or(1) - 1).min(last))),
+            Target::Object(object, around) => {
+                let (start, end) = match object {
+                    Object::Word => motion::word_object(buffer, pos, around)?,
+                    Object::Pair(open, close) => motion::pair_object(buffer, pos, open, close, around)?,
+                    Object::Quote(quote) => motion::quote_object(buffer, pos, quote, around)?,
+                    Object::Paragraph => {
+                        let (first, end) = motion::paragraph_object(buffer, self.cursor_y, around);
+                        return Some(lines(first, end));
+                    }
+                };
+                Some(Span { start, end, linewise: false })
+            }
+            Target::Motion(motion) => {
+                let on_word = pos < buffer.len_chars() && !buffer.char_at(pos).is_whitespace();
+                // `cw` changes to the end of the word, like `ce`.
+                let motion = match motion {
+                    Motion::WordForward if operator == Operator::Change && on_word => Motion::WordEnd,
+                    motion => motion,
+                };
+                let (mut target, kind) = self.motion_target(motion, count)?;
+                // `dw` on the last word of a line stops at the line break.
+                if motion == Motion::WordForward && buffer.char_to_line(target) > self.cursor_y {
+                    target = target.min(buffer.line_to_char(self.cursor_y) + buffer.line_len(self.cursor_y));
+                }
+                let (start, end) = (pos.min(target), pos.max(target));
+                Some(match kind {
+                    Kind::Exclusive => Span { start, end, linewise: false },
+                    Kind::Inclusive => {
+                        let past = end < buffer.len_chars() && buffer.char_at(end) != '\n';
+                        Span { start, end: end + past as usize, linewise: false }
+                    }
+                    Kind::Linewise => lines(buffer.char_to_line(start), buffer.char_to_line(end)),
+                })
+            }
+        }
+    }
+
+    fn operate(&mut self, operator: Operator, span: Span, register: Option<char>) {
+        let buffer = self.doc().buffer.as_ref();
+        let (first, last) = (buffer.char_to_line(span.start), buffer.char_to_line(span.end));
+        let mut text = buffer.slice(span.start..span.end);
+        if span.linewise {
+            text.push('\n');
+        }
+        let yanked = Register { text, linewise: span.linewise };
+        match operator {
+            Operator::Yank => {
+                self.registers.store(register, yanked, true);
+                if !span.linewise {
+                    self.set_cursor_char(span.start);
+                }
+            }
+            Operator::Delete => {
+                self.registers.store(register, yanked, false);
+                if span.linewise {
+                    self.delete_lines(first, last);
+                } else {
+                    self.remove_text(span.start..span.end);
+                    self.set_cursor_char(span.start);
+                }
+            }
+            Operator::Change => {
+                self.registers.store(register, yanked, false);
+                self.remove_text(span.start..span.end);
+                self.set_cursor_char(span.start);
+                self.mode = Mode::Insert;
+            }
+            Operator::Indent | Operator::Outdent => {
+                for y in first..=last {
+                    let line = self.doc().buffer.line(y);
+                    let start = self.doc().buffer.line_to_char(y);
+                    if operator == Operator::Indent {
+                        if !line.is_empty() {
+                            self.insert_text(start, "    ");
+                        }
+                    } else {
+                        let width = if line.starts_with('\t') {
+                            1
+                        } else {
+                            line.chars().take(unicode::TAB_WIDTH).take_while(|&c| c == ' ').count()
+                        };
+                        if width > 0 {
+                            self.remove_text(start..start + width);
+                        }
+                    }
+                }
+                let x = motion::first_non_blank(&self.doc().buffer.line(first));
+                self.set_cursor((first, x));
+            }
+        }
+    }
+
+    pub fn delete_lines(&mut self, first: usize, last: usize) {
+        let buffer = self.doc().buffer.as_ref();
+        let range = if last + 1 < buffer.len_lines() {
+            buffer.line_to_char(first)..buffer.line_to_char(last + 1)
+        } else {
+            buffer.line_to_char(first).saturating_sub(1)..buffer.len_chars()
+        };
+        self.remove_text(range);
+        let y = first.min(self.doc().buffer.len_lines() - 1);
+        let x = motion::first_non_blank(&self.doc().buffer.line(y));
+        self.set_cursor((y, x));
+    }
+
+    pub fn put(&mut self, before: bool, count: usize, name: Option<char>) {
+        if let Some(register) = self.registers.get(name) {
+            self.put_register(register, before, count);
+        }
+    }
+
+    fn put_register(&mut self, register: Register, before: bool, count: usize) {
+        if register.text.len().saturating_mul(count) > MAX_PUT {
+            self.status_message = Some(format!("Too much to put {} times", count));
+            return;
+        }
+        let text = register.text.repeat(count);
+        if register.linewise {
+            let y = if before { self.cursor_y } else { self.cursor_y + 1 };
+            if y < self.doc().buffer.len_lines() {
+                let pos = self.doc().buffer.line_to_char(y);
+                self.insert_text(pos, &text);
+            } else {
+                let pos = self.doc().buffer.len_chars();
+                self.insert_text(pos, &format!("\n{}", &text[..text.len() - 1]));
+            }
+            let x = motion::first_non_blank(&self.doc().buffer.line(y));
+            self.set_cursor((y, x));
+        } else {
+            let line = self.doc().buffer.line(self.cursor_y);
+            let x = if before { self.cursor_x } else { unicode::next_grapheme(&line, self.cursor_x) };
+            let pos = self.doc().buffer.pos_to_char(self.cursor_y, x);
+            self.insert_text(pos, &text);
+            self.set_cursor_char(pos + text.chars().count() - 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Action, Command, InsertAt, Motion, Object, Operator, Parse, Target, MAX_COUNT};
+    use crate::Atto;
+
+    fn parsed(keys: &str) -> Command {
+        match parse(keys) {
+            Parse::Done(command) => command,
+            _ => panic!("{} didn't parse", keys),
+        }
+    }
+
+    fn more(keys: &str) -> bool {
+        matches!(parse(keys), Parse::More)
+    }
+
+    fn invalid(keys: &str) -> bool {
+        matches!(parse(keys), Parse::Invalid)
+    }
+
+    #[test]
+    fn motions() {
+        assert!(matches!(parsed("j"), Command { count: None, register: None, action: Action::Move(Motion::Down) }));
+        assert!(matches!(parsed("10j"), Command { count: Some(10), action: Action::Move(Motion::Down), .. }));
+        // A leading 0 is a motion rather than a count.
+        assert!(matches!(parsed("0"), Command { count: None, action: Action::Move(Motion::LineStart), .. }));
+        assert!(matches!(parsed("gg"), Command { action: Action::Move(Motion::FileStart), .. }));
+        assert!(matches!(parsed("2fx"), Command { count: Some(2), action: Action::Move(Motion::Find('x')), .. }));
+        assert!(more("g") && more("f") && more("3"));
+        assert!(invalid("gq") && invalid("z"));
+    }
+
+    #[test]
+    fn operators() {
+        assert!(matches!(parsed("dw"), Command { count: None, action: Action::Operate(Operator::Delete, Target::Motion(Motion::WordForward)), .. }));
+        assert!(matches!(parsed("3dd"), Command { count: Some(3), action: Action::Operate(Operator::Delete, Target::Line), .. }));
+        assert!(matches!(parsed("2d3w"), Command { count: Some(6), .. }));
+        assert!(matches!(parsed("ci("), Command { action: Action::Operate(Operator::Change, Target::Object(Object::Pair('(', ')'), false)), .. }));
+        assert!(matches!(parsed("yap"), Command { action: Action::Operate(Operator::Yank, Target::Object(Object::Paragraph, true)), .. }));
+        assert!(matches!(parsed(">>"), Command { action: Action::Operate(Operator::Indent, Target::Line), .. }));
+        assert!(matches!(parsed("dt)"), Command { action: Action::Operate(Operator::Delete, Target::Motion(Motion::Till(')'))), .. }));
+        assert!(more("d") && more("d2") && more("di") && more("dt"));
+        assert!(invalid("dix") && invalid("dz"));
+    }
+
+    #[test]
+    fn registers_and_others() {
+        assert!(matches!(parsed("\"ayy"), Command { register: Some('a'), action: Action::Operate(Operator::Yank, Target::Line), .. }));
+        assert!(matches!(parsed("\"+2p"), Command { register: Some('+'), count: Some(2), action: Action::Put { before: false } }));
+        assert!(matches!(parsed("A"), Command { action: Action::Insert(InsertAt::LineEnd), .. }));
+        assert!(matches!(parsed("O"), Command { action: Action::Open { above: true }, .. }));
+        assert!(matches!(parsed("3."), Command { count: Some(3), action: Action::Repeat, .. }));
+        assert!(more("\"") && more("\"a"));
+        assert!(invalid("\"%"));
+    }
+
+    #[test]
+    fn counts_are_capped() {
+        assert_eq!(parsed("99999999999999999999999dd").count, Some(MAX_COUNT));
+        assert_eq!(parsed("5000d5000d").count, Some(MAX_COUNT));
+    }
+
+    #[test]
+    fn huge_counts_stop_at_the_buffer() {
+        let mut atto = Atto::scratch("one\ntwo\nthree", true);
+        atto.type_keys("99999999999999999999j");
+        assert_eq!(atto.cursor_y, 2);
+        atto.type_keys("gg99999999999999999999$");
+        assert_eq!((atto.cursor_y, atto.cursor_x), (2, 4));
+        atto.type_keys("gg99999999999999999999dd");
+        assert_eq!(atto.doc().buffer.text(), "");
+    }
+
+    #[test]
+    fn counted_put() {
+        let mut atto = Atto::scratch("line", true);
+        atto.type_keys("yy99999999p");
+        assert_eq!(atto.doc().buffer.len_lines(), MAX_COUNT + 1);
+    }
+
+    #[test]
+    fn counts_before_bound_keys() {
+        let mut atto = Atto::scratch("abcdef", true);
+        atto.type_keys("xxxx");
+        assert_eq!(atto.doc().buffer.text(), "ef");
+        atto.type_keys("3u");
+        assert_eq!(atto.doc().buffer.text(), "bcdef");
+        atto.type_keys("2<C-r>");
+        assert_eq!(atto.doc().buffer.text(), "def");
+        // Still a count for the vim commands after it.
+        atto.type_keys("2x");
+        assert_eq!(atto.doc().buffer.text(), "f");
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{Atto, Prompt};
+
+// Open files are looked at for changes on disk this often.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// A file's modification time and size, to tell when something else wrote it.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+pub fn stamp(filename: &str) -> Option<Stamp> {
+    let metadata = fs::metadata(filename).ok()?;
+    Some(Stamp { modified: metadata.modified().ok()?, len: metadata.len() })
+}
+
+pub struct Watch {
+    last_check: Instant,
+    // Files changed on disk while they had unsaved changes, waiting to be
+    // asked about.
+    conflicts: Vec<String>,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Self { last_check: Instant::now(), conflicts: Vec::new() }
+    }
+}
+
+impl Atto {
+    // Reloads documents whose file changed on disk, unless that would throw
+    // away unsaved changes; those are asked about instead.
+    pub fn check_files(&mut self) {
+        if self.watch.last_check.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.watch.last_check = Instant::now();
+        for index in 0..self.documents.len() {
+            let filename = match self.documents[index].filename.clone() {
+                Some(filename) => filename,
+                None => continue,
+            };
+            let current = stamp(&filename);
+            let document = &mut self.documents[index];
+            if current == document.disk {
+                continue;
+            }
+            document.disk = current;
+            if current.is_none() {
+                self.status_message = Some(format!("{} was deleted on disk", filename));
+            } else if !document.modified() {
+                if self.reload(index) {
+                    self.status_message = Some(format!("{} changed on disk and was reloaded", filename));
+                }
+            } else {
+                document.stale = true;
+                if !self.watch.conflicts.contains(&filename) {
+                    self.watch.conflicts.push(filename);
+                }
+            }
+        }
+    }
+
+    // How long until the files are due to be checked again.
+    pub fn check_timeout(&self) -> Duration {
+        CHECK_INTERVAL.saturating_sub(self.watch.last_check.elapsed())
+    }
+
+    // Asks about the next file changed under unsaved changes, once nothing
+    // else is asked.
+    pub fn offer_reload(&mut self) {
+        if self.prompt.is_some() || self.picker.is_some() || self.watch.conflicts.is_empty() {
+            return;
+        }
+        let filename = self.watch.conflicts.remove(0);
+        let index = self.documents.iter().position(|d| d.filename.as_deref() == Some(filename.as_str()));
+        // Saved or reloaded in the meantime.
+        if let Some(index) = index.filter(|&i| self.documents[i].stale) {
+            self.switch_buffer(index);
+            self.prompt = Some(Prompt::Reload(filename, true));
+            self.command_input.clear();
+        }
+    }
+
+    // The reload key asks first when the buffer has unsaved changes.
+    pub fn reload_file(&mut self) {
+        let filename = match self.doc().filename.clone() {
+            Some(filename) => filename,
+            None => {
+                self.status_message = Some("No file name".to_string());
+                return;
+            }
+        };
+        if self.doc().modified() {
+            self.prompt = Some(Prompt::Reload(filename, false));
+            self.command_input.clear();
+        } else {
+            self.reload(self.current);
+        }
+    }
+
+    // Keeping the buffer leaves it to overwrite the file on the next save.
+    pub fn answer_reload(&mut self, filename: String, answer: &str) {
+        match answer.trim() {
+            "r" | "reload" => {
+                self.reload(self.current);
+            }
+            "k" | "keep" => self.doc_mut().stale = false,
+            // The question comes back after the diff.
+            "v" | "diff" => {
+                let changed = self.doc().stale;
+                if self.show_diff(&filename, self.doc().buffer.text(), "buffer") {
+                    self.prompt = Some(Prompt::Reload(filename, changed));
+                    self.command_input.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Reads the document at `index` again from disk, keeping the cursor where
+    // it was as far as the new text allows; false, with the error in the
+    // status bar, if that failed. `u` brings back the text from before.
+    fn reload(&mut self, index: usize) -> bool {
+        let name = self.documents[index].display_name();
+        let cursor = if index == self.current { (self.cursor_y, self.cursor_x) } else { self.documents[index].cursor };
+        if let Err(e) = self.documents[index].reload(cursor) {
+            self.status_message = Some(format!("Error reading {}: {}", name, e));
+            return false;
+        }
+        self.status_message = Some(format!("Reloaded {}", name));
+        self.remove_swap(index);
+        if index == self.current {
+            self.changed = true;
+            self.completion = None;
+            self.snippet = None;
+            self.selections.clear();
+            self.clear_selection();
+            self.set_cursor((self.cursor_y, self.cursor_x));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use std::{env, fs, process};
+
+    use super::CHECK_INTERVAL;
+    use crate::document::Document;
+    use crate::{Atto, Prompt};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    // An editor on a file holding `text`, in a directory of its own.
+    fn editing(text: &str) -> (Atto, PathBuf) {
+        let dir = env::temp_dir().join(format!("atto-watch-{}-{}", process::id(), DIRS.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watched.txt");
+        fs::write(&path, text).unwrap();
+        let mut atto = Atto::scratch("", true);
+        atto.documents[0] = Document::open(&path.display().to_string()).unwrap();
+        (atto, path)
+    }
+
+    fn check(atto: &mut Atto) {
+        atto.watch.last_check = Instant::now() - CHECK_INTERVAL;
+        atto.check_files();
+        atto.offer_reload();
+    }
+
+    #[test]
+    fn reloads_unmodified_buffers() {
+        let (mut atto, path) = editing("one\ntwo\n");
+        atto.type_keys("j");
+        fs::write(&path, "one\nTWO\nthree\n").unwrap();
+        check(&mut atto);
+        assert_eq!(atto.doc().buffer.text(), "one\nTWO\nthree");
+        assert!(!atto.doc().modified());
+        assert!(atto.prompt.is_none());
+        // The text from before the reload is one undo away.
+        atto.type_keys("u");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwo");
+        assert_eq!(atto.cursor_y, 1);
+        assert!(atto.doc().modified());
+        atto.type_keys("<C-r>");
+        assert_eq!(atto.doc().buffer.text(), "one\nTWO\nthree");
+        assert!(!atto.doc().modified());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn asks_before_dropping_changes() {
+        let (mut atto, path) = editing("one\ntwo\n");
+        atto.type_keys("jAx<Esc>");
+        fs::write(&path, "one\nTWO\n").unwrap();
+        check(&mut atto);
+        assert!(matches!(atto.prompt, Some(Prompt::Reload(_, true))));
+        atto.type_keys("k<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwox");
+        assert!(!atto.doc().stale && atto.doc().modified());
+
+        fs::write(&path, "one\nTWO\nthree\n").unwrap();
+        check(&mut atto);
+        // The differences open next to the buffer and the question comes back.
+        atto.type_keys("v<Enter>");
+        assert!(matches!(atto.prompt, Some(Prompt::Reload(_, true))));
+        assert_eq!(atto.documents.len(), 2);
+        assert!(atto.documents[1].buffer.text().contains("-three\n+twox"));
+        atto.type_keys("r<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "one\nTWO\nthree");
+        assert!(!atto.doc().modified());
+        atto.type_keys("u");
+        assert_eq!(atto.doc().buffer.text(), "one\ntwox");
+
+        // The reload key asks too while there are unsaved changes.
+        atto.type_keys(":reload<Enter>");
+        assert!(matches!(atto.prompt, Some(Prompt::Reload(_, false))));
+        atto.type_keys("r<Enter>");
+        assert_eq!(atto.doc().buffer.text(), "one\nTWO\nthree");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use tui::layout::{Constraint, Direction, Layout as TuiLayout, Rect};
+
+#[derive(Clone, Copy)]
+pub struct Window {
+    pub document: usize,
+    pub cursor: (usize, usize),
+    pub scroll_offset: usize,
+    pub horizontal_scroll_offset: usize,
+    pub area: Rect,
+    // Side-by-side windows draw a separator on their right edge.
+    pub separator: bool,
+}
+
+impl Window {
+    pub fn new(document: usize) -> Self {
+        Self {
+            document,
+            cursor: (0, 0),
+            scroll_offset: 0,
+            horizontal_scroll_offset: 0,
+            area: Rect::default(),
+            separator: false,
+        }
+    }
+}
+
+pub enum FocusDirection {
+    Left,
+    Down,
+    Up,
+    Right,
+}
+
+pub enum Layout {
+    Window(usize),
+    Split(Direction, Vec<Layout>),
+}
+
+impl Layout {
+    // Splits `target` so that `new` sits after it along `direction`.
+    pub fn split(&mut self, target: usize, new: usize, direction: Direction) -> bool {
+        match self {
+            Layout::Window(id) if *id == target => {
+                *self = Layout::Split(direction, vec![Layout::Window(target), Layout::Window(new)]);
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split(split_direction, children) => {
+                let same_direction = *split_direction == direction;
+                for i in 0..children.len() {
+                    if same_direction && matches!(children[i], Layout::Window(id) if id == target) {
+                        children.insert(i + 1, Layout::Window(new));
+                        return true;
+                    }
+                    if children[i].split(target, new, direction.clone()) {
+                        return true;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    // Drops `target` and renumbers the windows after it, collapsing splits
+    // that are left with a single child.
+    pub fn remove(&mut self, target: usize) {
+        if let Layout::Split(_, children) = self {
+            children.retain(|child| !matches!(child, Layout::Window(id) if *id == target));
+            for child in children.iter_mut() {
+                child.remove(target);
+            }
+            if children.len() == 1 {
+                *self = children.pop().unwrap();
+                return;
+            }
+        }
+        if let Layout::Window(id) = self {
+            if *id > target {
+                *id -= 1;
+            }
+        }
+    }
+
+    pub fn arrange(&self, area: Rect, separator: bool, out: &mut Vec<(usize, Rect, bool)>) {
+        match self {
+            Layout::Window(id) => out.push((*id, area, separator)),
+            Layout::Split(direction, children) => {
+                let constraints = vec![Constraint::Ratio(1, children.len() as u32); children.len()];
+                let areas = TuiLayout::default()
+                    .direction(direction.clone())
+                    .constraints(constraints)
+                    .split(area);
+                for (i, (child, area)) in children.iter().zip(areas).enumerate() {
+                    let last = i + 1 == children.len();
+                    let separator = match direction {
+                        Direction::Horizontal => !last || separator,
+                        Direction::Vertical => separator,
+                    };
+                    child.arrange(area, separator, out);
+                }
+            }
+        }
+    }
+}
+
+// Picks the window nearest to `from` whose area lies in `direction`.
+pub fn neighbour(windows: &[Window], from: usize, direction: FocusDirection) -> Option<usize> {
+    let a = windows[from].area;
+    let (ax, ay) = (a.x as i32 + a.width as i32 / 2, a.y as i32 + a.height as i32 / 2);
+    windows
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != from)
+        .filter(|(_, w)| {
+            let b = w.area;
+            match direction {
+                FocusDirection::Left => b.x + b.width <= a.x,
+                FocusDirection::Right => b.x >= a.x + a.width,
+                FocusDirection::Up => b.y + b.height <= a.y,
+                FocusDirection::Down => b.y >= a.y + a.height,
+            }
+        })
+        .min_by_key(|(_, w)| {
+            let b = w.area;
+            let (bx, by) = (b.x as i32 + b.width as i32 / 2, b.y as i32 + b.height as i32 / 2);
+            (ax - bx).abs() + (ay - by).abs()
+        })
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use tui::layout::{Direction, Rect};
+
+    use super::{neighbour, FocusDirection, Layout, Window};
+    use crate::Atto;
+
+    fn areas(layout: &Layout) -> Vec<(usize, Rect, bool)> {
+        let mut out = Vec::new();
+        layout.arrange(Rect::new(0, 0, 90, 20), false, &mut out);
+        out
+    }
+
+    // Window 0 and 3 side by side with 1 over 2 on their right.
+    fn layout() -> Layout {
+        let mut layout = Layout::Window(0);
+        assert!(layout.split(0, 1, Direction::Horizontal));
+        assert!(layout.split(1, 2, Direction::Vertical));
+        // Along the direction of its split, a window gets a sibling.
+        assert!(layout.split(0, 3, Direction::Horizontal));
+        assert!(!layout.split(9, 4, Direction::Vertical));
+        layout
+    }
+
+    #[test]
+    fn split_and_remove() {
+        let mut layout = layout();
+        assert_eq!(areas(&layout), [
+            (0, Rect::new(0, 0, 30, 20), true),
+            (3, Rect::new(30, 0, 30, 20), true),
+            (1, Rect::new(60, 0, 30, 10), false),
+            (2, Rect::new(60, 10, 30, 10), false),
+        ]);
+        // The split left with one window goes, and the windows after the
+        // removed one move down a number.
+        layout.remove(1);
+        assert_eq!(areas(&layout), [
+            (0, Rect::new(0, 0, 30, 20), true),
+            (2, Rect::new(30, 0, 30, 20), true),
+            (1, Rect::new(60, 0, 30, 20), false),
+        ]);
+        layout.remove(0);
+        assert_eq!(areas(&layout), [(1, Rect::new(0, 0, 45, 20), true), (0, Rect::new(45, 0, 45, 20), false)]);
+    }
+
+    #[test]
+    fn neighbours() {
+        let mut windows = vec![Window::new(0); 4];
+        for (id, area, _) in areas(&layout()) {
+            windows[id].area = area;
+        }
+        assert_eq!(neighbour(&windows, 0, FocusDirection::Right), Some(3));
+        assert_eq!(neighbour(&windows, 0, FocusDirection::Left), None);
+        assert_eq!(neighbour(&windows, 3, FocusDirection::Right), Some(1));
+        assert_eq!(neighbour(&windows, 2, FocusDirection::Up), Some(1));
+        assert_eq!(neighbour(&windows, 2, FocusDirection::Left), Some(3));
+        assert_eq!(neighbour(&windows, 1, FocusDirection::Down), Some(2));
+    }
+
+    #[test]
+    fn windows_share_buffers() {
+        let mut atto = Atto::scratch("one\ntwo\nthree", true);
+        atto.type_keys(":vs<Enter>");
+        atto.arrange_windows(Rect::new(0, 0, 80, 24));
+        assert_eq!((atto.windows.len(), atto.focus), (2, 1));
+        atto.type_keys("jj<C-w>h");
+        assert_eq!((atto.focus, atto.cursor_y), (0, 0));
+        // An edit in one window shows in the other.
+        atto.type_keys("x<C-w>l");
+        assert_eq!((atto.focus, atto.cursor_y), (1, 2));
+        assert_eq!(atto.doc().buffer.text(), "ne\ntwo\nthree");
+        atto.type_keys(":clo<Enter>");
+        assert_eq!((atto.windows.len(), atto.focus, atto.cursor_y), (1, 0, 0));
+        atto.type_keys(":clo<Enter>");
+        assert_eq!(atto.status_message.as_deref(), Some("Cannot close the last window"));
+    }
+}
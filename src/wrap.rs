@@ -0,0 +1,179 @@
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::unicode;
+use crate::Atto;
+
+// Soft wrapping shows a long line on as many screen rows as it takes instead
+// of scrolling sideways.
+pub struct Wrap {
+    pub enabled: bool,
+    // Rows break after the last blank that fits rather than at the edge.
+    pub words: bool,
+    // Shown in the number column of the rows a line continues on.
+    pub indicator: String,
+}
+
+impl Default for Wrap {
+    fn default() -> Self {
+        Self { enabled: false, words: true, indicator: "↪".to_string() }
+    }
+}
+
+// The screen columns of `line` each row shows at `width` columns. Rows break
+// between graphemes, so a wide one never straddles two.
+pub fn rows(line: &str, width: usize, words: bool) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let (mut start, mut pos) = (0, 0);
+    // Where the row could break after a blank.
+    let mut blank = None;
+    for grapheme in line.graphemes(true) {
+        let w = unicode::grapheme_width(grapheme);
+        while pos + w > start + width && pos > start {
+            let end = match blank {
+                Some(end) if words && end > start => end,
+                _ => pos,
+            };
+            rows.push(start..end);
+            start = end;
+            blank = None;
+        }
+        pos += w;
+        if grapheme.trim().is_empty() {
+            blank = Some(pos);
+        }
+    }
+    rows.push(start..pos);
+    rows
+}
+
+// The row showing screen column `x` of its line.
+fn row_at(rows: &[Range<usize>], x: usize) -> usize {
+    rows.iter().rposition(|row| row.start <= x).unwrap_or(0)
+}
+
+impl Atto {
+    fn line_rows(&self, line: usize) -> Vec<Range<usize>> {
+        rows(&self.doc().buffer.line(line), self.text_width(), self.wrap.words)
+    }
+
+    // The cursor's row and column in the focused window's text area. The end
+    // of a line that fills its last row is at the start of the next one.
+    fn wrapped_cursor(&self) -> (usize, usize) {
+        let above: usize = (self.scroll_offset..self.cursor_y).map(|line| self.line_rows(line).len()).sum();
+        let rows = self.line_rows(self.cursor_y);
+        let x = self.display_x();
+        let row = row_at(&rows, x);
+        let col = x - rows[row].start;
+        if col >= self.text_width() {
+            (above + row + 1, 0)
+        } else {
+            (above + row, col)
+        }
+    }
+
+    pub fn wrapped_cursor_position(&self) -> (u16, u16) {
+        let area = self.windows[self.focus].area;
+        let (row, col) = self.wrapped_cursor();
+        (area.x + col as u16 + self.gutter_width(), area.y + row as u16 + self.cursor_offset_y)
+    }
+
+    // Scrolls by whole lines until the cursor's row is in view, which moving
+    // by lines doesn't ensure once lines above it take several rows.
+    pub fn scroll_to_cursor_row(&mut self) {
+        if !self.wrap.enabled {
+            return;
+        }
+        self.scroll_offset = self.scroll_offset.min(self.cursor_y);
+        while self.scroll_offset < self.cursor_y && self.wrapped_cursor().0 >= self.view_height {
+            self.scroll_offset += 1;
+        }
+    }
+
+    // `move_up` and `move_down` while wrapping go to the row above or below,
+    // keeping the column within the row.
+    pub fn move_row(&mut self, down: bool) {
+        let rows = self.line_rows(self.cursor_y);
+        let x = self.display_x();
+        let row = row_at(&rows, x);
+        let offset = x - rows[row].start;
+        let (y, rows, row) = if down && row + 1 < rows.len() {
+            (self.cursor_y, rows, row + 1)
+        } else if down && self.cursor_y + 1 < self.doc().buffer.len_lines() {
+            (self.cursor_y + 1, self.line_rows(self.cursor_y + 1), 0)
+        } else if !down && row > 0 {
+            (self.cursor_y, rows, row - 1)
+        } else if !down && self.cursor_y > 0 {
+            let rows = self.line_rows(self.cursor_y - 1);
+            let last = rows.len() - 1;
+            (self.cursor_y - 1, rows, last)
+        } else {
+            return;
+        };
+        let range = &rows[row];
+        // Columns past the end of a row that isn't the line's last belong to
+        // the next row.
+        let target = if row + 1 < rows.len() { (range.start + offset).min(range.end - 1) } else { range.start + offset };
+        self.cursor_y = y;
+        self.cursor_x = unicode::col_at_display(&self.doc().buffer.line(y), target);
+        self.scroll_to_cursor_row();
+    }
+
+    // Buffer position under a screen cell of the focused window.
+    pub fn wrapped_mouse_position(&self, column: u16, row: u16) -> (usize, usize) {
+        let area = self.windows[self.focus].area;
+        let mut target = row.saturating_sub(area.y + self.cursor_offset_y) as usize;
+        let x = column.saturating_sub(area.x + self.gutter_width()) as usize;
+        let len_lines = self.doc().buffer.len_lines();
+        for line in self.scroll_offset..len_lines {
+            let rows = self.line_rows(line);
+            if target < rows.len() || line == len_lines - 1 {
+                let row = target.min(rows.len() - 1);
+                let range = &rows[row];
+                let display_x = if row + 1 < rows.len() { (range.start + x).min(range.end - 1) } else { range.start + x };
+                return (line, unicode::col_at_display(&self.doc().buffer.line(line), display_x));
+            }
+            target -= rows.len();
+        }
+        (len_lines - 1, 0)
+    }
+
+    pub fn toggle_wrap(&mut self) {
+        self.wrap.enabled = !self.wrap.enabled;
+        self.horizontal_scroll_offset = 0;
+        self.scroll_to_cursor_x();
+        self.scroll_to_cursor_row();
+        self.status_message = Some(format!("Soft wrap {}", if self.wrap.enabled { "on" } else { "off" }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaks() {
+        assert_eq!(rows("", 8, true), vec![0..0]);
+        assert_eq!(rows("fits", 4, true), vec![0..4]);
+        assert_eq!(rows("hello world foo", 8, false), vec![0..8, 8..15]);
+        assert_eq!(rows("ab", 0, false), vec![0..1, 1..2]);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(rows("hello world foo", 8, true), vec![0..6, 6..12, 12..15]);
+        // A word longer than the row is cut at the edge.
+        assert_eq!(rows("a verylongword", 5, true), vec![0..2, 2..7, 7..12, 12..14]);
+    }
+
+    #[test]
+    fn wide() {
+        assert_eq!(rows("日本語", 5, false), vec![0..4, 4..6]);
+        assert_eq!(rows("a日", 2, false), vec![0..1, 1..3]);
+        // A grapheme wider than the row still gets one to itself.
+        assert_eq!(rows("日x", 1, false), vec![0..2, 2..3]);
+        assert_eq!(row_at(&rows("日本語", 5, false), 4), 1);
+    }
+}
@@ -0,0 +1,75 @@
+// The mock language server the editor's LSP tests talk to, checked on its
+// own. Having an integration test also has cargo build it for them.
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+use serde_json::{json, Value};
+
+fn send(stdin: &mut impl Write, message: Value) {
+    let body = message.to_string();
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdin.flush().unwrap();
+}
+
+fn receive(reader: &mut impl BufRead) -> Value {
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        match header.trim().split_once(':') {
+            Some((_, value)) => length = value.trim().parse().unwrap(),
+            None => break,
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn mirrors_documents() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mock-lsp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let uri = "file:///tmp/mock.rs";
+
+    send(&mut stdin, json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }));
+    let response = receive(&mut stdout);
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["result"]["capabilities"]["textDocumentSync"], 2);
+
+    send(&mut stdin, json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": { "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": "a bad\n" } },
+    }));
+    let diagnostics = receive(&mut stdout);
+    assert_eq!(diagnostics["method"], "textDocument/publishDiagnostics");
+    assert_eq!(diagnostics["params"]["diagnostics"][0]["range"]["start"], json!({ "line": 0, "character": 2 }));
+
+    send(&mut stdin, json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didChange",
+        "params": {
+            "textDocument": { "uri": uri, "version": 2 },
+            "contentChanges": [{ "range": { "start": { "line": 0, "character": 2 }, "end": { "line": 0, "character": 5 } }, "text": "good" }],
+        },
+    }));
+    assert_eq!(receive(&mut stdout)["params"]["diagnostics"], json!([]));
+
+    send(&mut stdin, json!({
+        "jsonrpc": "2.0",
+        "id": 2,
+        "method": "textDocument/hover",
+        "params": { "textDocument": { "uri": uri }, "position": { "line": 0, "character": 0 } },
+    }));
+    assert_eq!(receive(&mut stdout)["result"]["contents"]["value"], "a good\n");
+
+    send(&mut stdin, json!({ "jsonrpc": "2.0", "method": "exit" }));
+    assert!(child.wait().unwrap().success());
+}
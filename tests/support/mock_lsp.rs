@@ -0,0 +1,177 @@
+// A language server speaking just enough LSP over stdio for the tests. It
+// keeps its own copy of every document from didOpen and didChange, reports
+// each word "bad" in it as an error, answers hover with its copy so the
+// tests can see what it was sent, finds definitions and references by word,
+// renames words and formats by trimming trailing blanks. `--sync <kind>`
+// sets the TextDocumentSyncKind it asks for, incremental by default.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+
+use serde_json::{json, Value};
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn send(message: Value) {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+// The byte offset of an LSP position, which counts UTF-16 code units.
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({ "line": before.matches('\n').count(), "character": before[start..].encode_utf16().count() })
+}
+
+fn range(text: &str, range: Range<usize>) -> Value {
+    json!({ "start": position(text, range.start), "end": position(text, range.end) })
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn word_at(text: &str, offset: usize) -> &str {
+    let start = text[..offset].char_indices().rev().find(|&(_, c)| !is_word(c)).map_or(0, |(i, c)| i + c.len_utf8());
+    let end = text[offset..].find(|c| !is_word(c)).map_or(text.len(), |i| offset + i);
+    &text[start..end]
+}
+
+// Where `word` occurs as a whole word.
+fn occurrences(text: &str, word: &str) -> Vec<Range<usize>> {
+    if word.is_empty() {
+        return Vec::new();
+    }
+    text.match_indices(word)
+        .map(|(i, _)| i..i + word.len())
+        .filter(|r| !text[..r.start].chars().next_back().is_some_and(is_word) && !text[r.end..].chars().next().is_some_and(is_word))
+        .collect()
+}
+
+fn publish_diagnostics(uri: &str, text: &str) {
+    let diagnostics: Vec<Value> = occurrences(text, "bad").into_iter()
+        .map(|r| json!({ "range": range(text, r), "severity": 1, "message": "bad word" }))
+        .collect();
+    send(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let sync: u64 = args.iter().position(|arg| arg == "--sync").and_then(|i| args.get(i + 1)?.parse().ok()).unwrap_or(2);
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut reader = BufReader::new(io::stdin().lock());
+    while let Some(message) = read_message(&mut reader) {
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        let text = documents.get(&uri).cloned().unwrap_or_default();
+        let word = || word_at(&text, offset(&text, &params["position"])).to_string();
+        let result = match message["method"].as_str().unwrap_or("") {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": sync,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "renameProvider": true,
+                    "documentFormattingProvider": true,
+                },
+            }),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                publish_diagnostics(&uri, &text);
+                documents.insert(uri, text);
+                continue;
+            }
+            "textDocument/didChange" => {
+                let mut text = text;
+                for change in params["contentChanges"].as_array().into_iter().flatten() {
+                    let new = change["text"].as_str().unwrap_or("");
+                    match change.get("range") {
+                        Some(range) => {
+                            let (start, end) = (offset(&text, &range["start"]), offset(&text, &range["end"]));
+                            text.replace_range(start..end, new);
+                        }
+                        None => text = new.to_string(),
+                    }
+                }
+                publish_diagnostics(&uri, &text);
+                documents.insert(uri, text);
+                continue;
+            }
+            "textDocument/hover" => json!({ "contents": { "kind": "plaintext", "value": text } }),
+            "textDocument/definition" => match occurrences(&text, &word()).first() {
+                Some(first) => json!({ "uri": uri, "range": range(&text, first.clone()) }),
+                None => Value::Null,
+            },
+            "textDocument/references" => occurrences(&text, &word()).into_iter()
+                .map(|r| json!({ "uri": uri, "range": range(&text, r) }))
+                .collect(),
+            "textDocument/rename" => {
+                let edits: Vec<Value> = occurrences(&text, &word()).into_iter()
+                    .map(|r| json!({ "range": range(&text, r), "newText": params["newName"] }))
+                    .collect();
+                json!({ "changes": { uri: edits } })
+            }
+            "textDocument/formatting" => {
+                let mut edits = Vec::new();
+                let mut start = 0;
+                for line in text.split_inclusive('\n') {
+                    let content = line.trim_end_matches('\n');
+                    let trimmed = content.trim_end();
+                    if trimmed.len() < content.len() {
+                        edits.push(json!({ "range": range(&text, start + trimmed.len()..start + content.len()), "newText": "" }));
+                    }
+                    start += line.len();
+                }
+                Value::Array(edits)
+            }
+            "exit" => return,
+            _ if message.get("id").is_none() => continue,
+            _ => Value::Null,
+        };
+        send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }));
+    }
+}